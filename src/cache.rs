@@ -0,0 +1,213 @@
+use crate::workspace::PackageExt as _;
+use cargo_metadata as cm;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeSet},
+    fs,
+    hash::{Hash as _, Hasher as _},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// How long an entry is kept. Entries are not touched when they are read, so even the used ones
+/// are transformed again after this.
+const MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// A library crate transformed in a previous run.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct TransformedLib {
+    pub(crate) has_local_inner_macros_attr: bool,
+    pub(crate) libs_with_local_inner_macros: BTreeSet<String>,
+    pub(crate) crate_mod_content: String,
+    pub(crate) macro_mod_content: String,
+    pub(crate) prelude_mod_content: String,
+    /// Warnings printed while transforming the crate, which are printed again when this is used.
+    pub(crate) warnings: Vec<String>,
+}
+
+/// Whether the package never changes once published.
+///
+/// Packages with build scripts are not, since their code can depend on `OUT_DIR`.
+pub(crate) fn is_cacheable(package: &cm::Package) -> bool {
+    !package.has_custom_build()
+        && package.source.as_ref().map_or(false, |source| {
+            source.repr.starts_with("registry+") || source.repr.starts_with("sparse+")
+        })
+}
+
+/// Reads `{cache_dir}/transformed-libs/{name}-{version}/{hash of key}.json`.
+///
+/// An entry with a different key is ignored, so that the hash does not have to be stable or
+/// collision-free.
+pub(crate) fn load(cache_dir: &Path, package: &cm::Package, key: &str) -> Option<TransformedLib> {
+    let json = cargo_util::paths::read(&path(cache_dir, package, key)).ok()?;
+    let Entry {
+        key: entry_key,
+        lib,
+    } = serde_json::from_str(&json).ok()?;
+    return (entry_key == key).then_some(lib);
+
+    #[derive(Deserialize)]
+    struct Entry {
+        key: String,
+        lib: TransformedLib,
+    }
+}
+
+/// Writes an entry, removing the ones older than [`MAX_AGE`].
+pub(crate) fn save(
+    cache_dir: &Path,
+    package: &cm::Package,
+    key: &str,
+    lib: &TransformedLib,
+) -> anyhow::Result<()> {
+    let path = &path(cache_dir, package, key);
+    cargo_util::paths::create_dir_all(path.with_file_name(""))?;
+    cargo_util::paths::write(
+        path,
+        serde_json::to_string(&serde_json::json!({ "key": key, "lib": lib }))?,
+    )?;
+    evict(&cache_dir.join("transformed-libs"), SystemTime::now());
+    Ok(())
+}
+
+fn evict(dir: &Path, now: SystemTime) {
+    let package_dirs = fs::read_dir(dir).into_iter().flatten().flatten();
+    for package_dir in package_dirs {
+        let entries = fs::read_dir(package_dir.path())
+            .into_iter()
+            .flatten()
+            .flatten();
+        for entry in entries {
+            let is_old = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .map_or(false, |mtime| {
+                    now.duration_since(mtime).map_or(false, |age| age > MAX_AGE)
+                });
+            if is_old {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        // Fails unless it is empty.
+        let _ = fs::remove_dir(package_dir.path());
+    }
+}
+
+fn path(cache_dir: &Path, package: &cm::Package, key: &str) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    cache_dir
+        .join("transformed-libs")
+        .join(format!("{}-{}", package.name, package.version))
+        .join(format!("{:016x}.json", hasher.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::workspace::fixture;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::{
+        collections::BTreeSet,
+        time::{Duration, SystemTime},
+    };
+
+    const CRATES_IO: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+    fn lib() -> super::TransformedLib {
+        super::TransformedLib {
+            has_local_inner_macros_attr: true,
+            libs_with_local_inner_macros: BTreeSet::from(["a".to_owned()]),
+            crate_mod_content: "pub fn f() {}".to_owned(),
+            macro_mod_content: "pub use crate::m;".to_owned(),
+            prelude_mod_content: "pub(crate) use crate::a;".to_owned(),
+            warnings: vec!["could not find `b`".to_owned()],
+        }
+    }
+
+    #[test]
+    fn is_cacheable() {
+        let mut metadata = fixture::metadata(json!([
+            { "name": "registry", "source": CRATES_IO },
+            { "name": "sparse", "source": "sparse+https://index.crates.io/" },
+            { "name": "git", "source": "git+https://github.com/a/b#0123456789abcdef" },
+            { "name": "path" },
+            { "name": "build-script", "source": CRATES_IO },
+        ]));
+        let mut build_script = metadata.packages[4].targets[0].clone();
+        build_script.kind = vec!["custom-build".to_owned()];
+        metadata.packages[4].targets.push(build_script);
+
+        let cacheable = metadata
+            .packages
+            .iter()
+            .map(|p| (&*p.name, super::is_cacheable(p)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            [
+                ("registry", true),
+                ("sparse", true),
+                ("git", false),
+                ("path", false),
+                ("build-script", false),
+            ],
+            *cacheable,
+        );
+    }
+
+    #[test]
+    fn save_and_load() -> anyhow::Result<()> {
+        let cache_dir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let metadata = fixture::metadata(json!([{ "name": "a", "source": CRATES_IO }]));
+        let package = &metadata.packages[0];
+
+        assert!(super::load(cache_dir.path(), package, "key").is_none());
+
+        super::save(cache_dir.path(), package, "key", &lib())?;
+        let loaded = super::load(cache_dir.path(), package, "key").unwrap();
+        assert_eq!(serde_json::to_value(lib())?, serde_json::to_value(loaded)?,);
+        assert!(super::load(cache_dir.path(), package, "other key").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn load_ignores_key_mismatch() -> anyhow::Result<()> {
+        let cache_dir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let metadata = fixture::metadata(json!([{ "name": "a", "source": CRATES_IO }]));
+        let package = &metadata.packages[0];
+
+        // As if two keys had the same hash.
+        let path = super::path(cache_dir.path(), package, "key");
+        cargo_util::paths::create_dir_all(path.with_file_name(""))?;
+        cargo_util::paths::write(
+            path,
+            serde_json::to_string(&json!({ "key": "other key", "lib": lib() }))?,
+        )?;
+        assert!(super::load(cache_dir.path(), package, "key").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn evict() -> anyhow::Result<()> {
+        let cache_dir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let metadata = fixture::metadata(json!([{ "name": "a", "source": CRATES_IO }]));
+        let package = &metadata.packages[0];
+        let dir = &cache_dir.path().join("transformed-libs");
+
+        super::save(cache_dir.path(), package, "key", &lib())?;
+        super::evict(dir, SystemTime::now() + Duration::from_secs(24 * 60 * 60));
+        assert!(super::load(cache_dir.path(), package, "key").is_some());
+
+        super::evict(dir, SystemTime::now() + super::MAX_AGE * 2);
+        assert!(super::load(cache_dir.path(), package, "key").is_none());
+        assert_eq!(0, dir.read_dir()?.count());
+        Ok(())
+    }
+}
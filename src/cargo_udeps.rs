@@ -1,4 +1,9 @@
-use crate::{process::ProcessBuilderExt as _, shell::Shell, toolchain, workspace::TargetExt as _};
+use crate::{
+    process::ProcessBuilderExt as _,
+    shell::Shell,
+    toolchain,
+    workspace::{FeatureFlags, TargetExt as _},
+};
 use cargo_metadata as cm;
 use cargo_util::ProcessBuilder;
 use serde::Deserialize;
@@ -11,6 +16,7 @@ pub(crate) fn cargo_udeps(
     package: &cm::Package,
     target: &cm::Target,
     toolchain: &str,
+    feature_flags: &FeatureFlags,
     shell: &mut Shell,
 ) -> Result<HashSet<String>, String> {
     let cwd = &package.manifest_path.with_file_name("");
@@ -27,6 +33,7 @@ pub(crate) fn cargo_udeps(
         .arg("-p")
         .arg(&package.name)
         .args(&target.target_option())
+        .args(&feature_flags.to_args())
         .cwd(cwd)
         .try_inspect(|this| shell.status("Running", this))
         .map_err(|e| e.to_string())?
@@ -0,0 +1,336 @@
+use crate::{
+    workspace::TargetExt as _, CrateSinglePath, Minify, Profile, Prune, Remove, User,
+    VersionMismatch,
+};
+use anyhow::{anyhow, bail, Context as _};
+use cargo_metadata as cm;
+use krates::PkgSpec;
+use std::{env, fmt, str::FromStr};
+
+macro_rules! config {
+    (
+        scalars { $($scalar:ident: $scalar_ty:ty,)* }
+        lists { $($list:ident: $list_ty:ty,)* }
+    ) => {
+        /// Defaults for the options, which the command line flags take precedence over.
+        #[derive(Default, Debug)]
+        pub(crate) struct Config {
+            $(pub(crate) $scalar: Option<$scalar_ty>,)*
+            $(pub(crate) $list: Option<Vec<$list_ty>>,)*
+        }
+
+        impl Config {
+            fn from_table(
+                table: &serde_json::Map<String, serde_json::Value>,
+                allow_per_target: bool,
+            ) -> anyhow::Result<Self> {
+                let mut config = Self::default();
+                for (key, value) in table {
+                    match &*key.replace('-', "_") {
+                        $(
+                            stringify!($scalar) => {
+                                config.$scalar = Some(
+                                    parse_scalar(value)
+                                        .with_context(|| format!("invalid value for `{}`", key))?,
+                                );
+                            }
+                        )*
+                        $(
+                            stringify!($list) => {
+                                config.$list = Some(
+                                    parse_list(value)
+                                        .with_context(|| format!("invalid value for `{}`", key))?,
+                                );
+                            }
+                        )*
+                        "bin" | "example" if allow_per_target => {}
+                        _ => bail!("unknown key `{}`", key),
+                    }
+                }
+                Ok(config)
+            }
+
+            fn from_env(vars: impl Iterator<Item = (String, String)>) -> anyhow::Result<Self> {
+                let mut config = Self::default();
+                for (name, value) in vars {
+                    let key = match name.strip_prefix("CARGO_EQUIP_") {
+                        Some(key) => key.to_lowercase(),
+                        None => continue,
+                    };
+                    match &*key {
+                        $(
+                            stringify!($scalar) => {
+                                config.$scalar = Some(
+                                    parse_str(&value)
+                                        .with_context(|| format!("invalid value for `{}`", name))?,
+                                );
+                            }
+                        )*
+                        $(
+                            stringify!($list) => {
+                                config.$list = Some(
+                                    value
+                                        .split(|c: char| c == ',' || c.is_whitespace())
+                                        .filter(|s| !s.is_empty())
+                                        .map(parse_str)
+                                        .collect::<Result<_, _>>()
+                                        .with_context(|| format!("invalid value for `{}`", name))?,
+                                );
+                            }
+                        )*
+                        _ => {}
+                    }
+                }
+                Ok(config)
+            }
+
+            fn or(self, other: Self) -> Self {
+                Self {
+                    $($scalar: self.$scalar.or(other.$scalar),)*
+                    $($list: self.$list.or(other.$list),)*
+                }
+            }
+        }
+    };
+}
+
+config! {
+    scalars {
+        target: String,
+        profile: Profile,
+        exclude_atcoder_crates: bool,
+        exclude_atcoder_202301_crates: bool,
+        exclude_codingame_crates: bool,
+        on_version_mismatch: VersionMismatch,
+        toolchain_for_udeps: String,
+        toolchain_for_proc_macro_srv: String,
+        mod_path: CrateSinglePath,
+        minify: Minify,
+        prune: Prune,
+        max_size: usize,
+        no_resolve_cfgs: bool,
+        no_rustfmt: bool,
+        no_check: bool,
+    }
+    lists {
+        exclude: PkgSpec,
+        exclude_preset: String,
+        mine: User,
+        remove: Remove,
+    }
+}
+
+/// Reads the defaults for `target` in `package`.
+///
+/// From the highest priority:
+///
+/// 1. `CARGO_EQUIP_*` environment variables
+/// 2. `package.metadata.cargo-equip.{bin, example}.<name>`
+/// 3. `package.metadata.cargo-equip`
+/// 4. `workspace.metadata.cargo-equip.{bin, example}.<name>`
+/// 5. `workspace.metadata.cargo-equip`
+pub(crate) fn load(
+    metadata: &cm::Metadata,
+    package: &cm::Package,
+    target: &cm::Target,
+) -> anyhow::Result<Config> {
+    load_with_vars(metadata, package, target, env::vars())
+}
+
+fn load_with_vars(
+    metadata: &cm::Metadata,
+    package: &cm::Package,
+    target: &cm::Target,
+    vars: impl Iterator<Item = (String, String)>,
+) -> anyhow::Result<Config> {
+    let env_config = Config::from_env(vars)?;
+
+    let package_config = from_metadata(&package.metadata, target).with_context(|| {
+        format!(
+            "invalid `package.metadata.cargo-equip` in `{}`",
+            package.manifest_path,
+        )
+    })?;
+
+    let workspace_config =
+        from_metadata(&metadata.workspace_metadata, target).with_context(|| {
+            format!(
+                "invalid `workspace.metadata.cargo-equip` in `{}`",
+                metadata.workspace_root.join("Cargo.toml"),
+            )
+        })?;
+
+    Ok(env_config.or(package_config).or(workspace_config))
+}
+
+fn from_metadata(metadata: &serde_json::Value, target: &cm::Target) -> anyhow::Result<Config> {
+    let table = match metadata.get("cargo-equip") {
+        None => return Ok(Config::default()),
+        Some(serde_json::Value::Object(table)) => table,
+        Some(_) => bail!("expected a table"),
+    };
+    let config = Config::from_table(table, true)?;
+
+    let kind = if target.is_bin() {
+        "bin"
+    } else if target.is_example() {
+        "example"
+    } else {
+        return Ok(config);
+    };
+    match table.get(kind).and_then(|t| t.get(&target.name)) {
+        None => Ok(config),
+        Some(serde_json::Value::Object(table)) => Ok(Config::from_table(table, false)
+            .with_context(|| format!("invalid `{}.{}`", kind, target.name))?
+            .or(config)),
+        Some(_) => bail!("`{}.{}` must be a table", kind, target.name),
+    }
+}
+
+fn parse_scalar<T: FromStr>(value: &serde_json::Value) -> anyhow::Result<T>
+where
+    T::Err: fmt::Display,
+{
+    match value {
+        serde_json::Value::String(s) => parse_str(s),
+        serde_json::Value::Bool(_) | serde_json::Value::Number(_) => parse_str(&value.to_string()),
+        _ => bail!("expected a string, a boolean or an integer"),
+    }
+}
+
+fn parse_list<T: FromStr>(value: &serde_json::Value) -> anyhow::Result<Vec<T>>
+where
+    T::Err: fmt::Display,
+{
+    match value {
+        serde_json::Value::Array(values) => values.iter().map(parse_scalar).collect(),
+        _ => bail!("expected an array"),
+    }
+}
+
+fn parse_str<T: FromStr>(s: &str) -> anyhow::Result<T>
+where
+    T::Err: fmt::Display,
+{
+    s.parse().map_err(|e| anyhow!("{}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::{workspace::fixture, Minify, Remove};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn from_table() -> anyhow::Result<()> {
+        let table = json!({
+            "exclude-atcoder-crates": true,
+            "max-size": 65536,
+            "minify": "libs",
+            "remove": ["docs", "comments"],
+            "target": "x86_64-unknown-linux-gnu",
+            "bin": {},
+        });
+        let config = Config::from_table(table.as_object().unwrap(), true)?;
+        assert_eq!(Some(true), config.exclude_atcoder_crates);
+        assert_eq!(Some(65536), config.max_size);
+        assert_eq!(Some(Minify::Libs), config.minify);
+        assert_eq!(Some(vec![Remove::Docs, Remove::Comments]), config.remove);
+        assert_eq!(Some("x86_64-unknown-linux-gnu"), config.target.as_deref(),);
+        assert_eq!(None, config.no_check);
+
+        for (table, allow_per_target, err) in [
+            (json!({ "bin": {} }), false, "unknown key `bin`"),
+            (json!({ "foo": 1 }), true, "unknown key `foo`"),
+            (
+                json!({ "max-size": "a" }),
+                true,
+                "invalid value for `max-size`",
+            ),
+            (
+                json!({ "remove": "docs" }),
+                true,
+                "invalid value for `remove`",
+            ),
+        ] {
+            assert_eq!(
+                err,
+                Config::from_table(table.as_object().unwrap(), allow_per_target)
+                    .unwrap_err()
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn from_env() -> anyhow::Result<()> {
+        let vars = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+                .collect::<Vec<_>>()
+                .into_iter()
+        };
+
+        let config = Config::from_env(vars(&[
+            ("CARGO_EQUIP_NO_CHECK", "false"),
+            ("CARGO_EQUIP_MINIFY", "all"),
+            ("CARGO_EQUIP_REMOVE", "docs, comments"),
+            ("CARGO_EQUIP_UNKNOWN", "1"),
+            ("MINIFY", "libs"),
+        ]))?;
+        assert_eq!(Some(false), config.no_check);
+        assert_eq!(Some(Minify::All), config.minify);
+        assert_eq!(Some(vec![Remove::Docs, Remove::Comments]), config.remove);
+        assert_eq!(None, config.max_size);
+
+        assert_eq!(
+            "invalid value for `CARGO_EQUIP_MAX_SIZE`",
+            Config::from_env(vars(&[("CARGO_EQUIP_MAX_SIZE", "a")]))
+                .unwrap_err()
+                .to_string(),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn precedence() -> anyhow::Result<()> {
+        let levels = [
+            "env",
+            "package.bin",
+            "package",
+            "workspace.bin",
+            "workspace",
+        ];
+
+        for i in 0..levels.len() {
+            let set = |level: &str| levels[i..].contains(&level);
+
+            let table = |level: &str, bin_level: &str| {
+                let mut table = json!({});
+                if set(level) {
+                    table["target"] = json!(level);
+                }
+                if set(bin_level) {
+                    table["bin"] = json!({ "a": { "target": bin_level } });
+                }
+                table
+            };
+
+            let mut metadata = fixture::metadata(json!([{ "name": "a", "kind": "bin" }]));
+            metadata.workspace_metadata =
+                json!({ "cargo-equip": table("workspace", "workspace.bin") });
+            metadata.packages[0].metadata =
+                json!({ "cargo-equip": table("package", "package.bin") });
+            let vars = Some(("CARGO_EQUIP_TARGET".to_owned(), "env".to_owned()))
+                .filter(|_| set("env"))
+                .into_iter();
+
+            let package = &metadata.packages[0];
+            let config = super::load_with_vars(&metadata, package, &package.targets[0], vars)?;
+            assert_eq!(Some(levels[i]), config.target.as_deref());
+        }
+        Ok(())
+    }
+}
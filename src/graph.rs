@@ -0,0 +1,291 @@
+use crate::{
+    normal_non_host_dep_graph,
+    workspace::{PackageExt as _, TargetExt as _},
+    Job,
+};
+use anyhow::Context as _;
+use cargo_metadata as cm;
+use itertools::Itertools as _;
+use petgraph::visit::EdgeRef as _;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+/// Writes the dependency graph of the libraries to bundle, in JSON if `path` ends with `.json`,
+/// otherwise in Graphviz DOT.
+///
+/// Besides the libraries, the graph has the root crate, the excluded crates and the crates
+/// removed with cargo-udeps. Edges go from dependents to dependencies.
+pub(crate) fn write(metadata: &cm::Metadata, job: &Job<'_>, path: &Path) -> anyhow::Result<()> {
+    let resolve_nodes = metadata
+        .resolve
+        .as_ref()
+        .with_context(|| "`resolve` is `null`")?
+        .nodes
+        .iter()
+        .map(|node| (&node.id, node))
+        .collect::<HashMap<_, _>>();
+
+    let (root_package, root) = job.root_crate.split();
+
+    let (mut graph, mut indices) = normal_non_host_dep_graph(&resolve_nodes, &job.libs_to_bundle);
+
+    let mut statuses = job
+        .libs_to_bundle
+        .keys()
+        .map(|&package_id| (package_id, Status::Bundled))
+        .collect::<BTreeMap<_, _>>();
+    statuses.insert(&root_package.id, Status::Root);
+    for (package_ids, status) in [
+        (&job.excluded, Status::Excluded),
+        (&job.unused_libs, Status::Unused),
+    ] {
+        for &package_id in package_ids {
+            statuses.entry(package_id).or_insert(status);
+        }
+    }
+    for &package_id in statuses.keys() {
+        indices
+            .entry(package_id)
+            .or_insert_with(|| graph.add_node(package_id));
+    }
+
+    // `normal_non_host_dep_graph` only has the edges between the libraries to bundle.
+    for (&from, &status) in &statuses {
+        let expanded = match job.libs_to_bundle.get(from) {
+            Some((krate, _)) => krate.is_lib(),
+            None => status == Status::Root,
+        };
+        if !expanded {
+            continue;
+        }
+        let accepts_dev = from == &root_package.id && root.is_example();
+        for cm::NodeDep {
+            pkg: to, dep_kinds, ..
+        } in &resolve_nodes[from].deps
+        {
+            if from != to
+                && indices.contains_key(to)
+                && !(job.libs_to_bundle.contains_key(from) && job.libs_to_bundle.contains_key(to))
+                && dep_kinds.iter().any(|cm::DepKindInfo { kind, .. }| {
+                    *kind == cm::DependencyKind::Normal
+                        || accepts_dev && *kind == cm::DependencyKind::Development
+                })
+            {
+                graph.add_edge(indices[to], indices[from], ());
+            }
+        }
+    }
+
+    let nodes = graph
+        .node_indices()
+        .map(|index| {
+            let package = &metadata[graph[index]];
+            let pseudo_extern_crate_name = job
+                .libs_to_bundle
+                .get(&package.id)
+                .map(|(_, pseudo_extern_crate_name)| &**pseudo_extern_crate_name);
+            (
+                index.index(),
+                package,
+                pseudo_extern_crate_name,
+                statuses[&package.id],
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let edges = graph
+        .edge_references()
+        .map(|edge| (edge.target().index(), edge.source().index()))
+        .collect::<Vec<_>>();
+
+    let content = if path.extension().map_or(false, |ext| ext == "json") {
+        let nodes = nodes
+            .iter()
+            .map(|(index, package, pseudo_extern_crate_name, status)| {
+                serde_json::json!({
+                    "index": index,
+                    "package_id": package.id,
+                    "name": package.name,
+                    "version": package.version.to_string(),
+                    "pseudo_extern_crate_name": pseudo_extern_crate_name,
+                    "proc_macro": package.has_proc_macro(),
+                    "status": status.to_str(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let edges = edges
+            .iter()
+            .map(|(from, to)| serde_json::json!({ "from": from, "to": to }))
+            .collect::<Vec<_>>();
+        serde_json::to_string_pretty(&serde_json::json!({ "nodes": nodes, "edges": edges }))? + "\n"
+    } else {
+        let mut dot = "digraph {\n".to_owned();
+        for (index, package, pseudo_extern_crate_name, status) in &nodes {
+            let mut label = format!("{} {}", package.name, package.version);
+            if let Some(pseudo_extern_crate_name) = pseudo_extern_crate_name {
+                label += &format!(
+                    "\\ncrate::{}::crates::{}",
+                    job.cargo_equip_mod_name, pseudo_extern_crate_name,
+                );
+            }
+            let mut attrs = vec![];
+            match status {
+                Status::Root => attrs.push("style=bold"),
+                Status::Bundled => {}
+                Status::Excluded => {
+                    label += "\\nexcluded";
+                    attrs.push("style=dashed");
+                }
+                Status::Unused => {
+                    label += "\\nremoved with cargo-udeps";
+                    attrs.push("style=dotted");
+                }
+            }
+            attrs.push(if package.has_proc_macro() {
+                "shape=hexagon"
+            } else {
+                "shape=box"
+            });
+            dot += &format!(
+                "    {} [label=\"{}\", {}]\n",
+                index,
+                label.replace('"', "\\\""),
+                attrs.iter().format(", "),
+            );
+        }
+        for (from, to) in &edges {
+            dot += &format!("    {} -> {}\n", from, to);
+        }
+        dot += "}\n";
+        dot
+    };
+
+    if let Some(parent) = path.parent() {
+        cargo_util::paths::create_dir_all(parent)?;
+    }
+    cargo_util::paths::write(path, content)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Root,
+    Bundled,
+    Excluded,
+    Unused,
+}
+
+impl Status {
+    fn to_str(self) -> &'static str {
+        match self {
+            Self::Root => "root",
+            Self::Bundled => "bundled",
+            Self::Excluded => "excluded",
+            Self::Unused => "unused",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::job, workspace::fixture};
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn metadata() -> cargo_metadata::Metadata {
+        fixture::metadata(json!([
+            {
+                "name": "bin",
+                "kind": "bin",
+                "dependencies": [{ "name": "a" }, { "name": "m" }, { "name": "x" }, { "name": "u" }],
+            },
+            { "name": "a", "dependencies": [{ "name": "b" }, { "name": "x" }] },
+            { "name": "b" },
+            { "name": "m", "kind": "proc-macro" },
+            { "name": "x" },
+            { "name": "u" },
+        ]))
+    }
+
+    fn write(metadata: &cargo_metadata::Metadata, file_name: &str) -> anyhow::Result<String> {
+        let [bin, a, b, m, x, u] = &*metadata.packages else {
+            unreachable!();
+        };
+        let job = &job(
+            bin,
+            &[(a, "__a_0_1_0"), (b, "__b_0_1_0"), (m, "__m_0_1_0")],
+            &[x],
+            &[u],
+        );
+        let dir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let path = &dir.path().join("graphs").join(file_name);
+        super::write(metadata, job, path)?;
+        cargo_util::paths::read(path)
+    }
+
+    #[test]
+    fn dot() -> anyhow::Result<()> {
+        assert_eq!(
+            indoc! {r#"
+                digraph {
+                    0 [label="a 0.1.0\ncrate::__cargo_equip::crates::__a_0_1_0", shape=box]
+                    1 [label="b 0.1.0\ncrate::__cargo_equip::crates::__b_0_1_0", shape=box]
+                    2 [label="m 0.1.0\ncrate::__cargo_equip::crates::__m_0_1_0", shape=hexagon]
+                    3 [label="bin 0.1.0", style=bold, shape=box]
+                    4 [label="u 0.1.0\nremoved with cargo-udeps", style=dotted, shape=box]
+                    5 [label="x 0.1.0\nexcluded", style=dashed, shape=box]
+                    0 -> 1
+                    0 -> 5
+                    3 -> 0
+                    3 -> 2
+                    3 -> 5
+                    3 -> 4
+                }
+            "#},
+            write(&metadata(), "graph.dot")?,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn json() -> anyhow::Result<()> {
+        let node = |index: usize, name: &str, pseudo_extern_crate_name, proc_macro, status| {
+            json!({
+                "index": index,
+                "package_id": fixture::package_id(name),
+                "name": name,
+                "version": "0.1.0",
+                "pseudo_extern_crate_name": pseudo_extern_crate_name,
+                "proc_macro": proc_macro,
+                "status": status,
+            })
+        };
+        let edge = |from: usize, to: usize| json!({ "from": from, "to": to });
+        assert_eq!(
+            json!({
+                "nodes": [
+                    node(0, "a", Some("__a_0_1_0"), false, "bundled"),
+                    node(1, "b", Some("__b_0_1_0"), false, "bundled"),
+                    node(2, "m", Some("__m_0_1_0"), true, "bundled"),
+                    node(3, "bin", None, false, "root"),
+                    node(4, "u", None, false, "unused"),
+                    node(5, "x", None, false, "excluded"),
+                ],
+                "edges": [
+                    edge(0, 1),
+                    edge(0, 5),
+                    edge(3, 0),
+                    edge(3, 2),
+                    edge(3, 5),
+                    edge(3, 4),
+                ],
+            }),
+            serde_json::from_str::<serde_json::Value>(&write(&metadata(), "graph.json")?)?,
+        );
+        Ok(())
+    }
+}
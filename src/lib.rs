@@ -2,13 +2,19 @@
 #![warn(rust_2018_idioms)]
 #![recursion_limit = "256"]
 
+mod cache;
 mod cargo_udeps;
+mod config;
+mod graph;
+mod preset;
 mod process;
 mod ra_proc_macro;
+mod report;
 mod rust;
 mod rustfmt;
 pub mod shell;
 mod toolchain;
+mod watch;
 mod workspace;
 
 use crate::{
@@ -16,11 +22,11 @@ use crate::{
     rust::CodeEdit,
     shell::Shell,
     workspace::{
-        Edition, MetadataExt as _, PackageExt as _, PackageIdExt as _, ResolveBehavior,
-        TargetExt as _,
+        Edition, FeatureFlags, MetadataExt as _, PackageExt as _, PackageIdExt as _, TargetExt as _,
     },
 };
-use anyhow::Context as _;
+use anyhow::{bail, Context as _};
+use camino::Utf8PathBuf;
 use cargo_metadata as cm;
 use indoc::indoc;
 use itertools::{iproduct, Itertools as _};
@@ -32,10 +38,12 @@ use petgraph::{
 };
 use prettytable::{cell, format::FormatBuilder, row, Table};
 use quote::quote;
+use ra_ap_paths::AbsPathBuf;
 use std::{
     cmp,
     collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::Debug,
+    iter,
     path::{Path, PathBuf},
     rc::Rc,
     str::FromStr,
@@ -67,7 +75,9 @@ pub enum Opt {
     cargo equip [OPTIONS] --lib
     cargo equip [OPTIONS] --bin <NAME>
     cargo equip [OPTIONS] --example <NAME>
-    cargo equip [OPTIONS] --src <PATH>"#,
+    cargo equip [OPTIONS] --src <PATH>
+    cargo equip [OPTIONS] --bins --output-dir <DIR>
+    cargo equip [OPTIONS] --all-targets --output-dir <DIR>"#,
         )
     )]
     Equip(OptEquip),
@@ -100,52 +110,147 @@ pub struct OptEquip {
     #[structopt(long, value_name("NAME"))]
     example: Option<String>,
 
+    /// Bundle all the binaries into `--output-dir`
+    #[structopt(
+        long,
+        conflicts_with_all(&["src", "lib", "bin", "example", "snippets", "output", "watch"]),
+        requires("output-dir")
+    )]
+    bins: bool,
+
+    /// Bundle all the binaries and binary examples into `--output-dir`
+    #[structopt(
+        long,
+        conflicts_with_all(&["src", "lib", "bin", "example", "snippets", "output", "watch", "bins"]),
+        requires("output-dir")
+    )]
+    all_targets: bool,
+
+    /// Package with the target to bundle
+    #[structopt(short, long, value_name("SPEC"))]
+    package: Option<PkgSpec>,
+
     /// Path to Cargo.toml
     #[structopt(long, value_name("PATH"))]
     manifest_path: Option<PathBuf>,
 
+    /// Space or comma separated list of features to activate
+    #[structopt(long, value_name("FEATURES"), number_of_values(1))]
+    features: Vec<String>,
+
+    /// Activate all available features
+    #[structopt(long)]
+    all_features: bool,
+
+    /// Do not activate the `default` feature
+    #[structopt(long)]
+    no_default_features: bool,
+
+    /// Target triple of the judge
+    #[structopt(
+        long,
+        value_name("TRIPLE"),
+        long_help(indoc! {r#"
+            Target triple of the judge.
+
+            `#[cfg(..)]`s on the target (e.g. `#[cfg(target_os = "windows")]`) are resolved with `rustc --print cfg --target <TRIPLE>`, and dependencies for other targets are not bundled.
+        "#})
+    )]
+    target: Option<String>,
+
+    /// Profile of the judge [default: release with `--target`]  [possible values: debug, release]
+    #[structopt(
+        long,
+        value_name("PROFILE"),
+        possible_values(Profile::VARIANTS),
+        hide_possible_values(true),
+        long_help(concat!(
+            indoc! {r#"
+                Profile of the judge.
+
+                `#[cfg(debug_assertions)]`s are resolved with it. Defaults to `release` if `--target` is given.
+            "#},
+            ' ',
+        ))
+    )]
+    profile: Option<Profile>,
+
     /// Exclude library crates from bundling
     #[structopt(long, value_name("SPEC"))]
     exclude: Vec<PkgSpec>,
 
-    /// Alias for `--exclude {crates available on AtCoder}`
+    /// Exclude the crates that a judge environment (`Cargo.toml` or `Cargo.lock`) provides
     #[structopt(
         long,
-        long_help(Box::leak(
-            format!(
-                "Alias for:\n--exclude {}\n ",
-                ATCODER_CRATES.iter().format("\n          "),
-            )
-            .into_boxed_str(),
-        ))
+        value_name("PATH"),
+        long_help(indoc! {r#"
+            Exclude the crates that a judge environment (`Cargo.toml` or `Cargo.lock`) provides.
+
+            Crates from crates.io listed in `[dependencies]`, `[target.'..'.dependencies]` and `[workspace.dependencies]` of a `Cargo.toml` (including renamed ones), or all the packages with `source` in a `Cargo.lock`, are excluded if the versions are semver-compatible with the ones in this workspace.
+        "#})
     )]
-    exclude_atcoder_crates: bool,
+    exclude_from: Vec<PathBuf>,
 
-    /// Alias for `--exclude {crates available on AtCoder 202301}`
+    /// Exclude the crates that the judge provides
     #[structopt(
         long,
+        value_name("NAME"),
         long_help(Box::leak(
             format!(
-                "Alias for:\n--exclude {}\n ",
-                ATCODER_202301_CRATES.iter().format("\n          "),
+                "{}\n\nBuilt-in presets: {}\n ",
+                indoc! {r#"
+                    Exclude the crates that the judge provides.
+
+                    A preset is a TOML file that lists package ID specifications as `exclude = [..]`. `<NAME>.toml` is looked up in `{config directory}/cargo-equip/presets` and `{cache directory}/cargo-equip/presets` before the built-in presets.
+                "#}
+                .trim_end(),
+                preset::BUILTIN_PRESETS.iter().map(|(name, _)| name).format(", "),
             )
             .into_boxed_str(),
         ))
     )]
+    exclude_preset: Vec<String>,
+
+    /// Alias for `--exclude-preset atcoder`
+    #[structopt(long)]
+    exclude_atcoder_crates: bool,
+
+    /// Cancel `exclude-atcoder-crates` in the configuration
+    #[structopt(long, conflicts_with("exclude-atcoder-crates"))]
+    no_exclude_atcoder_crates: bool,
+
+    /// Alias for `--exclude-preset atcoder-202301`
+    #[structopt(long)]
     exclude_atcoder_202301_crates: bool,
 
-    /// Alias for `--exclude {crates available on CodinGame}`
+    /// Cancel `exclude-atcoder-202301-crates` in the configuration
+    #[structopt(long, conflicts_with("exclude-atcoder-202301-crates"))]
+    no_exclude_atcoder_202301_crates: bool,
+
+    /// Alias for `--exclude-preset codingame`
+    #[structopt(long)]
+    exclude_codingame_crates: bool,
+
+    /// Cancel `exclude-codingame-crates` in the configuration
+    #[structopt(long, conflicts_with("exclude-codingame-crates"))]
+    no_exclude_codingame_crates: bool,
+
+    /// What to do with a crate that is excluded with other versions [default: bundle]  [possible values: bundle, fail]
     #[structopt(
         long,
-        long_help(Box::leak(
-            format!(
-                "Alias for:\n--exclude {}\n ",
-                CODINGAME_CRATES.iter().format("\n          "),
-            )
-            .into_boxed_str(),
+        value_name("ACTION"),
+        possible_values(VersionMismatch::VARIANTS),
+        hide_possible_values(true),
+        long_help(concat!(
+            indoc! {r#"
+                What to do with a crate that is excluded with other versions.
+
+                When a preset or `--exclude` names a crate with a version different from the one in `Cargo.lock`, the crate is bundled with a warning with `--on-version-mismatch bundle`, or cargo-equip fails with `--on-version-mismatch fail`. Both show a `cargo update` command to switch to the excluded version.
+            "#},
+            ' ',
         ))
     )]
-    exclude_codingame_crates: bool,
+    on_version_mismatch: Option<VersionMismatch>,
 
     /// Do not include license and copyright notices for the users
     #[structopt(
@@ -170,17 +275,17 @@ pub struct OptEquip {
     #[structopt(long, value_name("TOOLCHAIN"), conflicts_with("toolchain_for_udeps"))]
     toolchain: Option<String>,
 
-    /// `nightly` toolchain for `cargo-udeps`
-    #[structopt(long, value_name("TOOLCHAIN"), default_value("nightly"))]
-    toolchain_for_udeps: String,
+    /// `nightly` toolchain for `cargo-udeps` [default: nightly]
+    #[structopt(long, value_name("TOOLCHAIN"))]
+    toolchain_for_udeps: Option<String>,
 
     /// Toolchain for expanding procedural macros
     #[structopt(long, value_name("TOOLCHAIN"))]
     toolchain_for_proc_macro_srv: Option<String>,
 
-    /// Expand the libraries to the module
-    #[structopt(long, value_name("MODULE_PATH"), default_value("crate::__cargo_equip"))]
-    mod_path: CrateSinglePath,
+    /// Expand the libraries to the module [default: crate::__cargo_equip]
+    #[structopt(long, value_name("MODULE_PATH"))]
+    mod_path: Option<CrateSinglePath>,
 
     /// Remove some part [possible values: docs, comments]
     #[structopt(
@@ -224,8 +329,6 @@ pub struct OptEquip {
         value_name("MINIFY"),
         possible_values(Minify::VARIANTS),
         hide_possible_values(true),
-        default_value("none"),
-        hide_default_value(true),
         long_help(concat!(
             indoc! {r#"
                 Minifies
@@ -237,24 +340,171 @@ pub struct OptEquip {
             ' ',
         ))
     )]
-    minify: Minify,
+    minify: Option<Minify>,
+
+    /// Remove unused items from the libraries [default: none]  [possible values: none, mods, items]
+    #[structopt(
+        long,
+        value_name("PRUNE"),
+        possible_values(Prune::VARIANTS),
+        hide_possible_values(true),
+        long_help(concat!(
+            indoc! {r#"
+                Removes
+                - modules of the libraries that the bin never reaches through `use`s and paths with `--prune mods`. Libraries with nothing reached are removed with their license notices.
+                - functions, types, traits, `macro_rules!`s and modules of the libraries that the bin never reaches with `--prune items`.
+
+                Items are traced by name. An item is kept whenever cargo-equip cannot tell whether it is used.
+            "#},
+            ' ',
+        ))
+    )]
+    prune: Option<Prune>,
+
+    /// Remove docs and comments and minify the output until it fits in the size
+    #[structopt(
+        long,
+        value_name("BYTES"),
+        long_help(indoc! {r#"
+            Remove docs and comments and minify the output until it fits in the size.
+
+            `--remove docs`, `--remove comments`, `--minify libs` and `--minify all --no-rustfmt` are applied in this order while the output is larger than <BYTES>. Fails with the size of each library if the output is still larger after all of them.
+        "#})
+    )]
+    max_size: Option<usize>,
 
     /// Do not resolve `cfg(..)`s
     #[structopt(long)]
     no_resolve_cfgs: bool,
 
+    /// Cancel `no-resolve-cfgs` in the configuration
+    #[structopt(long, conflicts_with("no-resolve-cfgs"))]
+    resolve_cfgs: bool,
+
     /// Do not format the output before emitting
     #[structopt(long)]
     no_rustfmt: bool,
 
+    /// Cancel `no-rustfmt` in the configuration
+    #[structopt(long, conflicts_with("no-rustfmt"))]
+    rustfmt: bool,
+
     /// Do not check the output before emitting
     #[structopt(long)]
     no_check: bool,
 
+    /// Cancel `no-check` in the configuration
+    #[structopt(long, conflicts_with("no-check"))]
+    check: bool,
+
+    /// Export snippets of the public modules of the library [possible values: vscode, cargo-snippet]
+    #[structopt(
+        long,
+        value_name("FORMAT"),
+        possible_values(SnippetFormat::VARIANTS),
+        hide_possible_values(true),
+        long_help(concat!(
+            indoc! {r#"
+                Export snippets of the public modules of the library.
+
+                For each `pub mod` at the root of the library, the module is bundled with `--prune mods` along with the `crates`/`macros`/`prelude` modules and the license notices it needs. The snippets are emitted as
+                * a JSON file for VS Code with `--snippets vscode`.
+                * a Rust file with `#[snippet(..)]`s for cargo-snippet with `--snippets cargo-snippet`.
+            "#},
+            ' ',
+        ))
+    )]
+    snippets: Option<SnippetFormat>,
+
     /// Write to the file instead of STDOUT
     #[structopt(short, long, value_name("PATH"))]
     output: Option<PathBuf>,
 
+    /// Write to `<DIR>/<NAME>.rs` instead of STDOUT
+    #[structopt(
+        long,
+        value_name("DIR"),
+        conflicts_with("output"),
+        long_help(indoc! {r#"
+            Write to `<DIR>/<NAME>.rs` instead of STDOUT.
+
+            Examples are written to `<DIR>/examples/<NAME>.rs`. With `--bins` or `--all-targets`, the targets are bundled in one process, sharing `cargo metadata`, the dependency resolution, the `OUT_DIR`s, the procedural macro server and the license notices.
+        "#})
+    )]
+    output_dir: Option<PathBuf>,
+
+    /// Keep running and bundle the code again whenever the sources change
+    #[structopt(
+        long,
+        requires("output"),
+        long_help(indoc! {r#"
+            Keep running and bundle the code again whenever the sources change.
+
+            The directories of the target and the libraries from path dependencies are polled. Cargo.toml and Cargo.lock are read only once, so restart cargo-equip after editing them.
+        "#})
+    )]
+    watch: bool,
+
+    /// Write a JSON report of the run to the file
+    #[structopt(
+        long,
+        value_name("PATH"),
+        conflicts_with("snippets"),
+        long_help(indoc! {r#"
+            Write a JSON report of the run to the file.
+
+            For each target, the report lists the bundled libraries with their pseudo extern crate names, sources and licenses, the expanded procedural macros, the excluded packages, the dependencies removed with cargo-udeps, the warnings, the output size and the error if it failed. The report is written even if bundling a target fails, and again after each run with `--watch`.
+        "#})
+    )]
+    report: Option<PathBuf>,
+
+    /// Print how many bytes each bundled library takes up
+    #[structopt(
+        long,
+        conflicts_with("snippets"),
+        long_help(indoc! {r#"
+            Print how many bytes each bundled library takes up.
+
+            The sizes of the modules of each library in `crates`, `macros` and `preludes`, the license notices and the rest of the code are printed to STDERR, largest library first. If `--remove`, `--minify` or `--max-size` shrank the code, the code is bundled again without them to print the sizes before shrinking as well.
+        "#})
+    )]
+    report_size: bool,
+
+    /// Write the dependency graph of the bundled crates to the file
+    #[structopt(
+        long,
+        value_name("PATH"),
+        conflicts_with_all(&["bins", "all-targets"]),
+        long_help(indoc! {r#"
+            Write the dependency graph of the bundled crates to the file.
+
+            The graph is written in JSON if the path ends with `.json`, otherwise in Graphviz DOT. The nodes are annotated with the names, versions and pseudo extern crate names of the crates and whether they are procedural macro crates. The root crate, the excluded crates and the crates removed with cargo-udeps are distinct nodes. Edges go from dependents to dependencies.
+        "#})
+    )]
+    emit_graph: Option<PathBuf>,
+
+    /// List the crates to bundle without bundling them
+    #[structopt(
+        long,
+        visible_alias("dry-run"),
+        conflicts_with_all(&[
+            "bins",
+            "all-targets",
+            "snippets",
+            "output",
+            "output-dir",
+            "watch",
+            "report",
+            "report-size",
+        ]),
+        long_help(indoc! {r#"
+            List the crates to bundle without bundling them.
+
+            The dependencies are resolved and cargo-udeps is run, but the code is not expanded, formatted or checked. For each crate, the package ID, the pseudo extern crate name, the license and why it is bundled (a direct dependency, a transitive dependency or a procedural macro) are printed to STDOUT. The crates removed with `--exclude` or cargo-udeps are listed as well. Libraries that `--prune` would remove are still listed.
+        "#})
+    )]
+    list: bool,
+
     /// [Deprecated] Alias for `--minify`
     #[structopt(
         long,
@@ -263,21 +513,9 @@ pub struct OptEquip {
         default_value("none")
     )]
     oneline: Minify,
-
-    /// [Deprecated] No-op
-    #[structopt(long, conflicts_with("no_resolve_cfgs"))]
-    resolve_cfgs: bool,
-
-    /// [Deprecated] No-op
-    #[structopt(long, conflicts_with("no_rustfmt"))]
-    rustfmt: bool,
-
-    /// [Deprecated] No-op
-    #[structopt(long, conflicts_with("no_check"))]
-    check: bool,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum User {
     Github(String),
     GitlabCom(String),
@@ -303,7 +541,7 @@ impl FromStr for User {
     }
 }
 
-#[derive(Debug, derive_more::Display)]
+#[derive(Clone, Debug, derive_more::Display)]
 #[display(fmt = "crate::{}", _0)]
 pub struct CrateSinglePath(syn::Ident);
 
@@ -371,181 +609,170 @@ impl FromStr for Minify {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Prune {
+    None,
+    Mods,
+    Items,
+}
+
+impl Prune {
+    const VARIANTS: &'static [&'static str] = &["none", "mods", "items"];
+}
+
+impl FromStr for Prune {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            "none" => Ok(Self::None),
+            "mods" => Ok(Self::Mods),
+            "items" => Ok(Self::Items),
+            _ => Err(r#"expected "none", "mods", or "items""#),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Profile {
+    Debug,
+    Release,
+}
+
+impl Profile {
+    const VARIANTS: &'static [&'static str] = &["debug", "release"];
+}
+
+impl FromStr for Profile {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            "debug" => Ok(Self::Debug),
+            "release" => Ok(Self::Release),
+            _ => Err(r#"expected "debug" or "release""#),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VersionMismatch {
+    Bundle,
+    Fail,
+}
+
+impl VersionMismatch {
+    const VARIANTS: &'static [&'static str] = &["bundle", "fail"];
+}
+
+impl FromStr for VersionMismatch {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            "bundle" => Ok(Self::Bundle),
+            "fail" => Ok(Self::Fail),
+            _ => Err(r#"expected "bundle" or "fail""#),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SnippetFormat {
+    Vscode,
+    CargoSnippet,
+}
+
+impl SnippetFormat {
+    const VARIANTS: &'static [&'static str] = &["vscode", "cargo-snippet"];
+}
+
+impl FromStr for SnippetFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, &'static str> {
+        match s {
+            "vscode" => Ok(Self::Vscode),
+            "cargo-snippet" => Ok(Self::CargoSnippet),
+            _ => Err(r#"expected "vscode" or "cargo-snippet""#),
+        }
+    }
+}
+
 pub struct Context<'a> {
     pub cwd: PathBuf,
     pub cache_dir: PathBuf,
     pub shell: &'a mut Shell,
 }
 
-static ATCODER_CRATES: &[&str] = &[
-    "https://github.com/rust-lang/crates.io-index#alga:0.9.3",
-    "https://github.com/rust-lang/crates.io-index#ascii:1.0.0",
-    "https://github.com/rust-lang/crates.io-index#bitset-fixed:0.1.0",
-    "https://github.com/rust-lang/crates.io-index#either:1.5.3",
-    "https://github.com/rust-lang/crates.io-index#fixedbitset:0.2.0",
-    "https://github.com/rust-lang/crates.io-index#getrandom:0.1.14",
-    "https://github.com/rust-lang/crates.io-index#im-rc:14.3.0",
-    "https://github.com/rust-lang/crates.io-index#indexmap:1.3.2",
-    "https://github.com/rust-lang/crates.io-index#itertools:0.9.0",
-    "https://github.com/rust-lang/crates.io-index#itertools-num:0.1.3",
-    "https://github.com/rust-lang/crates.io-index#lazy_static:1.4.0",
-    "https://github.com/rust-lang/crates.io-index#libm:0.2.1",
-    "https://github.com/rust-lang/crates.io-index#maplit:1.0.2",
-    "https://github.com/rust-lang/crates.io-index#nalgebra:0.20.0",
-    "https://github.com/rust-lang/crates.io-index#ndarray:0.13.0",
-    "https://github.com/rust-lang/crates.io-index#num:0.2.1",
-    "https://github.com/rust-lang/crates.io-index#num-bigint:0.2.6",
-    "https://github.com/rust-lang/crates.io-index#num-complex:0.2.4",
-    "https://github.com/rust-lang/crates.io-index#num-derive:0.3.0",
-    "https://github.com/rust-lang/crates.io-index#num-integer:0.1.42",
-    "https://github.com/rust-lang/crates.io-index#num-iter:0.1.40",
-    "https://github.com/rust-lang/crates.io-index#num-rational:0.2.4",
-    "https://github.com/rust-lang/crates.io-index#num-traits:0.2.11",
-    "https://github.com/rust-lang/crates.io-index#ordered-float:1.0.2",
-    "https://github.com/rust-lang/crates.io-index#permutohedron:0.2.4",
-    "https://github.com/rust-lang/crates.io-index#petgraph:0.5.0",
-    "https://github.com/rust-lang/crates.io-index#proconio:0.3.6",
-    "https://github.com/rust-lang/crates.io-index#proconio:0.3.7",
-    "https://github.com/rust-lang/crates.io-index#proconio:0.3.8",
-    "https://github.com/rust-lang/crates.io-index#rand:0.7.3",
-    "https://github.com/rust-lang/crates.io-index#rand_chacha:0.2.2",
-    "https://github.com/rust-lang/crates.io-index#rand_core:0.5.1",
-    "https://github.com/rust-lang/crates.io-index#rand_distr:0.2.2",
-    "https://github.com/rust-lang/crates.io-index#rand_hc:0.2.0",
-    "https://github.com/rust-lang/crates.io-index#rand_pcg:0.2.1",
-    "https://github.com/rust-lang/crates.io-index#regex:1.3.6",
-    "https://github.com/rust-lang/crates.io-index#rustc-hash:1.1.0",
-    "https://github.com/rust-lang/crates.io-index#smallvec:1.2.0",
-    "https://github.com/rust-lang/crates.io-index#superslice:1.0.0",
-    "https://github.com/rust-lang/crates.io-index#text_io:0.1.8",
-    "https://github.com/rust-lang/crates.io-index#whiteread:0.5.0",
-];
-
-static ATCODER_202301_CRATES: &[&str] = &[
-    "https://github.com/rust-lang/crates.io-index#ac-library-rs:0.1.1",
-    "https://github.com/rust-lang/crates.io-index#once_cell:1.18.0",
-    "https://github.com/rust-lang/crates.io-index#static_assertions:1.1.0",
-    "https://github.com/rust-lang/crates.io-index#varisat:0.2.2",
-    "https://github.com/rust-lang/crates.io-index#memoise:0.3.2",
-    "https://github.com/rust-lang/crates.io-index#argio:0.2.0",
-    "https://github.com/rust-lang/crates.io-index#bitvec:1.0.1",
-    "https://github.com/rust-lang/crates.io-index#counter:0.5.7",
-    "https://github.com/rust-lang/crates.io-index#hashbag:0.1.11",
-    "https://github.com/rust-lang/crates.io-index#pathfinding:4.3.0",
-    "https://github.com/rust-lang/crates.io-index#recur-fn:2.2.0",
-    "https://github.com/rust-lang/crates.io-index#indexing:0.4.1",
-    "https://github.com/rust-lang/crates.io-index#amplify:3.14.2",
-    "https://github.com/rust-lang/crates.io-index#amplify_derive:2.11.3",
-    "https://github.com/rust-lang/crates.io-index#amplify_num:0.4.1",
-    "https://github.com/rust-lang/crates.io-index#easy-ext:1.0.1",
-    "https://github.com/rust-lang/crates.io-index#multimap:0.9.0",
-    "https://github.com/rust-lang/crates.io-index#btreemultimap:0.1.1",
-    "https://github.com/rust-lang/crates.io-index#bstr:1.6.0",
-    "https://github.com/rust-lang/crates.io-index#az:1.2.1",
-    "https://github.com/rust-lang/crates.io-index#glidesort:0.1.2",
-    "https://github.com/rust-lang/crates.io-index#tap:1.0.1",
-    "https://github.com/rust-lang/crates.io-index#omniswap:0.1.0",
-    "https://github.com/rust-lang/crates.io-index#multiversion:0.7.2",
-    "https://github.com/rust-lang/crates.io-index#num:0.4.1",
-    "https://github.com/rust-lang/crates.io-index#num-bigint:0.4.3",
-    "https://github.com/rust-lang/crates.io-index#num-complex:0.4.3",
-    "https://github.com/rust-lang/crates.io-index#num-integer:0.1.45",
-    "https://github.com/rust-lang/crates.io-index#num-iter:0.1.43",
-    "https://github.com/rust-lang/crates.io-index#num-rational:0.4.1",
-    "https://github.com/rust-lang/crates.io-index#num-traits:0.2.15",
-    "https://github.com/rust-lang/crates.io-index#num-derive:0.4.0",
-    "https://github.com/rust-lang/crates.io-index#ndarray:0.15.6",
-    "https://github.com/rust-lang/crates.io-index#nalgebra:0.32.3",
-    "https://github.com/rust-lang/crates.io-index#alga:0.9.3",
-    "https://github.com/rust-lang/crates.io-index#libm:0.2.7",
-    "https://github.com/rust-lang/crates.io-index#rand:0.8.5",
-    "https://github.com/rust-lang/crates.io-index#getrandom:0.2.10",
-    "https://github.com/rust-lang/crates.io-index#rand_chacha:0.3.1",
-    "https://github.com/rust-lang/crates.io-index#rand_core:0.6.4",
-    "https://github.com/rust-lang/crates.io-index#rand_hc:0.3.2",
-    "https://github.com/rust-lang/crates.io-index#rand_pcg:0.3.1",
-    "https://github.com/rust-lang/crates.io-index#rand_distr:0.4.3",
-    "https://github.com/rust-lang/crates.io-index#petgraph:0.6.3",
-    "https://github.com/rust-lang/crates.io-index#indexmap:2.0.0",
-    "https://github.com/rust-lang/crates.io-index#regex:1.9.1",
-    "https://github.com/rust-lang/crates.io-index#lazy_static:1.4.0",
-    "https://github.com/rust-lang/crates.io-index#ordered-float:3.7.0",
-    "https://github.com/rust-lang/crates.io-index#ascii:1.1.0",
-    "https://github.com/rust-lang/crates.io-index#permutohedron:0.2.4",
-    "https://github.com/rust-lang/crates.io-index#superslice:1.0.0",
-    "https://github.com/rust-lang/crates.io-index#itertools:0.11.0",
-    "https://github.com/rust-lang/crates.io-index#itertools-num:0.1.3",
-    "https://github.com/rust-lang/crates.io-index#maplit:1.0.2",
-    "https://github.com/rust-lang/crates.io-index#either:1.8.1",
-    "https://github.com/rust-lang/crates.io-index#im-rc:15.1.0",
-    "https://github.com/rust-lang/crates.io-index#fixedbitset:0.4.2",
-    "https://github.com/rust-lang/crates.io-index#bitset-fixed:0.1.0",
-    "https://github.com/rust-lang/crates.io-index#proconio:0.4.5",
-    "https://github.com/rust-lang/crates.io-index#text_io:0.1.12",
-    "https://github.com/rust-lang/crates.io-index#rustc-hash:1.1.0",
-    "https://github.com/rust-lang/crates.io-index#smallvec:1.11.0",
-];
-
-static CODINGAME_CRATES: &[&str] = &[
-    "https://github.com/rust-lang/crates.io-index#chrono:0.4.19",
-    "https://github.com/rust-lang/crates.io-index#itertools:0.10.0",
-    "https://github.com/rust-lang/crates.io-index#libc:0.2.93",
-    "https://github.com/rust-lang/crates.io-index#rand:0.8.3",
-    "https://github.com/rust-lang/crates.io-index#regex:1.4.5",
-    "https://github.com/rust-lang/crates.io-index#time:0.2.26",
-];
-
 pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
     let Opt::Equip(OptEquip {
         src,
         lib,
         bin,
         example,
+        bins,
+        all_targets,
+        package,
         manifest_path,
+        features,
+        all_features,
+        no_default_features,
+        target,
+        profile,
         exclude,
+        exclude_from,
+        exclude_preset,
         exclude_atcoder_crates,
+        no_exclude_atcoder_crates,
         exclude_atcoder_202301_crates,
+        no_exclude_atcoder_202301_crates,
         exclude_codingame_crates,
+        no_exclude_codingame_crates,
+        on_version_mismatch,
         mine,
         toolchain: deprecated_toolchain_opt,
         toolchain_for_udeps,
         toolchain_for_proc_macro_srv,
-        mod_path: CrateSinglePath(cargo_equip_mod_name),
+        mod_path,
         remove,
         minify,
+        prune,
+        max_size,
         no_resolve_cfgs,
+        resolve_cfgs,
         no_rustfmt,
+        rustfmt,
         no_check,
+        check,
+        snippets,
         output,
+        output_dir,
+        watch,
+        report,
+        report_size,
+        emit_graph,
+        list,
         oneline: deprecated_oneline_opt,
-        resolve_cfgs: deprecated_resolve_cfgs_flag,
-        rustfmt: deprecated_rustfmt_flag,
-        check: deprecated_check_flag,
     }) = opt;
 
     let minify = match (minify, deprecated_oneline_opt) {
-        (Minify::None, oneline) => oneline,
+        (None, Minify::None) => None,
+        (None, oneline) => Some(oneline),
         (minify, _) => minify,
     };
 
-    let exclude = {
-        let mut exclude = exclude;
-        if exclude_atcoder_crates {
-            exclude.extend(ATCODER_CRATES.iter().map(|s| s.parse().unwrap()));
-        }
-        if exclude_atcoder_202301_crates {
-            exclude.extend(ATCODER_202301_CRATES.iter().map(|s| s.parse().unwrap()));
-        }
-        if exclude_codingame_crates {
-            exclude.extend(CODINGAME_CRATES.iter().map(|s| s.parse().unwrap()));
-        }
-        exclude
+    let feature_flags = &FeatureFlags {
+        features: features
+            .iter()
+            .flat_map(|s| s.split(|c: char| c == ',' || c.is_whitespace()))
+            .filter(|s| !s.is_empty())
+            .map(ToOwned::to_owned)
+            .collect(),
+        all_features,
+        no_default_features,
     };
 
-    let toolchain_for_udeps = deprecated_toolchain_opt
-        .as_ref()
-        .unwrap_or(&toolchain_for_udeps);
-
     let Context {
         cwd,
         cache_dir,
@@ -555,15 +782,6 @@ pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
     if deprecated_toolchain_opt.is_some() {
         shell.warn("`--toolchain` was renamed to `--toolchain-for-udeps`")?;
     }
-    if deprecated_resolve_cfgs_flag {
-        shell.warn("`--resolve-cfgs` is deprecated. `#[cfg(..)]`s are resolved by default")?;
-    }
-    if deprecated_rustfmt_flag {
-        shell.warn("`--rustfmt` is deprecated. the output is formatted by default")?;
-    }
-    if deprecated_check_flag {
-        shell.warn("`--check` is deprecated. the output is checked by default")?;
-    }
 
     let manifest_path = if let Some(manifest_path) = manifest_path {
         cwd.join(manifest_path.strip_prefix(".").unwrap_or(&manifest_path))
@@ -571,36 +789,155 @@ pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
         workspace::locate_project(&cwd)?
     };
 
-    let metadata = workspace::cargo_metadata(&manifest_path, &cwd)?;
+    let metadata = workspace::cargo_metadata(&manifest_path, feature_flags, &cwd)?;
 
-    let (root, root_package) = if lib {
-        metadata.lib_target()
-    } else if let Some(bin) = bin {
-        metadata.bin_target_by_name(&bin)
-    } else if let Some(example) = example {
-        metadata.example_target_by_name(&example)
-    } else if let Some(src) = src {
-        metadata.target_by_src_path(&cwd.join(src))
+    let package = package.as_ref();
+    let (roots, root_package) = if bins || all_targets {
+        metadata.bin_like_targets(package, all_targets)?
     } else {
-        metadata.exactly_one_target()
-    }?;
+        let (root, root_package) = if lib {
+            metadata.lib_target(package)
+        } else if let Some(bin) = bin {
+            metadata.bin_target_by_name(package, &bin)
+        } else if let Some(example) = example {
+            metadata.example_target_by_name(package, &example)
+        } else if let Some(src) = src {
+            metadata.target_by_src_path(package, &cwd.join(src))
+        } else {
+            metadata.exactly_one_target(package)
+        }?;
+        (vec![root], root_package)
+    };
 
-    if root_package.edition() == Edition::Edition2015 {
+    if root_package.edition()? == Edition::Edition2015 {
         shell.warn("Rust 2015 is unsupported")?;
     }
+
+    let exclude_from = exclude_from
+        .iter()
+        .map(|path| workspace::packages_provided_by(&cwd.join(path), &metadata))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let output_dir = output_dir.map(|output_dir| cwd.join(output_dir));
+    let output = output.map(|output| cwd.join(output));
+    let report = report.map(|report| cwd.join(report));
+    let emit_graph = emit_graph.map(|emit_graph| cwd.join(emit_graph));
+
     let resolve_behavior = workspace::resolve_behavior(root_package, &metadata.workspace_root)?;
-    if resolve_behavior >= ResolveBehavior::V2 {
-        shell.warn(
-            "Currently cargo-equip only support Feature Resovler v1, and may go search more crates \
-             than real Cargo does. Please watch https://github.com/qryxip/cargo-equip/issues/94",
-        )?;
-    }
 
-    let libs_to_bundle = {
-        let unused_deps = &if root.is_lib() {
+    // `rustc --print cfg` and the features are shared among the targets with the same settings.
+    let mut rustc_cfgs = HashMap::new();
+    let mut resolved_features = HashMap::new();
+
+    let mut jobs = vec![];
+
+    for root in roots {
+        let config = config::load(&metadata, root_package, root)?;
+        let target = target.clone().or(config.target);
+        let profile = profile.or(config.profile);
+        let toolchain_for_udeps = &deprecated_toolchain_opt
+            .clone()
+            .or_else(|| toolchain_for_udeps.clone())
+            .or(config.toolchain_for_udeps)
+            .unwrap_or_else(|| "nightly".to_owned());
+        let toolchain_for_proc_macro_srv = toolchain_for_proc_macro_srv
+            .clone()
+            .or(config.toolchain_for_proc_macro_srv);
+        let CrateSinglePath(cargo_equip_mod_name) = mod_path
+            .clone()
+            .or(config.mod_path)
+            .unwrap_or_else(|| "crate::__cargo_equip".parse().unwrap());
+        let mine = if mine.is_empty() {
+            config.mine.unwrap_or_default()
+        } else {
+            mine.clone()
+        };
+        let remove = if remove.is_empty() {
+            config.remove.unwrap_or_default()
+        } else {
+            remove.clone()
+        };
+        let minify = minify.or(config.minify).unwrap_or(Minify::None);
+        let prune = prune.or(config.prune).unwrap_or(Prune::None);
+        let max_size = max_size.or(config.max_size);
+        let on_version_mismatch = on_version_mismatch
+            .or(config.on_version_mismatch)
+            .unwrap_or(VersionMismatch::Bundle);
+        let no_resolve_cfgs =
+            no_resolve_cfgs || !resolve_cfgs && config.no_resolve_cfgs == Some(true);
+        let no_rustfmt = no_rustfmt || !rustfmt && config.no_rustfmt == Some(true);
+        let no_check = no_check || !check && config.no_check == Some(true);
+
+        let exclude = {
+            let mut exclude = if exclude.is_empty() {
+                config.exclude.unwrap_or_default()
+            } else {
+                exclude.iter().map(clone_pkg_spec).collect()
+            };
+            let mut exclude_preset = if exclude_preset.is_empty() {
+                config.exclude_preset.unwrap_or_default()
+            } else {
+                exclude_preset.clone()
+            };
+            if exclude_atcoder_crates
+                || !no_exclude_atcoder_crates && config.exclude_atcoder_crates == Some(true)
+            {
+                exclude_preset.push("atcoder".to_owned());
+            }
+            if exclude_atcoder_202301_crates
+                || !no_exclude_atcoder_202301_crates
+                    && config.exclude_atcoder_202301_crates == Some(true)
+            {
+                exclude_preset.push("atcoder-202301".to_owned());
+            }
+            if exclude_codingame_crates
+                || !no_exclude_codingame_crates && config.exclude_codingame_crates == Some(true)
+            {
+                exclude_preset.push("codingame".to_owned());
+            }
+            for name in exclude_preset.iter().unique() {
+                exclude.extend(preset::find(name, &cache_dir)?.exclude);
+            }
+            exclude.extend(exclude_from.iter().flatten().map(clone_pkg_spec));
+            exclude
+        };
+
+        if !rustc_cfgs.contains_key(&target) {
+            let target_cfgs = workspace::rustc_cfgs(root_package, target.as_deref())?;
+            rustc_cfgs.insert(target.clone(), Rc::new(target_cfgs));
+        }
+        let target_cfgs = rustc_cfgs[&target].clone();
+        let target_preds = &target_cfgs
+            .iter()
+            .flat_map(cfg_expr::Expression::predicates)
+            .collect::<Vec<_>>();
+        let debug_assertions = profile
+            .or_else(|| target.as_ref().map(|_| Profile::Release))
+            .map(|profile| profile == Profile::Debug);
+
+        let features_key = (root.is_example(), target.clone());
+        if !resolved_features.contains_key(&features_key) {
+            let features = metadata.resolve_features(
+                &root_package.id,
+                root.is_example(),
+                resolve_behavior,
+                feature_flags,
+                target_preds,
+            )?;
+            resolved_features.insert(features_key.clone(), Rc::new(features));
+        }
+        let features = resolved_features[&features_key].clone();
+
+        let unused_deps = if root.is_lib() {
             hashset!()
         } else {
-            match cargo_udeps::cargo_udeps(root_package, root, toolchain_for_udeps, shell) {
+            match cargo_udeps::cargo_udeps(
+                root_package,
+                root,
+                toolchain_for_udeps,
+                feature_flags,
+                shell,
+            ) {
                 Ok(unused_deps) => unused_deps,
                 Err(warning) => {
                     shell.warn(warning)?;
@@ -608,106 +945,772 @@ pub fn run(opt: Opt, ctx: Context<'_>) -> anyhow::Result<()> {
                 }
             }
         };
-        let mut libs_to_bundle =
-            metadata.libs_to_bundle(&root_package.id, root.is_example(), unused_deps, &exclude)?;
-        if root.is_lib() {
-            libs_to_bundle.insert(&root_package.id, (root, root.crate_name()));
+
+        let (libs_to_bundle, excluded, unused_libs) = {
+            let (mut libs_to_bundle, version_mismatches, excluded, unused_libs) = metadata
+                .libs_to_bundle(
+                    &root_package.id,
+                    root.is_example(),
+                    &features,
+                    target_preds,
+                    &unused_deps,
+                    &exclude,
+                )?;
+            for (package_id, versions) in version_mismatches {
+                check_version_mismatch(
+                    &metadata,
+                    package_id,
+                    &versions,
+                    on_version_mismatch,
+                    shell,
+                )?;
+            }
+            if root.is_lib() {
+                libs_to_bundle.insert(&root_package.id, (root, root.crate_name()));
+            }
+            (libs_to_bundle, excluded, unused_libs)
+        };
+
+        let output = if let Some(output_dir) = &output_dir {
+            Some(output_in_dir(output_dir, root))
+        } else {
+            output.clone()
+        };
+
+        jobs.push(Job {
+            root_crate: if root.is_lib() {
+                RootCrate::Lib(root_package, root)
+            } else {
+                RootCrate::BinLike(root_package, root)
+            },
+            output,
+            libs_to_bundle,
+            excluded,
+            unused_libs,
+            unused_deps: unused_deps.into_iter().sorted().collect(),
+            features,
+            target,
+            target_cfgs,
+            debug_assertions,
+            exclude,
+            mine,
+            cargo_equip_mod_name,
+            remove,
+            minify,
+            prune,
+            max_size,
+            resolve_cfgs: !no_resolve_cfgs,
+            rustfmt: !no_rustfmt,
+            check: !no_check,
+            toolchain_for_proc_macro_srv,
+        });
+    }
+
+    if let Some(emit_graph) = &emit_graph {
+        graph::write(&metadata, &jobs[0], emit_graph)?;
+    }
+
+    if list {
+        return list_libs(&metadata, &jobs[0], shell);
+    }
+
+    let atcoder_preset = preset::find("atcoder", &cache_dir)?;
+
+    let error_message = |head: &str,
+                         libs_to_bundle: &BTreeMap<&cm::PackageId, (&cm::Target, String)>,
+                         cargo_equip_mod_name: &syn::Ident| {
+        let mut msg = head.to_owned();
+
+        msg += "\n\n";
+        msg += &libs_to_bundle
+            .iter()
+            .map(|(package_id, (_, pseudo_extern_crate_name))| {
+                format!(
+                    "- `{}` as `crate::{}::crates::{}`\n",
+                    package_id, cargo_equip_mod_name, pseudo_extern_crate_name,
+                )
+            })
+            .join("");
+
+        let crates_available_on_atcoder = iproduct!(libs_to_bundle.keys(), &atcoder_preset.exclude)
+            .filter(|(id, spec)| spec.matches(&metadata[id]))
+            .map(|(id, _)| format!("- `{}`\n", id))
+            .join("");
+
+        if !crates_available_on_atcoder.is_empty() {
+            msg += &format!(
+                "\nnote: attempted to bundle with the following crate(s), which are available on \
+                 AtCoder. to exclude them from bundling, run with `--exclude-preset atcoder`\n\n{}",
+                crates_available_on_atcoder,
+            );
         }
-        libs_to_bundle
+
+        msg
     };
 
-    let error_message = |head: &str| {
-        let mut msg = head.to_owned();
+    // The `OUT_DIR`s, the procedural macro server and the license notices are shared among the
+    // targets.
+    let toolchain_for_proc_macro_srv = match *jobs
+        .iter()
+        .map(|job| &job.toolchain_for_proc_macro_srv)
+        .unique()
+        .collect::<Vec<_>>()
+    {
+        [toolchain_for_proc_macro_srv] => toolchain_for_proc_macro_srv.as_deref(),
+        _ => bail!("`toolchain-for-proc-macro-srv` must be the same for all the targets"),
+    };
+
+    let all_libs_to_bundle = jobs
+        .iter()
+        .flat_map(|job| &job.libs_to_bundle)
+        .map(|(&package_id, (lib, pseudo_extern_crate_name))| {
+            (package_id, (*lib, pseudo_extern_crate_name.clone()))
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let (cargo_messages_for_out_dirs, cargo_messages_for_proc_macro_dll_paths) =
+        cargo_messages_for_bundling(
+            &metadata,
+            root_package,
+            &jobs
+                .iter()
+                .map(|job| job.root_crate.split().1)
+                .collect::<Vec<_>>(),
+            &all_libs_to_bundle,
+            feature_flags,
+            toolchain_for_proc_macro_srv,
+            shell,
+        )
+        .with_context(|| {
+            error_message(
+                "could not bundle the code",
+                &all_libs_to_bundle,
+                &jobs[0].cargo_equip_mod_name,
+            )
+        })?;
+
+    let out_dirs = &workspace::list_out_dirs(&metadata, &cargo_messages_for_out_dirs);
+
+    let mut macro_expander = cargo_messages_for_proc_macro_dll_paths
+        .as_ref()
+        .map(
+            |(cargo_messages_for_proc_macro_dll_paths, proc_macro_srv_exe)| {
+                let proc_macro_crate_dylibs = &ra_proc_macro::list_proc_macro_dylibs(
+                    cargo_messages_for_proc_macro_dll_paths,
+                    |p| all_libs_to_bundle.contains_key(p),
+                );
+                ProcMacroExpander::spawn(proc_macro_srv_exe, proc_macro_crate_dylibs)
+            },
+        )
+        .transpose()
+        .with_context(|| {
+            error_message(
+                "could not bundle the code",
+                &all_libs_to_bundle,
+                &jobs[0].cargo_equip_mod_name,
+            )
+        })?;
+
+    let mut license_texts = HashMap::new();
+
+    // The warnings so far are not specific to a target.
+    let warnings = shell.take_warnings();
+
+    let write_report = |targets: Vec<report::Target>| -> anyhow::Result<()> {
+        if let Some(report) = &report {
+            let warnings = warnings.clone();
+            report::Report { targets, warnings }.write(report)?;
+        }
+        Ok(())
+    };
+
+    let mut bundle_and_check = |job: &Job<'_>,
+                                snippet: Option<&str>,
+                                report: &mut report::Target,
+                                shell: &mut Shell|
+     -> anyhow::Result<_> {
+        let error_message =
+            |head: &str| error_message(head, &job.libs_to_bundle, &job.cargo_equip_mod_name);
+
+        let target_preds = &job
+            .target_cfgs
+            .iter()
+            .flat_map(cfg_expr::Expression::predicates)
+            .collect::<Vec<_>>();
+
+        let mut bundle_with = |remove: &[Remove],
+                               minify: Minify,
+                               rustfmt: bool,
+                               report: &mut report::Target,
+                               shell: &mut Shell| {
+            bundle(
+                &metadata,
+                job.root_crate,
+                &job.libs_to_bundle,
+                &job.features,
+                &job.mine,
+                &job.cargo_equip_mod_name,
+                job.resolve_cfgs,
+                job.target.as_ref().map(|_| &**target_preds),
+                job.debug_assertions,
+                remove,
+                minify,
+                job.prune,
+                snippet,
+                rustfmt,
+                out_dirs,
+                macro_expander.as_mut(),
+                &mut license_texts,
+                &cache_dir,
+                report,
+                shell,
+            )
+            .with_context(|| error_message("could not bundle the code"))
+        };
+
+        let (mut remove, mut minify, mut rustfmt) = (job.remove.clone(), job.minify, job.rustfmt);
+        let code = shrink_to_max_size(
+            job.max_size,
+            &job.cargo_equip_mod_name,
+            (&mut remove, &mut minify, &mut rustfmt),
+            |remove, minify, rustfmt, shell| bundle_with(remove, minify, rustfmt, report, shell),
+            shell,
+        )?;
+
+        if report_size {
+            let unshrunk = if remove.is_empty() && minify == Minify::None {
+                None
+            } else {
+                shell.status("Measuring", "the code without `--remove` and `--minify`")?;
+                Some(bundle_with(&[], Minify::None, job.rustfmt, report, shell)?)
+            };
+            print_sizes(&job.cargo_equip_mod_name, &code, unshrunk.as_deref(), shell)?;
+        }
+
+        if job.check {
+            let (root_package, root) = job.root_crate.split();
+            workspace::cargo_check_using_current_lockfile_and_cache(
+                &metadata,
+                root_package,
+                root,
+                &job.exclude,
+                feature_flags,
+                &code,
+            )
+            .with_context(|| error_message("the bundled code was not valid"))?;
+        }
+        Ok(code)
+    };
+
+    let mut equip =
+        |job: &Job<'_>, report: &mut report::Target, shell: &mut Shell| -> anyhow::Result<()> {
+            let (_, root) = job.root_crate.split();
+
+            let code = if let Some(format) = snippets {
+                if !root.is_lib() {
+                    bail!("`--snippets` is only available for a library. use `--lib`");
+                }
+                let mut snippets = vec![];
+                for mod_name in rust::public_mods(&root.src_path)?.into_iter().unique() {
+                    shell.status(
+                        "Exporting",
+                        format!("`{}::{}`", root.crate_name(), mod_name),
+                    )?;
+                    let code = bundle_and_check(job, Some(&mod_name), report, shell)?;
+                    snippets.push((format!("{}::{}", root.crate_name(), mod_name), code));
+                }
+                render_snippets(format, &snippets)?
+            } else {
+                bundle_and_check(job, None, report, shell)?
+            };
+            report.size = Some(code.len());
+
+            if let Some(output) = &job.output {
+                if let Some(parent) = output.parent() {
+                    cargo_util::paths::create_dir_all(parent)?;
+                }
+                cargo_util::paths::write(output, code)
+            } else {
+                write!(shell.out(), "{}", code)?;
+                Ok(())
+            }
+        };
+
+    if bins || all_targets {
+        return equip_each(&jobs, equip, write_report, shell);
+    }
+
+    let job = &jobs[0];
+
+    if !watch {
+        let mut target_report = new_target_report(job);
+        let result = equip(job, &mut target_report, shell);
+        target_report.finish(&result, shell);
+        write_report(vec![target_report])?;
+        return result;
+    }
+
+    let output = job
+        .output
+        .as_deref()
+        .expect("`--watch` requires `--output`");
+
+    let dirs_to_watch = iter::once(job.root_crate.split().1)
+        .chain(
+            job.libs_to_bundle
+                .iter()
+                .filter(|(package_id, _)| metadata[package_id].source.is_none())
+                .map(|(_, (lib, _))| *lib),
+        )
+        .flat_map(|target| target.src_path.parent())
+        .map(ToOwned::to_owned)
+        .unique()
+        .collect::<Vec<_>>();
+
+    // Do not trigger another run with what the run writes.
+    let written = &iter::once(output)
+        .chain(report.as_deref())
+        .chain(emit_graph.as_deref())
+        .collect::<Vec<_>>();
+
+    let mut mtimes = watch::mtimes(&dirs_to_watch, written);
+    loop {
+        let mut target_report = new_target_report(job);
+        let result = equip(job, &mut target_report, shell);
+        match &result {
+            Ok(()) => shell.status("Finished", format!("wrote `{}`", output.display()))?,
+            Err(err) => shell.report(err)?,
+        }
+        target_report.finish(&result, shell);
+        write_report(vec![target_report])?;
+        shell.status(
+            "Watching",
+            format!(
+                "{} for changes",
+                dirs_to_watch
+                    .iter()
+                    .map(|d| format!("`{}`", d))
+                    .format(", "),
+            ),
+        )?;
+        let changed = watch::wait_for_changes(&dirs_to_watch, written, &mut mtimes);
+        shell.status(
+            "Changed",
+            changed
+                .iter()
+                .map(|p| format!("`{}`", p.display()))
+                .format(", "),
+        )?;
+    }
+}
+
+/// Where `--output-dir` puts the bundled code of `root`.
+fn output_in_dir(output_dir: &Path, root: &cm::Target) -> PathBuf {
+    let output_dir = if root.is_example() {
+        output_dir.join("examples")
+    } else {
+        output_dir.to_owned()
+    };
+    output_dir.join(format!("{}.rs", root.name))
+}
+
+fn new_target_report(job: &Job<'_>) -> report::Target {
+    let (package, target) = job.root_crate.split();
+    report::Target::new(
+        package,
+        target,
+        job.output.clone(),
+        job.excluded.iter().map(|&id| id.clone()).collect(),
+        job.unused_deps.clone(),
+    )
+}
+
+/// Bundles the targets of `--bins` or `--all-targets` one by one, going on after failures.
+fn equip_each(
+    jobs: &[Job<'_>],
+    mut equip: impl FnMut(&Job<'_>, &mut report::Target, &mut Shell) -> anyhow::Result<()>,
+    write_report: impl FnOnce(Vec<report::Target>) -> anyhow::Result<()>,
+    shell: &mut Shell,
+) -> anyhow::Result<()> {
+    let mut failed = vec![];
+    let mut target_reports = vec![];
+    for job in jobs {
+        let (_, root) = job.root_crate.split();
+        shell.status(
+            "Equipping",
+            format!(
+                "the {} `{}`",
+                if root.is_example() { "example" } else { "bin" },
+                root.name,
+            ),
+        )?;
+        let mut target_report = new_target_report(job);
+        let result = equip(job, &mut target_report, shell);
+        match &result {
+            Ok(()) => {
+                let output = job.output.as_ref().expect("should be in `--output-dir`");
+                shell.status("Finished", format!("wrote `{}`", output.display()))?;
+            }
+            Err(err) => {
+                shell.report(err)?;
+                failed.push(root);
+            }
+        }
+        target_report.finish(&result, shell);
+        target_reports.push(target_report);
+    }
+    write_report(target_reports)?;
+    if !failed.is_empty() {
+        bail!(
+            "could not bundle {}",
+            failed.iter().map(|t| format!("`{}`", t.name)).format(", "),
+        );
+    }
+    Ok(())
+}
+
+/// Warns or fails about a library which is bundled since it does not match the excluded
+/// `versions`.
+fn check_version_mismatch(
+    metadata: &cm::Metadata,
+    package_id: &cm::PackageId,
+    versions: &[semver::Version],
+    on_version_mismatch: VersionMismatch,
+    shell: &mut Shell,
+) -> anyhow::Result<()> {
+    let package = &metadata[package_id];
+    let msg = format!(
+        "`{} v{}` does not match the excluded version{} {}",
+        package.name,
+        package.version,
+        if versions.len() > 1 { "s" } else { "" },
+        versions.iter().map(|v| format!("`{}`", v)).format(", "),
+    );
+
+    let reqs = metadata
+        .resolve
+        .as_ref()
+        .with_context(|| "`resolve` is `null`")?
+        .nodes
+        .iter()
+        .filter(|cm::Node { deps, .. }| deps.iter().any(|d| d.pkg == *package_id))
+        .flat_map(|cm::Node { id, .. }| {
+            let dependent = &metadata[id];
+            dependent
+                .dependencies
+                .iter()
+                .filter(|d| d.name == package.name)
+                .map(move |d| (&d.req, &dependent.name))
+        })
+        .unique()
+        .collect::<Vec<_>>();
+
+    // `cargo update --precise` fails unless the version satisfies every requirement.
+    let help = match versions
+        .iter()
+        .rev()
+        .find(|v| reqs.iter().all(|(req, _)| req.matches(v)))
+    {
+        Some(version) => format!(
+            "run `cargo update -p {}@{} --precise {}`",
+            package.name, package.version, version,
+        ),
+        None => format!(
+            "edit `Cargo.toml` so that {} accept{} one of them",
+            reqs.iter()
+                .map(|(req, dependent)| format!("`{}` from `{}`", req, dependent))
+                .format(", "),
+            if reqs.len() > 1 { "" } else { "s" },
+        ),
+    };
+
+    match on_version_mismatch {
+        VersionMismatch::Bundle => {
+            shell.warn(format!(
+                "{}. bundling it anyway. to use the excluded version instead, {}",
+                msg, help,
+            ))?;
+            Ok(())
+        }
+        VersionMismatch::Fail => bail!(
+            "{}\n\nhelp: to use the excluded version instead, {}\nhelp: to bundle it anyway, \
+             run with `--on-version-mismatch bundle`",
+            msg,
+            help,
+        ),
+    }
+}
+
+fn list_libs(metadata: &cm::Metadata, job: &Job<'_>, shell: &mut Shell) -> anyhow::Result<()> {
+    let root_package = job.root_crate.package();
+
+    let direct_deps = metadata
+        .resolve
+        .as_ref()
+        .and_then(|cm::Resolve { nodes, .. }| nodes.iter().find(|n| n.id == root_package.id))
+        .map(|cm::Node { deps, .. }| deps.iter().map(|d| &d.pkg).collect::<HashSet<_>>())
+        .unwrap_or_default();
+
+    let mut table = Table::new();
+    *table.get_format() = FormatBuilder::new()
+        .column_separator(' ')
+        .borders(' ')
+        .build();
+    table.set_titles(row!["package", "as", "license", "reason"]);
+
+    let license = |package_id: &cm::PackageId| {
+        metadata[package_id]
+            .license
+            .clone()
+            .unwrap_or_else(|| "**missing**".to_owned())
+    };
+
+    for (package_id, (krate, pseudo_extern_crate_name)) in &job.libs_to_bundle {
+        let reason = if *package_id == &root_package.id {
+            "root package"
+        } else if krate.is_proc_macro() {
+            "procedural macro"
+        } else if direct_deps.contains(package_id) {
+            "direct dependency"
+        } else {
+            "transitive dependency"
+        };
+        table.add_row(row![
+            package_id,
+            format!(
+                "crate::{}::crates::{}",
+                job.cargo_equip_mod_name, pseudo_extern_crate_name,
+            ),
+            license(package_id),
+            reason,
+        ]);
+    }
+    for (package_ids, reason) in [
+        (&job.excluded, "excluded"),
+        (&job.unused_libs, "removed with cargo-udeps"),
+    ] {
+        for package_id in package_ids {
+            table.add_row(row![package_id, "", license(package_id), reason]);
+        }
+    }
+
+    for line in table.to_string().lines() {
+        writeln!(shell.out(), "{}", line.trim_end())?;
+    }
+    Ok(())
+}
 
-        msg += "\n\n";
-        msg += &libs_to_bundle
-            .iter()
-            .map(|(package_id, (_, pseudo_extern_crate_name))| {
-                format!(
-                    "- `{}` as `crate::{}::crates::{}`\n",
-                    package_id, cargo_equip_mod_name, pseudo_extern_crate_name,
-                )
-            })
-            .join("");
+/// Bundles the code again with `--remove docs`, `--remove comments`, `--minify libs` and
+/// `--minify all --no-rustfmt` in this order until it fits in `max_size`.
+fn shrink_to_max_size(
+    max_size: Option<usize>,
+    cargo_equip_mod_name: &syn::Ident,
+    (remove, minify, rustfmt): (&mut Vec<Remove>, &mut Minify, &mut bool),
+    mut bundle_with: impl FnMut(&[Remove], Minify, bool, &mut Shell) -> anyhow::Result<String>,
+    shell: &mut Shell,
+) -> anyhow::Result<String> {
+    loop {
+        let code = bundle_with(remove, *minify, *rustfmt, shell)?;
 
-        let crates_available_on_atcoder = iproduct!(libs_to_bundle.keys(), ATCODER_CRATES)
-            .filter(|(id, s)| s.parse::<PkgSpec>().unwrap().matches(&metadata[id]))
-            .map(|(id, _)| format!("- `{}`\n", id))
-            .join("");
+        let max_size = match max_size {
+            Some(max_size) if code.len() > max_size => max_size,
+            _ => return Ok(code),
+        };
 
-        if !crates_available_on_atcoder.is_empty() {
+        let step = if !remove.contains(&Remove::Docs) {
+            remove.push(Remove::Docs);
+            "--remove docs"
+        } else if !remove.contains(&Remove::Comments) {
+            remove.push(Remove::Comments);
+            "--remove comments"
+        } else if *minify == Minify::None {
+            *minify = Minify::Libs;
+            "--minify libs"
+        } else if *minify == Minify::Libs {
+            *minify = Minify::All;
+            *rustfmt = false;
+            "--minify all --no-rustfmt"
+        } else if *rustfmt {
+            *rustfmt = false;
+            "--no-rustfmt"
+        } else {
+            let sizes = rust::bundled_crate_sizes(cargo_equip_mod_name, &code)?
+                .into_iter()
+                .map(|(name, sizes)| (name, sizes.iter().sum::<usize>()))
+                .collect::<BTreeMap<_, _>>();
+            let mut msg = format!(
+                "the bundled code is {} bytes, which is larger than `--max-size {}` even with \
+                 `--remove docs --remove comments --minify all --no-rustfmt`\n\n",
+                code.len(),
+                max_size,
+            );
+            for (pseudo_extern_crate_name, size) in
+                sizes.iter().sorted_by_key(|(_, &n)| cmp::Reverse(n))
+            {
+                msg += &format!(
+                    "- `crate::{}::crates::{}`: {} bytes\n",
+                    cargo_equip_mod_name, pseudo_extern_crate_name, size,
+                );
+            }
             msg += &format!(
-                "\nnote: attempted to bundle with the following crate(s), which are available on \
-                 AtCoder. to exclude them from bundling, run with `--exclude-atcoder-crates`\n\n{}",
-                crates_available_on_atcoder,
+                "- the rest: {} bytes\n",
+                code.len() - sizes.values().sum::<usize>()
             );
-        }
+            bail!("{}", msg.trim_end());
+        };
+        shell.status(
+            "Shrinking",
+            format!(
+                "the code with `{}` since it is {} bytes (max: {} bytes)",
+                step,
+                code.len(),
+                max_size,
+            ),
+        )?;
+    }
+}
 
-        msg
+fn print_sizes(
+    cargo_equip_mod_name: &syn::Ident,
+    code: &str,
+    unshrunk: Option<&str>,
+    shell: &mut Shell,
+) -> anyhow::Result<()> {
+    let measure = |code: &str| -> anyhow::Result<_> {
+        let crate_sizes = rust::bundled_crate_sizes(cargo_equip_mod_name, code)?;
+        let license_notices_size = rust::license_notices_size(cargo_equip_mod_name, code)?;
+        let rest =
+            code.len() - crate_sizes.values().flatten().sum::<usize>() - license_notices_size;
+        Ok((crate_sizes, license_notices_size, rest, code.len()))
     };
 
-    let code = bundle(
-        &metadata,
-        if root.is_lib() {
-            RootCrate::Lib(root_package, root)
-        } else {
-            RootCrate::BinLike(root_package, root)
-        },
-        &libs_to_bundle,
-        &mine,
-        &cargo_equip_mod_name,
-        !no_resolve_cfgs,
-        &remove,
-        minify,
-        !no_rustfmt,
-        toolchain_for_proc_macro_srv.as_deref(),
-        &cache_dir,
-        shell,
-    )
-    .with_context(|| error_message("could not bundle the code"))?;
+    let (crate_sizes, license_notices_size, rest, total) = measure(code)?;
+    let unshrunk = unshrunk.map(measure).transpose()?;
 
-    if !no_check {
-        workspace::cargo_check_using_current_lockfile_and_cache(
-            &metadata,
-            root_package,
-            root,
-            &exclude,
-            &code,
-        )
-        .with_context(|| error_message("the bundled code was not valid"))?;
+    let format_size = |size: usize, unshrunk_size: Option<usize>| match unshrunk_size {
+        Some(unshrunk_size) => format!("{} -> {}", unshrunk_size, size),
+        None => size.to_string(),
+    };
+
+    let mut table = Table::new();
+    *table.get_format() = FormatBuilder::new()
+        .column_separator(' ')
+        .borders(' ')
+        .build();
+    table.set_titles(row!["", r->"crates", r->"macros", r->"preludes", r->"total"]);
+
+    for (pseudo_extern_crate_name, sizes) in crate_sizes
+        .iter()
+        .sorted_by_key(|(_, sizes)| cmp::Reverse(sizes.iter().sum::<usize>()))
+    {
+        let unshrunk_sizes = unshrunk.as_ref().map(|(crate_sizes, ..)| {
+            crate_sizes
+                .get(pseudo_extern_crate_name)
+                .copied()
+                .unwrap_or_default()
+        });
+        let mut row = row![format!(
+            "`crate::{}::crates::{}`",
+            cargo_equip_mod_name, pseudo_extern_crate_name,
+        )];
+        for i in 0..3 {
+            row.add_cell(cell!(r->format_size(sizes[i], unshrunk_sizes.map(|s| s[i]))));
+        }
+        row.add_cell(cell!(r->format_size(
+            sizes.iter().sum(),
+            unshrunk_sizes.map(|s| s.iter().sum()),
+        )));
+        table.add_row(row);
     }
+    table.add_row(row![
+        "license notices",
+        "",
+        "",
+        "",
+        r->format_size(license_notices_size, unshrunk.as_ref().map(|(_, n, _, _)| *n)),
+    ]);
+    table.add_row(row![
+        "the rest",
+        "",
+        "",
+        "",
+        r->format_size(rest, unshrunk.as_ref().map(|(_, _, n, _)| *n)),
+    ]);
+    table.add_row(row![
+        "total",
+        "",
+        "",
+        "",
+        r->format_size(total, unshrunk.as_ref().map(|(_, _, _, n)| *n)),
+    ]);
+
+    shell.status("Size", format!("of the code is {} bytes", total))?;
+    for line in table.to_string().lines() {
+        writeln!(shell.err(), "{}", line.trim_end())?;
+    }
+    Ok(())
+}
 
-    if let Some(output) = output {
-        let output = cwd.join(output);
-        cargo_util::paths::write(output, code)
-    } else {
-        write!(shell.out(), "{}", code)?;
-        Ok(())
+fn render_snippets(format: SnippetFormat, snippets: &[(String, String)]) -> anyhow::Result<String> {
+    match format {
+        SnippetFormat::Vscode => {
+            let snippets = snippets
+                .iter()
+                .map(|(name, code)| {
+                    let snippet = serde_json::json!({
+                        "prefix": name,
+                        // The body has no placeholders, so only `\` and `$` need to be escaped.
+                        "body": code
+                            .replace('\\', "\\\\")
+                            .replace('$', "\\$")
+                            .lines()
+                            .collect::<Vec<_>>(),
+                        "description": format!("`{}` bundled by cargo-equip", name),
+                    });
+                    (name.clone(), snippet)
+                })
+                .collect::<serde_json::Map<_, _>>();
+            Ok(serde_json::to_string_pretty(&snippets)? + "\n")
+        }
+        SnippetFormat::CargoSnippet => {
+            let mut output = "use cargo_snippet::snippet;\n".to_owned();
+            for (name, code) in snippets {
+                output += "\n";
+                output += &rust::annotate_items(code, &format!("#[snippet({:?})]", name))?;
+            }
+            Ok(output)
+        }
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-fn bundle(
+/// Runs `cargo check --message-format json` for the `OUT_DIR`s and the dylibs of the procedural
+/// macros, along with `proc-macro-srv` to load the dylibs with.
+#[allow(clippy::type_complexity)]
+fn cargo_messages_for_bundling(
     metadata: &cm::Metadata,
-    root_crate: RootCrate<'_>,
+    package: &cm::Package,
+    krates: &[&cm::Target],
     libs_to_bundle: &BTreeMap<&cm::PackageId, (&cm::Target, String)>,
-    mine: &[User],
-    cargo_equip_mod_name: &syn::Ident,
-    resolve_cfgs: bool,
-    remove: &[Remove],
-    minify: Minify,
-    rustfmt: bool,
+    feature_flags: &FeatureFlags,
     toolchain_for_proc_macro_srv: Option<&str>,
-    cache_dir: &Path,
     shell: &mut Shell,
-) -> anyhow::Result<String> {
+) -> anyhow::Result<(
+    Rc<Vec<cm::Message>>,
+    Option<(Rc<Vec<cm::Message>>, AbsPathBuf)>,
+)> {
     let cargo_check_message_format_json = |toolchain: &str, shell: &mut Shell| -> _ {
-        let (package, krate) = root_crate.split();
-        workspace::cargo_check_message_format_json(toolchain, metadata, package, krate, shell)
-            .map(Rc::new)
+        workspace::cargo_check_message_format_json(
+            toolchain,
+            metadata,
+            package,
+            krates,
+            feature_flags,
+            shell,
+        )
+        .map(Rc::new)
     };
 
-    let active_toolchain = &*toolchain::active_toolchain(root_crate.package().manifest_dir())?;
+    let active_toolchain = &*toolchain::active_toolchain(package.manifest_dir())?;
     let toolchain_for_proc_macro_srv = toolchain_for_proc_macro_srv.unwrap_or(active_toolchain);
 
     let has_custom_build = libs_to_bundle
@@ -734,27 +1737,47 @@ fn bundle(
             )
         };
 
-    let out_dirs = workspace::list_out_dirs(metadata, &cargo_messages_for_out_dirs);
-
-    let macro_expander = cargo_messages_for_proc_macro_dll_paths
-        .as_ref()
+    let cargo_messages_for_proc_macro_dll_paths = cargo_messages_for_proc_macro_dll_paths
         .map(|cargo_messages_for_proc_macro_dll_paths| {
-            let proc_macro_srv_exe = &toolchain::find_rust_analyzer_proc_macro_srv(
-                root_crate.package().manifest_dir(),
+            let proc_macro_srv_exe = toolchain::find_rust_analyzer_proc_macro_srv(
+                package.manifest_dir(),
                 toolchain_for_proc_macro_srv,
             )?;
-
-            let proc_macro_crate_dylibs = &ra_proc_macro::list_proc_macro_dylibs(
-                cargo_messages_for_proc_macro_dll_paths,
-                |p| libs_to_bundle.contains_key(p),
-            );
-
-            ProcMacroExpander::spawn(proc_macro_srv_exe, proc_macro_crate_dylibs)
+            Ok::<_, anyhow::Error>((cargo_messages_for_proc_macro_dll_paths, proc_macro_srv_exe))
         })
         .transpose()?;
 
-    let proc_macro_names = macro_expander
-        .as_ref()
+    Ok((
+        cargo_messages_for_out_dirs,
+        cargo_messages_for_proc_macro_dll_paths,
+    ))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn bundle(
+    metadata: &cm::Metadata,
+    root_crate: RootCrate<'_>,
+    libs_to_bundle: &BTreeMap<&cm::PackageId, (&cm::Target, String)>,
+    features: &HashMap<&cm::PackageId, Vec<String>>,
+    mine: &[User],
+    cargo_equip_mod_name: &syn::Ident,
+    resolve_cfgs: bool,
+    target_preds: Option<&[cfg_expr::Predicate<'_>]>,
+    debug_assertions: Option<bool>,
+    remove: &[Remove],
+    minify: Minify,
+    prune: Prune,
+    snippet: Option<&str>,
+    rustfmt: bool,
+    out_dirs: &BTreeMap<&cm::PackageId, Utf8PathBuf>,
+    macro_expander: Option<&mut ProcMacroExpander<'_>>,
+    license_texts: &mut HashMap<(cm::PackageId, Vec<User>), Option<String>>,
+    cache_dir: &Path,
+    report: &mut report::Target,
+    shell: &mut Shell,
+) -> anyhow::Result<String> {
+    let mut proc_macro_names = macro_expander
+        .as_deref()
         .map(|macro_expander| {
             let mut proc_macro_names = HashMap::<_, BTreeSet<_>>::new();
             for (pkg, macro_names) in macro_expander.macro_names() {
@@ -795,7 +1818,8 @@ fn bundle(
         code = rust::process_bin(
             cargo_equip_mod_name,
             &bin_target.src_path,
-            { macro_expander }.as_mut(),
+            bin_package.manifest_dir(),
+            macro_expander,
             |extern_crate_name| {
                 metadata
                     .dep_lib_by_extern_crate_name(&bin_package.id, extern_crate_name)
@@ -811,19 +1835,173 @@ fn bundle(
         )?;
     }
 
-    let libs = libs_to_bundle
+    let cfg_env = |package_id: &cm::PackageId| {
+        resolve_cfgs.then(|| rust::CfgEnv {
+            features: &features[package_id],
+            target_preds,
+            debug_assertions,
+        })
+    };
+
+    let root_edition = root_crate.package().edition()?;
+
+    // Libraries from registries never change, so they are transformed once and reused unless they
+    // are pruned by the root crate.
+    let lib_cache_keys = if prune == Prune::None && snippet.is_none() {
+        libs_to_bundle
+            .iter()
+            .filter(|(pkg, (krate, _))| krate.is_lib() && cache::is_cacheable(&metadata[pkg]))
+            .map(|(pkg, (_, pseudo_extern_crate_name))| {
+                let extern_crate_names = metadata
+                    .libs_with_extern_crate_names(pkg, &libs_to_bundle.keys().copied().collect())?
+                    .into_iter()
+                    .map(|(package_id, extern_crate_name)| {
+                        (extern_crate_name, &libs_to_bundle[package_id].1)
+                    })
+                    .collect::<BTreeMap<_, _>>();
+                let key = serde_json::json!({
+                    "cargo-equip": env!("CARGO_PKG_VERSION"),
+                    "package-id": pkg,
+                    "features": features[pkg].iter().sorted().collect::<Vec<_>>(),
+                    "cfgs": cfg_env(pkg).map(|_| format!("{:?} {:?}", target_preds, debug_assertions)),
+                    "pseudo-extern-crate-name": pseudo_extern_crate_name,
+                    "extern-crate-names": extern_crate_names,
+                    "mod-path": cargo_equip_mod_name.to_string(),
+                    "root-edition": root_crate.package().edition,
+                    "remove-docs": remove.contains(&Remove::Docs),
+                    "remove-comments": remove.contains(&Remove::Comments),
+                    "minify-libs": minify == Minify::Libs,
+                });
+                Ok((*pkg, key.to_string()))
+            })
+            .collect::<anyhow::Result<HashMap<_, _>>>()?
+    } else {
+        hashmap!()
+    };
+
+    let mut cached_libs = lib_cache_keys
+        .iter()
+        .flat_map(|(pkg, key)| Some((*pkg, cache::load(cache_dir, &metadata[pkg], key)?)))
+        .collect::<HashMap<_, _>>();
+
+    let new_edit = |pkg: &cm::PackageId, krate: &cm::Target| {
+        CodeEdit::new(
+            cargo_equip_mod_name,
+            &krate.src_path,
+            metadata[pkg].manifest_dir(),
+            out_dirs.get(pkg).map(|out_dir| &**out_dir),
+            cfg_env(pkg).as_ref(),
+            || (krate.crate_name(), &pkg.repr),
+        )
+    };
+
+    let mut libs = libs_to_bundle
         .iter()
+        .filter(|(pkg, _)| !cached_libs.contains_key(*pkg))
         .map(|(pkg, (krate, pseudo_extern_crate_name))| {
-            let mut edit = CodeEdit::new(cargo_equip_mod_name, &krate.src_path, || {
-                (krate.crate_name(), &pkg.repr)
-            })?;
-            if let Some(out_dir) = out_dirs.get(pkg) {
-                edit.expand_includes(out_dir)?;
-            }
+            let edit = new_edit(pkg, krate)?;
             Ok((*pkg, (*krate, &**pseudo_extern_crate_name, edit)))
         })
         .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
 
+    let root_lib = root_crate
+        .bin_like()
+        .is_none()
+        .then(|| &root_crate.package().id);
+
+    // For a snippet, the modules are pruned as if a bin used the whole module of the root library.
+    let (root_code, prune) = match (snippet, root_lib) {
+        (Some(mod_name), Some(root_lib)) => {
+            let (_, pseudo_extern_crate_name, edit) = libs.get_mut(root_lib).unwrap();
+            let root_code = edit.uses_of_mod_tree(pseudo_extern_crate_name, mod_name)?;
+            (root_code, Prune::Mods)
+        }
+        _ => (code.clone(), prune),
+    };
+    let unprunable_lib = root_lib.filter(|_| snippet.is_none());
+
+    if prune == Prune::Items {
+        rust::prune_unused_items(
+            &root_code,
+            &mut libs
+                .iter_mut()
+                .filter(|(_, (krate, _, _))| krate.is_lib())
+                .map(|(pkg, (_, _, edit))| (edit, Some(*pkg) != unprunable_lib))
+                .collect::<Vec<_>>(),
+        )?;
+    }
+
+    let libs_to_bundle = &if prune == Prune::Mods {
+        let lib_indices = libs
+            .iter()
+            .filter(|(_, (krate, _, _))| krate.is_lib())
+            .enumerate()
+            .map(|(i, (pkg, _))| (*pkg, i))
+            .collect::<HashMap<_, _>>();
+
+        let reached = rust::prune_unused_mods(
+            cargo_equip_mod_name,
+            &root_code,
+            &mut libs
+                .iter_mut()
+                .filter(|(_, (krate, _, _))| krate.is_lib())
+                .map(|(pkg, (_, pseudo_extern_crate_name, edit))| {
+                    let extern_crate_names = metadata
+                        .libs_with_extern_crate_names(pkg, &lib_indices.keys().copied().collect())?
+                        .into_iter()
+                        .map(|(pkg, extern_crate_name)| (extern_crate_name, lib_indices[pkg]))
+                        .collect();
+                    Ok(rust::LibToPrune {
+                        edit,
+                        pseudo_extern_crate_name,
+                        extern_crate_names,
+                        prunable: Some(*pkg) != unprunable_lib,
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+        )?;
+
+        let mut kept = lib_indices
+            .iter()
+            .filter(|(_, &i)| reached[i])
+            .map(|(pkg, _)| *pkg)
+            .collect::<HashSet<_>>();
+
+        // A procedural macro crate is kept if the root crate or any kept library depends on it.
+        let (graph, indices) = normal_non_host_dep_graph(&resolve_nodes, libs_to_bundle);
+        for goal in libs_to_bundle.keys() {
+            if metadata[goal].has_proc_macro() {
+                let used_by_root = resolve_nodes[&root_crate.package().id]
+                    .deps
+                    .iter()
+                    .any(|cm::NodeDep { pkg, .. }| pkg == *goal);
+                let mut dfs = Dfs::new(&graph, indices[goal]);
+                if used_by_root
+                    || iter::from_fn(|| dfs.next(&graph)).any(|next| kept.contains(graph[next]))
+                {
+                    kept.insert(goal);
+                }
+            }
+        }
+
+        for pkg in libs_to_bundle.keys() {
+            if !kept.contains(pkg) {
+                shell.status("Pruning", format!("`{}`", pkg))?;
+            }
+        }
+        libs.retain(|pkg, _| kept.contains(pkg));
+        proc_macro_names.retain(|pkg, _| kept.contains(pkg));
+        libs_to_bundle
+            .iter()
+            .filter(|(pkg, _)| kept.contains(*pkg))
+            .map(|(pkg, (krate, pseudo_extern_crate_name))| {
+                (*pkg, (*krate, pseudo_extern_crate_name.clone()))
+            })
+            .collect()
+    } else {
+        libs_to_bundle.clone()
+    };
+
     let (graph, indices) = normal_non_host_dep_graph(&resolve_nodes, libs_to_bundle);
 
     let libs_using_proc_macros = {
@@ -857,26 +2035,63 @@ fn bundle(
     let libs_with_local_inner_macros = {
         let mut libs_with_local_inner_macros = libs
             .keys()
+            .chain(cached_libs.keys())
             .map(|pkg| (*pkg, btreeset!()))
             .collect::<HashMap<_, _>>();
-        for (goal, (_, pseudo_extern_crate_name, edit)) in &libs {
-            if edit.has_local_inner_macros_attr() {
+        let goals = libs
+            .iter()
+            .filter(|(_, (_, _, edit))| edit.has_local_inner_macros_attr())
+            .map(|(goal, (_, pseudo_extern_crate_name, _))| (*goal, *pseudo_extern_crate_name))
+            .chain(
+                cached_libs
+                    .iter()
+                    .filter(|(_, lib)| lib.has_local_inner_macros_attr)
+                    .map(|(goal, _)| (*goal, &*libs_to_bundle[goal].1)),
+            )
+            .collect::<Vec<_>>();
+        for (goal, pseudo_extern_crate_name) in goals {
+            libs_with_local_inner_macros
+                .get_mut(goal)
+                .unwrap()
+                .insert(pseudo_extern_crate_name);
+            let mut dfs = Dfs::new(&graph, indices[goal]);
+            while let Some(next) = dfs.next(&graph) {
                 libs_with_local_inner_macros
-                    .get_mut(*goal)
+                    .get_mut(graph[next])
                     .unwrap()
-                    .insert(&**pseudo_extern_crate_name);
-                let mut dfs = Dfs::new(&graph, indices[goal]);
-                while let Some(next) = dfs.next(&graph) {
-                    libs_with_local_inner_macros
-                        .get_mut(graph[next])
-                        .unwrap()
-                        .insert(&**pseudo_extern_crate_name);
-                }
+                    .insert(pseudo_extern_crate_name);
             }
         }
         libs_with_local_inner_macros
     };
 
+    // A cached library is transformed again if it now sees other libraries with
+    // `#[macro_export(local_inner_macros)]`.
+    let outdated_libs = cached_libs
+        .iter()
+        .filter(|(pkg, lib)| {
+            !libs_with_local_inner_macros[*pkg]
+                .iter()
+                .copied()
+                .eq(lib.libs_with_local_inner_macros.iter().map(|s| &**s))
+        })
+        .map(|(pkg, _)| *pkg)
+        .collect::<Vec<_>>();
+    for pkg in outdated_libs {
+        cached_libs.remove(pkg);
+        let (krate, pseudo_extern_crate_name) = &libs_to_bundle[pkg];
+        let edit = new_edit(pkg, krate)?;
+        libs.insert(pkg, (*krate, &**pseudo_extern_crate_name, edit));
+    }
+
+    let has_local_inner_macros_attr = libs
+        .iter()
+        .filter(|(_, (_, _, edit))| edit.has_local_inner_macros_attr())
+        .map(|(pkg, _)| *pkg)
+        .collect::<HashSet<_>>();
+
+    let mut lib_warnings = HashMap::new();
+
     let libs = libs
         .into_iter()
         .map(
@@ -935,8 +2150,6 @@ fn bundle(
                     ));
                 }
 
-                let cm::Node { features, .. } = resolve_nodes[&lib_package.id];
-
                 let translate_extern_crate_name = |dst: &_| -> _ {
                     let dst_package =
                         metadata.dep_lib_by_extern_crate_name(&lib_package.id, dst)?;
@@ -953,10 +2166,19 @@ fn bundle(
 
                 edit.translate_crate_path(pseudo_extern_crate_name)?;
                 edit.translate_extern_crate_paths(translate_extern_crate_name)?;
+                let num_warnings = shell.warnings().len();
                 edit.process_extern_crates_in_lib(translate_extern_crate_name, shell)?;
+                lib_warnings.insert(&lib_package.id, shell.warnings()[num_warnings..].to_vec());
                 let macro_mod_content = edit.modify_declarative_macros(pseudo_extern_crate_name)?;
+                let lib_edition = lib_package.edition()?;
+                if lib_edition < Edition::Edition2024 && root_edition >= Edition::Edition2024 {
+                    edit.migrate_to_rust_2024().with_context(|| {
+                        format!("could not bundle `{}` into Rust 2024 code", lib_package.id)
+                    })?;
+                }
                 let prelude_mod_content = edit.resolve_pseudo_prelude(
                     pseudo_extern_crate_name,
+                    lib_edition >= Edition::Edition2024 && root_edition < Edition::Edition2024,
                     &libs_with_local_inner_macros[&lib_package.id],
                     &{
                         metadata
@@ -975,8 +2197,8 @@ fn bundle(
                             .collect::<anyhow::Result<_>>()?
                     },
                 )?;
-                if resolve_cfgs {
-                    edit.resolve_cfgs(features)?;
+                if let Some(cfg_env) = &cfg_env(&lib_package.id) {
+                    edit.resolve_cfgs(cfg_env)?;
                 }
                 if remove.contains(&Remove::Docs) {
                     edit.allow_missing_docs();
@@ -1001,6 +2223,78 @@ fn bundle(
         )
         .collect::<anyhow::Result<Vec<(&str, (&cm::Package, String, String, String))>>>()?;
 
+    let mut libs = libs
+        .into_iter()
+        .map(
+            |(pseudo_extern_crate_name, (lib_package, crate_mod, macro_mod, prelude_mod))| {
+                let minify = |code: String| -> anyhow::Result<_> {
+                    Ok(if minify == Minify::Libs {
+                        rustminify::minify_file(&rust::parse_file(&code)?)
+                    } else {
+                        code
+                    })
+                };
+                let (crate_mod, macro_mod, prelude_mod) =
+                    (minify(crate_mod)?, minify(macro_mod)?, minify(prelude_mod)?);
+
+                if let Some(key) = lib_cache_keys.get(&lib_package.id) {
+                    let lib = &cache::TransformedLib {
+                        has_local_inner_macros_attr: has_local_inner_macros_attr
+                            .contains(&lib_package.id),
+                        libs_with_local_inner_macros: libs_with_local_inner_macros[&lib_package.id]
+                            .iter()
+                            .map(|&s| s.to_owned())
+                            .collect(),
+                        crate_mod_content: crate_mod.clone(),
+                        macro_mod_content: macro_mod.clone(),
+                        prelude_mod_content: prelude_mod.clone(),
+                        warnings: lib_warnings.remove(&lib_package.id).unwrap_or_default(),
+                    };
+                    cache::save(cache_dir, lib_package, key, lib)?;
+                }
+                Ok((
+                    pseudo_extern_crate_name,
+                    (lib_package, crate_mod, macro_mod, prelude_mod),
+                ))
+            },
+        )
+        .chain(cached_libs.into_iter().map(|(pkg, lib)| {
+            let (_, pseudo_extern_crate_name) = &libs_to_bundle[pkg];
+            for warning in &lib.warnings {
+                shell.warn(warning)?;
+            }
+            Ok((
+                &**pseudo_extern_crate_name,
+                (
+                    &metadata[pkg],
+                    lib.crate_mod_content,
+                    lib.macro_mod_content,
+                    lib.prelude_mod_content,
+                ),
+            ))
+        }))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    libs.sort_by(|(_, (p1, ..)), (_, (p2, ..))| p1.id.cmp(&p2.id));
+
+    report.bundled = libs
+        .iter()
+        .filter(|(_, (p, _, _, _))| p.has_lib())
+        .map(|(k, (p, _, _, _))| report::Package::new(p, k))
+        .collect();
+    report.proc_macros = libs
+        .iter()
+        .filter(|(_, (p, _, _, _))| p.has_proc_macro())
+        .map(|(k, (p, _, _, _))| report::ProcMacro {
+            package: report::Package::new(p, k),
+            macros: proc_macro_names
+                .get(&p.id)
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect(),
+        })
+        .collect();
+
     if !libs.is_empty() {
         if !root_crate.package().authors.is_empty() {
             shell.warn(
@@ -1103,15 +2397,19 @@ fn bundle(
                     p.has_lib() && !metadata.workspace_members.contains(&p.id)
                 })
                 .map(|(_, (lib_package, _, _, _))| {
-                    shell.status("Checking", format!("the license of `{}`", lib_package.id))?;
-                    lib_package
-                        .read_license_text(mine, cache_dir)
-                        .map(|license_text| {
-                            license_text.map(|license_text| (&lib_package.id, license_text))
-                        })
+                    let key = (lib_package.id.clone(), mine.to_owned());
+                    let license_text = if let Some(license_text) = license_texts.get(&key) {
+                        license_text.clone()
+                    } else {
+                        shell.status("Checking", format!("the license of `{}`", lib_package.id))?;
+                        let license_text = lib_package.read_license_text(mine, cache_dir)?;
+                        license_texts.insert(key, license_text.clone());
+                        license_text
+                    };
+                    Ok(license_text.map(|license_text| (&lib_package.id, license_text)))
                 })
                 .flat_map(Result::transpose)
-                .collect::<Result<Vec<_>, _>>()?;
+                .collect::<anyhow::Result<Vec<_>>>()?;
 
             if !notices.is_empty() {
                 doc += "\n # License and Copyright Notices\n";
@@ -1172,7 +2470,7 @@ fn bundle(
                     *code += "        pub mod ";
                     *code += pseudo_extern_crate_name;
                     *code += " {";
-                    *code += &rustminify::minify_file(&rust::parse_file(mod_content)?);
+                    *code += mod_content;
                     *code += "}\n";
                 }
             } else {
@@ -1307,6 +2605,39 @@ fn normal_non_host_dep_graph<'cm>(
     (graph, indices)
 }
 
+/// A target to bundle with the options and the dependencies resolved for it.
+struct Job<'cm> {
+    root_crate: RootCrate<'cm>,
+    output: Option<PathBuf>,
+    libs_to_bundle: BTreeMap<&'cm cm::PackageId, (&'cm cm::Target, String)>,
+    excluded: Vec<&'cm cm::PackageId>,
+    unused_libs: Vec<&'cm cm::PackageId>,
+    unused_deps: Vec<String>,
+    features: Rc<HashMap<&'cm cm::PackageId, Vec<String>>>,
+    target: Option<String>,
+    target_cfgs: Rc<Vec<cfg_expr::Expression>>,
+    debug_assertions: Option<bool>,
+    exclude: Vec<PkgSpec>,
+    mine: Vec<User>,
+    cargo_equip_mod_name: syn::Ident,
+    remove: Vec<Remove>,
+    minify: Minify,
+    prune: Prune,
+    max_size: Option<usize>,
+    resolve_cfgs: bool,
+    rustfmt: bool,
+    check: bool,
+    toolchain_for_proc_macro_srv: Option<String>,
+}
+
+fn clone_pkg_spec(spec: &PkgSpec) -> PkgSpec {
+    PkgSpec {
+        name: spec.name.clone(),
+        version: spec.version.clone(),
+        url: spec.url.clone(),
+    }
+}
+
 #[derive(Clone, Copy)]
 enum RootCrate<'cm> {
     BinLike(&'cm cm::Package, &'cm cm::Target),
@@ -1333,3 +2664,380 @@ impl<'cm> RootCrate<'cm> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        shell::Shell,
+        workspace::{fixture, TargetExt as _},
+        Job, Minify, Prune, Remove, RootCrate, SnippetFormat, VersionMismatch,
+    };
+    use cargo_metadata as cm;
+    use indoc::indoc;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::{
+        cell::RefCell,
+        io::{self, Write},
+        path::Path,
+        rc::Rc,
+    };
+
+    /// Builds a `Job` bundling the first targets of `libs_to_bundle` into the first target of
+    /// `root`.
+    pub(crate) fn job<'cm>(
+        root: &'cm cm::Package,
+        libs_to_bundle: &[(&'cm cm::Package, &str)],
+        excluded: &[&'cm cm::Package],
+        unused_libs: &[&'cm cm::Package],
+    ) -> Job<'cm> {
+        let root_target = &root.targets[0];
+        Job {
+            root_crate: if root_target.is_lib() {
+                RootCrate::Lib(root, root_target)
+            } else {
+                RootCrate::BinLike(root, root_target)
+            },
+            output: None,
+            libs_to_bundle: libs_to_bundle
+                .iter()
+                .map(|(p, pseudo_extern_crate_name)| {
+                    (
+                        &p.id,
+                        (&p.targets[0], (*pseudo_extern_crate_name).to_owned()),
+                    )
+                })
+                .collect(),
+            excluded: excluded.iter().map(|p| &p.id).collect(),
+            unused_libs: unused_libs.iter().map(|p| &p.id).collect(),
+            unused_deps: vec![],
+            features: Default::default(),
+            target: None,
+            target_cfgs: Default::default(),
+            debug_assertions: None,
+            exclude: vec![],
+            mine: vec![],
+            cargo_equip_mod_name: syn::parse_quote!(__cargo_equip),
+            remove: vec![],
+            minify: Minify::None,
+            prune: Prune::None,
+            max_size: None,
+            resolve_cfgs: true,
+            rustfmt: true,
+            check: true,
+            toolchain_for_proc_macro_srv: None,
+        }
+    }
+
+    #[test]
+    fn check_version_mismatch() -> anyhow::Result<()> {
+        let versions = &["0.1.3".parse()?, "0.2.0".parse()?];
+
+        let metadata = fixture::metadata(json!([
+            { "name": "a", "dependencies": [{ "name": "b", "req": "^0.1" }] },
+            { "name": "b" },
+        ]));
+        let shell = &mut Shell::from_stdout(Box::new(io::sink()));
+        super::check_version_mismatch(
+            &metadata,
+            &fixture::package_id("b"),
+            versions,
+            VersionMismatch::Bundle,
+            shell,
+        )?;
+        assert_eq!(
+            vec![
+                "`b v0.1.0` does not match the excluded versions `0.1.3`, `0.2.0`. bundling it \
+                 anyway. to use the excluded version instead, run `cargo update -p b@0.1.0 \
+                 --precise 0.1.3`"
+                    .to_owned(),
+            ],
+            shell.take_warnings(),
+        );
+
+        let metadata = fixture::metadata(json!([
+            { "name": "a", "dependencies": [{ "name": "b", "req": "^0.1" }, { "name": "c" }] },
+            { "name": "b" },
+            { "name": "c", "dependencies": [{ "name": "b", "req": "<0.1.2" }] },
+        ]));
+        let err = super::check_version_mismatch(
+            &metadata,
+            &fixture::package_id("b"),
+            versions,
+            VersionMismatch::Fail,
+            shell,
+        )
+        .unwrap_err();
+        assert_eq!(
+            "`b v0.1.0` does not match the excluded versions `0.1.3`, `0.2.0`\n\
+             \n\
+             help: to use the excluded version instead, edit `Cargo.toml` so that `^0.1` from \
+             `a`, `<0.1.2` from `c` accept one of them\n\
+             help: to bundle it anyway, run with `--on-version-mismatch bundle`",
+            err.to_string(),
+        );
+        assert_eq!(Vec::<String>::new(), shell.take_warnings());
+        Ok(())
+    }
+
+    #[test]
+    fn render_vscode_snippets() -> anyhow::Result<()> {
+        let snippets = [(
+            "a".to_owned(),
+            "macro_rules! m {\n    ($x:expr) => { println!(\"\\\\{}\", $x) };\n}\n".to_owned(),
+        )];
+        let output = super::render_snippets(SnippetFormat::Vscode, &snippets)?;
+        assert_eq!(
+            json!({
+                "a": {
+                    "prefix": "a",
+                    "body": [
+                        "macro_rules! m {",
+                        "    (\\$x:expr) => { println!(\"\\\\\\\\{}\", \\$x) };",
+                        "}",
+                    ],
+                    "description": "`a` bundled by cargo-equip",
+                },
+            }),
+            serde_json::from_str::<serde_json::Value>(&output)?,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn list_libs() -> anyhow::Result<()> {
+        let metadata = fixture::metadata(json!([
+            {
+                "name": "bin",
+                "kind": "bin",
+                "dependencies": [{ "name": "a" }, { "name": "m" }, { "name": "x" }, { "name": "u" }],
+            },
+            { "name": "a", "license": "CC0-1.0", "dependencies": [{ "name": "b" }] },
+            { "name": "b", "license": "MIT OR Apache-2.0" },
+            { "name": "m", "kind": "proc-macro", "license": "MIT" },
+            { "name": "x", "license": "MIT" },
+            { "name": "u" },
+        ]));
+        let [bin, a, b, m, x, u] = &*metadata.packages else {
+            unreachable!();
+        };
+        let bin_job = &job(
+            bin,
+            &[(a, "__a_0_1_0"), (b, "__b_0_1_0"), (m, "__m_0_1_0")],
+            &[x],
+            &[u],
+        );
+        assert_eq!(
+            indoc! {"
+                package                     as                                      license           reason
+                a 0.1.0 (path+file:///ws/a) crate::__cargo_equip::crates::__a_0_1_0 CC0-1.0           direct dependency
+                b 0.1.0 (path+file:///ws/b) crate::__cargo_equip::crates::__b_0_1_0 MIT OR Apache-2.0 transitive dependency
+                m 0.1.0 (path+file:///ws/m) crate::__cargo_equip::crates::__m_0_1_0 MIT               procedural macro
+                x 0.1.0 (path+file:///ws/x)                                         MIT               excluded
+                u 0.1.0 (path+file:///ws/u)                                         **missing**       removed with cargo-udeps
+            "},
+            list(&metadata, bin_job)?,
+        );
+
+        let metadata = fixture::metadata(json!([
+            { "name": "lib", "license": "CC0-1.0", "dependencies": [{ "name": "a" }] },
+            { "name": "a", "license": "CC0-1.0" },
+        ]));
+        let [lib, a] = &*metadata.packages else {
+            unreachable!();
+        };
+        let lib_job = &job(lib, &[(lib, "__lib_0_1_0"), (a, "__a_0_1_0")], &[], &[]);
+        assert_eq!(
+            indoc! {"
+                package                         as                                        license reason
+                a 0.1.0 (path+file:///ws/a)     crate::__cargo_equip::crates::__a_0_1_0   CC0-1.0 direct dependency
+                lib 0.1.0 (path+file:///ws/lib) crate::__cargo_equip::crates::__lib_0_1_0 CC0-1.0 root package
+            "},
+            list(&metadata, lib_job)?,
+        );
+        return Ok(());
+
+        fn list(metadata: &cm::Metadata, job: &Job<'_>) -> anyhow::Result<String> {
+            let stdout = Rc::new(RefCell::new(vec![]));
+            let shell = &mut Shell::from_stdout(Box::new(Writer(stdout.clone())));
+            super::list_libs(metadata, job, shell)?;
+            let stdout = String::from_utf8(stdout.borrow().clone())?;
+            // Without the left border, which `indoc!` would strip.
+            Ok(stdout
+                .lines()
+                .flat_map(|line| [line.strip_prefix(' ').unwrap_or(line), "\n"])
+                .collect())
+        }
+
+        struct Writer(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for Writer {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.borrow_mut().flush()
+            }
+        }
+    }
+
+    #[test]
+    fn shrink_to_max_size() -> anyhow::Result<()> {
+        let cargo_equip_mod_name = &syn::parse_quote!(__cargo_equip);
+        let shell = &mut Shell::from_stdout(Box::new(io::sink()));
+
+        // Each option shrinks the code by 10 bytes, and `--minify all` by 20 bytes.
+        let mut shrink = |max_size, remove: &mut Vec<_>, minify: &mut _, rustfmt: &mut _| {
+            let mut calls = vec![];
+            let result = super::shrink_to_max_size(
+                max_size,
+                cargo_equip_mod_name,
+                (remove, minify, rustfmt),
+                |remove: &[Remove], minify, rustfmt, _: &mut Shell| {
+                    calls.push((remove.to_owned(), minify, rustfmt));
+                    let size = 200
+                        - 10 * remove.len()
+                        - match minify {
+                            Minify::None => 0,
+                            Minify::Libs => 10,
+                            Minify::All => 20,
+                        }
+                        - if rustfmt { 0 } else { 10 };
+                    let code = "mod __cargo_equip { pub(crate) mod crates { pub mod a {} } }";
+                    Ok(format!("{}{}", code, " ".repeat(size - code.len())))
+                },
+                shell,
+            );
+            (result, calls)
+        };
+
+        let (remove, minify, rustfmt) = &mut (vec![], Minify::None, true);
+        let (result, calls) = shrink(None, remove, minify, rustfmt);
+        assert_eq!(200, result?.len());
+        assert_eq!([(vec![], Minify::None, true)], *calls);
+
+        let (remove, minify, rustfmt) = &mut (vec![], Minify::None, true);
+        let (result, calls) = shrink(Some(200), remove, minify, rustfmt);
+        assert_eq!(200, result?.len());
+        assert_eq!(1, calls.len());
+
+        let (remove, minify, rustfmt) = &mut (vec![], Minify::None, true);
+        let (result, calls) = shrink(Some(175), remove, minify, rustfmt);
+        assert_eq!(170, result?.len());
+        assert_eq!(
+            [
+                (vec![], Minify::None, true),
+                (vec![Remove::Docs], Minify::None, true),
+                (vec![Remove::Docs, Remove::Comments], Minify::None, true),
+                (vec![Remove::Docs, Remove::Comments], Minify::Libs, true),
+            ],
+            *calls,
+        );
+        assert_eq!(
+            (
+                &mut vec![Remove::Docs, Remove::Comments],
+                &mut Minify::Libs,
+                &mut true
+            ),
+            (remove, minify, rustfmt),
+        );
+
+        let (remove, minify, rustfmt) = &mut (vec![Remove::Comments], Minify::None, true);
+        let (result, calls) = shrink(Some(100), remove, minify, rustfmt);
+        assert_eq!(
+            [
+                (vec![Remove::Comments], Minify::None, true),
+                (vec![Remove::Comments, Remove::Docs], Minify::None, true),
+                (vec![Remove::Comments, Remove::Docs], Minify::Libs, true),
+                (vec![Remove::Comments, Remove::Docs], Minify::All, false),
+            ],
+            *calls,
+        );
+        assert_eq!(
+            "the bundled code is 150 bytes, which is larger than `--max-size 100` even with \
+             `--remove docs --remove comments --minify all --no-rustfmt`\n\
+             \n\
+             - `crate::__cargo_equip::crates::a`: 12 bytes\n\
+             - the rest: 138 bytes",
+            result.unwrap_err().to_string(),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn equip_each() -> anyhow::Result<()> {
+        let metadata = fixture::metadata(json!([
+            {
+                "name": "a",
+                "targets": [
+                    { "name": "x", "kind": "bin" },
+                    { "name": "y", "kind": "bin" },
+                    { "name": "z", "kind": "bin" },
+                    { "name": "e", "kind": "example" },
+                ],
+            },
+        ]));
+        let package = &metadata.packages[0];
+        let output_dir = Path::new("/out");
+
+        let jobs = package
+            .targets
+            .iter()
+            .map(|target| {
+                let mut job = job(package, &[], &[], &[]);
+                job.root_crate = RootCrate::BinLike(package, target);
+                job.output = Some(super::output_in_dir(output_dir, target));
+                job
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            [
+                output_dir.join("x.rs"),
+                output_dir.join("y.rs"),
+                output_dir.join("z.rs"),
+                output_dir.join("examples").join("e.rs"),
+            ],
+            *jobs
+                .iter()
+                .map(|j| j.output.clone().unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        let shell = &mut Shell::from_stdout(Box::new(io::sink()));
+        let mut equipped = vec![];
+        let mut reports = vec![];
+        let result = super::equip_each(
+            &jobs,
+            |job, _, shell| {
+                let (_, root) = job.root_crate.split();
+                equipped.push(root.name.clone());
+                if root.name == "y" || root.name == "e" {
+                    shell.warn(format!("`{}` is broken", root.name))?;
+                    anyhow::bail!("could not bundle `{}`", root.name);
+                }
+                Ok(())
+            },
+            |targets| {
+                reports = targets;
+                Ok(())
+            },
+            shell,
+        );
+        assert_eq!(["x", "y", "z", "e"], *equipped);
+        assert_eq!("could not bundle `y`, `e`", result.unwrap_err().to_string(),);
+        assert_eq!(
+            json!([
+                { "name": "x", "error": null, "warnings": [] },
+                { "name": "y", "error": "could not bundle `y`", "warnings": ["`y` is broken"] },
+                { "name": "z", "error": null, "warnings": [] },
+                { "name": "e", "error": "could not bundle `e`", "warnings": ["`e` is broken"] },
+            ]),
+            json!(reports
+                .iter()
+                .map(|r| json!({ "name": r.name, "error": r.error, "warnings": r.warnings }))
+                .collect::<Vec<_>>()),
+        );
+        Ok(())
+    }
+}
@@ -33,18 +33,7 @@ fn exit_with_error(err: anyhow::Error, shell: &mut Shell) -> ! {
         err.exit();
     }
 
-    let _ = shell.error(&err);
-
-    for cause in err.chain().skip(1) {
-        let _ = writeln!(shell.err(), "\nCaused by:");
-
-        for line in cause.to_string().lines() {
-            let _ = match line {
-                "" => writeln!(shell.err()),
-                line => writeln!(shell.err(), "  {}", line),
-            };
-        }
-    }
+    let _ = shell.report(&err);
 
     std::process::exit(1);
 }
@@ -0,0 +1,169 @@
+use anyhow::{bail, Context as _};
+use itertools::Itertools as _;
+use krates::PkgSpec;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+pub(crate) static BUILTIN_PRESETS: &[(&str, &str)] = &[
+    ("atcoder", include_str!("../presets/atcoder.toml")),
+    (
+        "atcoder-202301",
+        include_str!("../presets/atcoder-202301.toml"),
+    ),
+    ("codingame", include_str!("../presets/codingame.toml")),
+];
+
+/// A set of crates that a judge provides.
+pub(crate) struct Preset {
+    pub(crate) exclude: Vec<PkgSpec>,
+}
+
+impl Preset {
+    fn parse(toml: &str) -> anyhow::Result<Self> {
+        let PresetToml { exclude, .. } = toml::from_str(toml)?;
+
+        let exclude = exclude
+            .iter()
+            .map(|spec| {
+                spec.parse()
+                    .with_context(|| format!("invalid package ID specification `{}`", spec))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        return Ok(Self { exclude });
+
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct PresetToml {
+            #[allow(dead_code)]
+            description: Option<String>,
+            exclude: Vec<String>,
+        }
+    }
+}
+
+/// Directories to look for `<name>.toml` in, before the built-in presets.
+fn user_preset_dirs(cache_dir: &Path) -> Vec<PathBuf> {
+    dirs_next::config_dir()
+        .map(|config_dir| config_dir.join("cargo-equip").join("presets"))
+        .into_iter()
+        .chain([cache_dir.join("presets")])
+        .collect()
+}
+
+/// Finds a preset by name.
+///
+/// User presets take precedence over the built-in ones, so that judges can be updated without a
+/// new release.
+pub(crate) fn find(name: &str, cache_dir: &Path) -> anyhow::Result<Preset> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("invalid preset name: {:?}", name);
+    }
+
+    for dir in user_preset_dirs(cache_dir) {
+        let path = dir.join(name).with_extension("toml");
+        if path.exists() {
+            let toml = cargo_util::paths::read(&path)?;
+            return Preset::parse(&toml)
+                .with_context(|| format!("could not parse `{}`", path.display()));
+        }
+    }
+
+    if let Some((_, toml)) = BUILTIN_PRESETS.iter().find(|(n, _)| *n == name) {
+        return Preset::parse(toml)
+            .with_context(|| format!("could not parse the built-in preset `{}`", name));
+    }
+
+    bail!(
+        "no such preset `{}`. available presets are the built-in ones ({}) and `<name>.toml`s in \
+         {}",
+        name,
+        BUILTIN_PRESETS
+            .iter()
+            .map(|(n, _)| format!("`{}`", n))
+            .format(", "),
+        user_preset_dirs(cache_dir)
+            .iter()
+            .map(|d| format!("`{}`", d.display()))
+            .format(" or "),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BUILTIN_PRESETS;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn builtin_presets() -> anyhow::Result<()> {
+        for (name, toml) in BUILTIN_PRESETS {
+            let preset = super::Preset::parse(toml)
+                .map_err(|e| anyhow::anyhow!("could not parse `{}`: {:#}", name, e))?;
+            assert!(!preset.exclude.is_empty(), "`{}` excludes nothing", name);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn find() -> anyhow::Result<()> {
+        let cache_dir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let cache_dir = cache_dir.path();
+
+        let names = |name| -> anyhow::Result<Vec<String>> {
+            Ok(super::find(name, cache_dir)?
+                .exclude
+                .into_iter()
+                .map(|spec| spec.name)
+                .collect())
+        };
+
+        assert!(names("atcoder")?.contains(&"proconio".to_owned()));
+
+        cargo_util::paths::create_dir_all(cache_dir.join("presets"))?;
+        cargo_util::paths::write(
+            cache_dir.join("presets").join("atcoder.toml"),
+            "exclude = [\"a\", \"b:0.1.0\"]\n",
+        )?;
+        cargo_util::paths::write(
+            cache_dir.join("presets").join("mine.toml"),
+            "description = \"Mine\"\nexclude = [\"c\"]\n",
+        )?;
+        cargo_util::paths::write(
+            cache_dir.join("presets").join("broken.toml"),
+            "excluded = [\"c\"]\n",
+        )?;
+        assert_eq!(["a", "b"], *names("atcoder")?);
+        assert_eq!(["c"], *names("mine")?);
+        assert!(
+            format!("{:#}", names("broken").unwrap_err()).starts_with(&format!(
+                "could not parse `{}`: ",
+                cache_dir.join("presets").join("broken.toml").display(),
+            ))
+        );
+
+        let err = names("nope").unwrap_err().to_string();
+        assert!(
+            err.starts_with(
+                "no such preset `nope`. available presets are the built-in ones (`atcoder`, \
+                 `atcoder-202301`, `codingame`) and `<name>.toml`s in ",
+            ),
+            "{}",
+            err,
+        );
+        assert!(err.contains(&cache_dir.join("presets").display().to_string()));
+
+        for name in ["", "../atcoder", "a/b", "a.toml"] {
+            assert_eq!(
+                format!("invalid preset name: {:?}", name),
+                names(name).unwrap_err().to_string(),
+            );
+        }
+        Ok(())
+    }
+}
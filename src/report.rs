@@ -0,0 +1,223 @@
+use crate::{shell::Shell, workspace::TargetExt as _};
+use camino::Utf8PathBuf;
+use cargo_metadata as cm;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// What `--report` writes.
+#[derive(Serialize)]
+pub(crate) struct Report {
+    pub(crate) targets: Vec<Target>,
+    pub(crate) warnings: Vec<String>,
+}
+
+impl Report {
+    pub(crate) fn write(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            cargo_util::paths::create_dir_all(parent)?;
+        }
+        cargo_util::paths::write(path, serde_json::to_string_pretty(self)? + "\n")
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct Target {
+    pub(crate) package_id: cm::PackageId,
+    pub(crate) name: String,
+    pub(crate) kind: &'static str,
+    pub(crate) src_path: Utf8PathBuf,
+    pub(crate) output: Option<PathBuf>,
+    pub(crate) size: Option<usize>,
+    pub(crate) error: Option<String>,
+    pub(crate) bundled: Vec<Package>,
+    pub(crate) proc_macros: Vec<ProcMacro>,
+    pub(crate) excluded: Vec<cm::PackageId>,
+    pub(crate) unused_deps: Vec<String>,
+    pub(crate) warnings: Vec<String>,
+}
+
+impl Target {
+    pub(crate) fn new(
+        package: &cm::Package,
+        target: &cm::Target,
+        output: Option<PathBuf>,
+        excluded: Vec<cm::PackageId>,
+        unused_deps: Vec<String>,
+    ) -> Self {
+        Self {
+            package_id: package.id.clone(),
+            name: target.name.clone(),
+            kind: if target.is_lib() {
+                "lib"
+            } else if target.is_example() {
+                "example"
+            } else {
+                "bin"
+            },
+            src_path: target.src_path.clone(),
+            output,
+            size: None,
+            error: None,
+            bundled: vec![],
+            proc_macros: vec![],
+            excluded,
+            unused_deps,
+            warnings: vec![],
+        }
+    }
+
+    /// Records the outcome and the warnings of bundling the target.
+    pub(crate) fn finish(&mut self, result: &anyhow::Result<()>, shell: &mut Shell) {
+        self.error = result.as_ref().err().map(|err| format!("{:#}", err));
+        self.warnings = shell.take_warnings();
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct Package {
+    pub(crate) package_id: cm::PackageId,
+    pub(crate) name: String,
+    pub(crate) version: semver::Version,
+    pub(crate) source: Option<String>,
+    pub(crate) license: Option<String>,
+    pub(crate) pseudo_extern_crate_name: String,
+}
+
+impl Package {
+    pub(crate) fn new(package: &cm::Package, pseudo_extern_crate_name: &str) -> Self {
+        Self {
+            package_id: package.id.clone(),
+            name: package.name.clone(),
+            version: package.version.clone(),
+            source: package.source.as_ref().map(|s| s.repr.clone()),
+            license: package.license.clone(),
+            pseudo_extern_crate_name: pseudo_extern_crate_name.to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ProcMacro {
+    #[serde(flatten)]
+    pub(crate) package: Package,
+    pub(crate) macros: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{shell::Shell, workspace::fixture};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::{io, path::PathBuf};
+
+    #[test]
+    fn serialize() -> anyhow::Result<()> {
+        let metadata = fixture::metadata(json!([
+            {
+                "name": "bin",
+                "kind": "bin",
+                "dependencies": [{ "name": "lib" }, { "name": "macros" }, { "name": "excluded" }],
+            },
+            {
+                "name": "lib",
+                "version": "1.2.3",
+                "source": "registry+https://github.com/rust-lang/crates.io-index",
+                "license": "MIT",
+            },
+            { "name": "macros", "kind": "proc-macro", "license": "CC0-1.0" },
+            { "name": "excluded" },
+        ]));
+        let (bin, lib, macros, excluded) = (
+            &metadata.packages[0],
+            &metadata.packages[1],
+            &metadata.packages[2],
+            &metadata.packages[3],
+        );
+
+        let mut target = super::Target::new(
+            bin,
+            &bin.targets[0],
+            Some(PathBuf::from("/ws/bin/bundled.rs")),
+            vec![excluded.id.clone()],
+            vec!["unused".to_owned()],
+        );
+        target.size = Some(42);
+        target.bundled.push(super::Package::new(lib, "__lib_0_1_0"));
+        target.proc_macros.push(super::ProcMacro {
+            package: super::Package::new(macros, "__macros_0_1_0"),
+            macros: vec!["m".to_owned()],
+        });
+        let report = super::Report {
+            targets: vec![target],
+            warnings: vec!["w".to_owned()],
+        };
+
+        assert_eq!(
+            json!({
+                "targets": [{
+                    "package_id": "bin 0.1.0 (path+file:///ws/bin)",
+                    "name": "bin",
+                    "kind": "bin",
+                    "src_path": "/ws/bin/src/lib.rs",
+                    "output": "/ws/bin/bundled.rs",
+                    "size": 42,
+                    "error": null,
+                    "bundled": [{
+                        "package_id": "lib 0.1.0 (path+file:///ws/lib)",
+                        "name": "lib",
+                        "version": "1.2.3",
+                        "source": "registry+https://github.com/rust-lang/crates.io-index",
+                        "license": "MIT",
+                        "pseudo_extern_crate_name": "__lib_0_1_0",
+                    }],
+                    "proc_macros": [{
+                        "package_id": "macros 0.1.0 (path+file:///ws/macros)",
+                        "name": "macros",
+                        "version": "0.1.0",
+                        "source": null,
+                        "license": "CC0-1.0",
+                        "pseudo_extern_crate_name": "__macros_0_1_0",
+                        "macros": ["m"],
+                    }],
+                    "excluded": ["excluded 0.1.0 (path+file:///ws/excluded)"],
+                    "unused_deps": ["unused"],
+                    "warnings": [],
+                }],
+                "warnings": ["w"],
+            }),
+            serde_json::to_value(&report)?,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn finish() -> anyhow::Result<()> {
+        let metadata = fixture::metadata(json!([{ "name": "a", "kind": "bin" }]));
+        let package = &metadata.packages[0];
+        let mut shell = Shell::from_stdout(Box::new(io::sink()));
+
+        shell.warn("ignored")?;
+        shell.take_warnings();
+
+        let mut target = super::Target::new(package, &package.targets[0], None, vec![], vec![]);
+        shell.warn("first")?;
+        shell.warn("second")?;
+        target.finish(&Ok(()), &mut shell);
+        assert_eq!(None, target.error);
+        assert_eq!(["first", "second"], *target.warnings);
+        assert!(shell.take_warnings().is_empty());
+
+        let mut target = super::Target::new(package, &package.targets[0], None, vec![], vec![]);
+        shell.warn("third")?;
+        target.finish(
+            &Err(anyhow::anyhow!("could not bundle").context("`a` failed")),
+            &mut shell,
+        );
+        assert_eq!(
+            Some("`a` failed: could not bundle"),
+            target.error.as_deref(),
+        );
+        assert_eq!(["third"], *target.warnings);
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+mod prune;
+
 use crate::{ra_proc_macro::ProcMacroExpander, shell::Shell};
 use anyhow::{anyhow, bail, Context as _};
 use camino::{Utf8Path, Utf8PathBuf};
@@ -9,32 +11,33 @@ use proc_macro2::{LineColumn, Span, TokenStream, TokenTree};
 use quote::{quote, ToTokens};
 use std::{
     borrow::Cow,
-    collections::{BTreeMap, BTreeSet, VecDeque},
-    env, mem,
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    env, iter, mem,
     ops::Range,
     str,
 };
 use syn::{
+    ext::IdentExt as _,
     parse::{ParseStream, Parser as _},
     parse_quote,
     punctuated::{Pair, Punctuated},
     spanned::Spanned,
     visit::{self, Visit},
-    Arm, AttrStyle, Attribute, BareFnArg, ConstParam, Expr, ExprArray, ExprAssign, ExprAssignOp,
-    ExprAsync, ExprAwait, ExprBinary, ExprBlock, ExprBox, ExprBreak, ExprCall, ExprCast,
-    ExprClosure, ExprContinue, ExprField, ExprForLoop, ExprGroup, ExprIf, ExprIndex, ExprLet,
-    ExprLit, ExprLoop, ExprMacro, ExprMatch, ExprMethodCall, ExprParen, ExprPath, ExprRange,
-    ExprReference, ExprRepeat, ExprReturn, ExprStruct, ExprTry, ExprTryBlock, ExprTuple, ExprType,
-    ExprUnary, ExprUnsafe, ExprWhile, ExprYield, Field, FieldPat, FieldValue, ForeignItemFn,
-    ForeignItemMacro, ForeignItemStatic, ForeignItemType, Ident, ImplItemConst, ImplItemMacro,
-    ImplItemMethod, ImplItemType, Item, ItemConst, ItemEnum, ItemExternCrate, ItemFn,
-    ItemForeignMod, ItemImpl, ItemMacro, ItemMacro2, ItemMod, ItemStatic, ItemStruct, ItemTrait,
-    ItemTraitAlias, ItemType, ItemUnion, ItemUse, LifetimeDef, Lit, LitStr, Local, Macro, Meta,
-    MetaList, MetaNameValue, NestedMeta, PatBox, PatIdent, PatLit, PatMacro, PatOr, PatPath,
-    PatRange, PatReference, PatRest, PatSlice, PatStruct, PatTuple, PatTupleStruct, PatType,
-    PatWild, PathSegment, Receiver, Token, TraitItemConst, TraitItemMacro, TraitItemMethod,
-    TraitItemType, TypeParam, UseGroup, UseName, UsePath, UseRename, UseTree, Variadic, Variant,
-    VisRestricted,
+    Arm, AttrStyle, Attribute, BareFnArg, Block, ConstParam, Expr, ExprArray, ExprAssign,
+    ExprAssignOp, ExprAsync, ExprAwait, ExprBinary, ExprBlock, ExprBox, ExprBreak, ExprCall,
+    ExprCast, ExprClosure, ExprContinue, ExprField, ExprForLoop, ExprGroup, ExprIf, ExprIndex,
+    ExprLet, ExprLit, ExprLoop, ExprMacro, ExprMatch, ExprMethodCall, ExprParen, ExprPath,
+    ExprRange, ExprReference, ExprRepeat, ExprReturn, ExprStruct, ExprTry, ExprTryBlock, ExprTuple,
+    ExprType, ExprUnary, ExprUnsafe, ExprWhile, ExprYield, Field, FieldPat, FieldValue,
+    ForeignItemFn, ForeignItemMacro, ForeignItemStatic, ForeignItemType, Generics, Ident,
+    ImplItemConst, ImplItemMacro, ImplItemMethod, ImplItemType, Item, ItemConst, ItemEnum,
+    ItemExternCrate, ItemFn, ItemForeignMod, ItemImpl, ItemMacro, ItemMacro2, ItemMod, ItemStatic,
+    ItemStruct, ItemTrait, ItemTraitAlias, ItemType, ItemUnion, ItemUse, LifetimeDef, Lit, LitStr,
+    Local, Macro, Meta, MetaList, MetaNameValue, NestedMeta, PatBox, PatIdent, PatLit, PatMacro,
+    PatOr, PatPath, PatRange, PatReference, PatRest, PatSlice, PatStruct, PatTuple, PatTupleStruct,
+    PatType, PatWild, PathSegment, Receiver, ReturnType, Signature, Token, TraitItemConst,
+    TraitItemMacro, TraitItemMethod, TraitItemType, TypeImplTrait, TypeParam, TypeReference,
+    UseGroup, UseName, UsePath, UseRename, UseTree, Variadic, Variant, VisRestricted,
 };
 
 pub(crate) fn find_skip_attribute(code: &str) -> anyhow::Result<bool> {
@@ -66,6 +69,40 @@ pub(crate) fn find_skip_attribute(code: &str) -> anyhow::Result<bool> {
         }))
 }
 
+pub(crate) fn public_mods(src_path: &Utf8Path) -> anyhow::Result<Vec<String>> {
+    let syn::File { items, .. } = parse_file(&cargo_util::paths::read(src_path.as_ref())?)
+        .with_context(|| format!("could not parse `{}`", src_path))?;
+
+    Ok(items
+        .iter()
+        .flat_map(|item| match item {
+            Item::Mod(ItemMod {
+                vis: syn::Visibility::Public(_),
+                ident,
+                ..
+            }) => Some(ident.to_string()),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Inserts `attr` before every item at the top level of `code`.
+pub(crate) fn annotate_items(code: &str, attr: &str) -> anyhow::Result<String> {
+    let syn::File { items, .. } = parse_file(code)?;
+    let code_lines = &code.split('\n').collect::<Vec<_>>();
+
+    let mut code = code.to_owned();
+    for start in items
+        .iter()
+        .map(|item| to_index(code_lines, item.span().start()))
+        .sorted()
+        .rev()
+    {
+        code.insert_str(start, &format!("{}\n", attr));
+    }
+    Ok(code)
+}
+
 pub(crate) fn indent_code(code: &str, n: usize) -> String {
     let is_safe_to_indent = code.parse::<TokenStream>().map_or(false, |token_stream| {
         !token_stream.into_iter().any(|tt| {
@@ -93,6 +130,14 @@ fn replace_ranges(code: &str, replacements: BTreeMap<(LineColumn, LineColumn), S
         return code.to_owned();
     }
     let replacements = replacements.into_iter().collect::<Vec<_>>();
+    debug_assert!(
+        replacements.windows(2).all(|w| w[0].0 .1 <= w[1].0 .0),
+        "overlapping replacements: {:?}",
+        replacements
+            .iter()
+            .map(|(range, _)| range)
+            .collect::<Vec<_>>(),
+    );
     let mut replacements = &*replacements;
     let mut skip_until = None;
     let mut ret = "".to_owned();
@@ -134,7 +179,7 @@ fn replace_ranges(code: &str, replacements: BTreeMap<(LineColumn, LineColumn), S
         }
     }
 
-    debug_assert!(syn::parse_file(code).is_ok());
+    debug_assert!(syn::parse_file(code).is_ok() || syn::parse_str::<Expr>(code).is_ok());
 
     ret
 }
@@ -282,21 +327,156 @@ fn set_span(mask: &mut [FixedBitSet], span: Span, p: bool) {
     }
 }
 
+fn to_range(lines: &[&str], span: Span) -> Range<usize> {
+    to_index(lines, span.start())..to_index(lines, span.end())
+}
+
+fn to_index(lines: &[&str], loc: LineColumn) -> usize {
+    lines[..loc.line - 1]
+        .iter()
+        .map(|s| s.len() + 1)
+        .sum::<usize>()
+        + lines[loc.line - 1]
+            .char_indices()
+            .nth(loc.column)
+            .map(|(i, _)| i)
+            .unwrap_or_else(|| lines[loc.line - 1].len())
+}
+
 pub(crate) fn parse_file(code: &str) -> anyhow::Result<syn::File> {
     syn::parse_file(code)
         .map_err(|e| anyhow!("{}", e))
         .with_context(|| "broke the code during modification")
 }
 
+pub(crate) fn prune_unused_items(
+    root_code: &str,
+    edits: &mut [(&mut CodeEdit<'_>, bool)],
+) -> anyhow::Result<()> {
+    prune::unused_items(root_code, edits)
+}
+
+pub(crate) struct LibToPrune<'a, 'opt> {
+    pub(crate) edit: &'a mut CodeEdit<'opt>,
+    pub(crate) pseudo_extern_crate_name: &'a str,
+    /// Extern crate names to the indices of the other libraries
+    pub(crate) extern_crate_names: HashMap<String, usize>,
+    pub(crate) prunable: bool,
+}
+
+pub(crate) fn prune_unused_mods(
+    cargo_equip_mod_name: &Ident,
+    root_code: &str,
+    libs: &mut [LibToPrune<'_, '_>],
+) -> anyhow::Result<Vec<bool>> {
+    prune::unused_mods(cargo_equip_mod_name, root_code, libs)
+}
+
+/// Measures how many bytes of the bundled code each library takes up in `crates`, `macros` and
+/// `preludes` respectively.
+pub(crate) fn bundled_crate_sizes(
+    cargo_equip_mod_name: &Ident,
+    code: &str,
+) -> anyhow::Result<BTreeMap<String, [usize; 3]>> {
+    let file = parse_file(code)?;
+    let code_lines = &code.split('\n').collect::<Vec<_>>();
+
+    let mut sizes = BTreeMap::<_, [usize; 3]>::new();
+    for item in &file.items {
+        if let Item::Mod(ItemMod {
+            ident,
+            content: Some((_, items)),
+            ..
+        }) = item
+        {
+            if ident != cargo_equip_mod_name {
+                continue;
+            }
+            for item in items {
+                if let Item::Mod(ItemMod {
+                    ident,
+                    content: Some((_, items)),
+                    ..
+                }) = item
+                {
+                    let i = match &*ident.to_string() {
+                        "crates" => 0,
+                        "macros" => 1,
+                        "preludes" => 2,
+                        _ => continue,
+                    };
+                    for item in items {
+                        if let Item::Mod(item_mod) = item {
+                            sizes.entry(item_mod.ident.to_string()).or_default()[i] +=
+                                to_range(code_lines, item_mod.span()).len();
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(sizes)
+}
+
+/// Measures how many bytes of the bundled code the "License and Copyright Notices" section takes
+/// up.
+pub(crate) fn license_notices_size(
+    cargo_equip_mod_name: &Ident,
+    code: &str,
+) -> anyhow::Result<usize> {
+    let file = parse_file(code)?;
+    let code_lines = &code.split('\n').collect::<Vec<_>>();
+
+    let docs = file
+        .items
+        .iter()
+        .flat_map(|item| match item {
+            Item::Mod(ItemMod { attrs, ident, .. }) if ident == cargo_equip_mod_name => Some(attrs),
+            _ => None,
+        })
+        .flatten()
+        .flat_map(|attr| match attr.parse_meta() {
+            Ok(Meta::NameValue(MetaNameValue {
+                path,
+                lit: Lit::Str(lit_str),
+                ..
+            })) if path.is_ident("doc") => Some((attr, lit_str.value())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(
+        match docs
+            .iter()
+            .position(|(_, doc)| doc.trim() == "# License and Copyright Notices")
+        {
+            Some(i) => {
+                let (first, _) = docs[i];
+                let (last, _) = docs[docs.len() - 1];
+                to_index(code_lines, last.span().end()) - to_index(code_lines, first.span().start())
+            }
+            None => 0,
+        },
+    )
+}
+
 pub(crate) fn process_bin<'cm>(
     cargo_equip_mod_name: &Ident,
     src_path: &Utf8Path,
+    manifest_dir: &Utf8Path,
     proc_macro_expander: Option<&mut ProcMacroExpander<'_>>,
     translate_extern_crate_name: impl FnMut(&str) -> Option<String>,
     is_lib_to_bundle: impl FnMut(&str) -> bool,
     context: impl FnOnce() -> (String, &'cm str),
 ) -> anyhow::Result<String> {
-    let mut edit = CodeEdit::new(cargo_equip_mod_name, src_path, context)?;
+    let mut edit = CodeEdit::new(
+        cargo_equip_mod_name,
+        src_path,
+        manifest_dir,
+        None,
+        None,
+        context,
+    )?;
     if let Some(proc_macro_expander) = proc_macro_expander {
         edit.expand_proc_macros(proc_macro_expander)?;
     }
@@ -305,6 +485,78 @@ pub(crate) fn process_bin<'cm>(
     edit.finish()
 }
 
+/// Values that `#[cfg(..)]`s are evaluated with.
+#[derive(Clone, Copy)]
+pub(crate) struct CfgEnv<'a> {
+    pub(crate) features: &'a [String],
+    /// `rustc --print cfg --target <TRIPLE>`. `target_*`, `unix`, `windows` and so on are not
+    /// resolved if this is `None`.
+    pub(crate) target_preds: Option<&'a [cfg_expr::Predicate<'a>]>,
+    /// `debug_assertions` is not resolved if this is `None`.
+    pub(crate) debug_assertions: Option<bool>,
+}
+
+impl CfgEnv<'_> {
+    fn eval(&self, expr: &cfg_expr::Expression) -> Option<bool> {
+        expr.eval(|pred| match pred {
+            cfg_expr::Predicate::Test | cfg_expr::Predicate::ProcMacro => Some(false),
+            cfg_expr::Predicate::Flag("cargo_equip") => Some(true),
+            cfg_expr::Predicate::Feature(feature) => {
+                Some(self.features.contains(&(*feature).to_owned()))
+            }
+            cfg_expr::Predicate::DebugAssertions => self.debug_assertions,
+            cfg_expr::Predicate::Target(_) => self.target_preds.map(|preds| preds.contains(pred)),
+            _ => None,
+        })
+    }
+
+    /// Evaluates the `#[cfg(..)]`s in `attrs`.
+    fn eval_attrs(&self, attrs: &[Attribute]) -> Option<bool> {
+        let mut all_true = true;
+        for attr in attrs {
+            if let Ok(Meta::List(MetaList { path, nested, .. })) = attr.parse_meta() {
+                if path.is_ident("cfg") {
+                    match cfg_expr::Expression::parse(&nested.to_token_stream().to_string())
+                        .ok()
+                        .and_then(|expr| self.eval(&expr))
+                    {
+                        Some(true) => {}
+                        Some(false) => return Some(false),
+                        None => all_true = false,
+                    }
+                }
+            }
+        }
+        all_true.then_some(true)
+    }
+}
+
+/// Splits `#[cfg_attr(<pred>, <attr>, ..)]` into `<pred>` and `<attr>`s.
+fn parse_cfg_attr(attr: &Attribute) -> Option<(cfg_expr::Expression, Vec<TokenStream>)> {
+    if !attr.path.is_ident("cfg_attr") {
+        return None;
+    }
+    let group = match attr.tokens.clone().into_iter().exactly_one() {
+        Ok(TokenTree::Group(group)) => group,
+        _ => return None,
+    };
+    let mut args = vec![TokenStream::new()];
+    for tt in group.stream() {
+        match tt {
+            TokenTree::Punct(p) if p.as_char() == ',' => args.push(TokenStream::new()),
+            tt => args.last_mut().unwrap().extend(iter::once(tt)),
+        }
+    }
+    if args.last().map_or(false, TokenStream::is_empty) {
+        args.pop();
+    }
+    if args.is_empty() {
+        return None;
+    }
+    let pred = cfg_expr::Expression::parse(&args.remove(0).to_string()).ok()?;
+    Some((pred, args))
+}
+
 pub(crate) struct CodeEdit<'opt> {
     cargo_equip_mod_name: &'opt Ident,
     has_local_inner_macros_attr: bool,
@@ -317,86 +569,485 @@ impl<'opt> CodeEdit<'opt> {
     pub(crate) fn new<'cm>(
         cargo_equip_mod_name: &'opt Ident,
         src_path: &Utf8Path,
+        manifest_dir: &Utf8Path,
+        out_dir: Option<&Utf8Path>,
+        cfg_env: Option<&CfgEnv<'_>>,
         err_context: impl FnOnce() -> (String, &'cm str),
     ) -> anyhow::Result<Self> {
         return (|| {
-            Self::from_code(cargo_equip_mod_name, &expand_mods(src_path, 0)?)
-                .map_err(anyhow::Error::from)
+            let include_env = IncludeEnv {
+                src_path,
+                manifest_dir,
+                out_dir,
+            };
+            let code = expand_mods(&include_env, &ModDir::root(src_path), cfg_env)?;
+            Self::from_code(cargo_equip_mod_name, &code).map_err(anyhow::Error::from)
         })()
         .with_context(|| {
             let (crate_name, package_id) = err_context();
             format!("could not expand `{}` from `{}`", crate_name, package_id)
         });
 
-        fn expand_mods(src_path: &Utf8Path, depth: usize) -> anyhow::Result<String> {
+        /// Expands `mod`s, `include!`s, `include_str!`s, and `include_bytes!`s in
+        /// `include_env.src_path`.
+        fn expand_mods(
+            include_env: &IncludeEnv<'_>,
+            mod_dir: &ModDir,
+            cfg_env: Option<&CfgEnv<'_>>,
+        ) -> anyhow::Result<String> {
+            let src_path = include_env.src_path;
             let content = cargo_util::paths::read(src_path.as_ref())?;
 
-            let syn::File { items, .. } = syn::parse_file(&content)
+            let file = syn::parse_file(&content)
                 .map_err(|e| anyhow!("{:?}", e))
                 .with_context(|| format!("could not parse `{}`", src_path))?;
 
-            let replacements = items
-                .into_iter()
-                .flat_map(|item| match item {
-                    Item::Mod(ItemMod {
-                        attrs,
-                        ident,
-                        content: None,
-                        semi,
-                        ..
-                    }) => Some((attrs, ident, semi)),
-                    _ => None,
+            let mut replacements = expand_includes(&file, include_env);
+
+            let mut visitor = Visitor {
+                include_env,
+                mod_dir: mod_dir.clone(),
+                cfg_env,
+                replacements: &mut replacements,
+                result: Ok(()),
+            };
+            visitor.visit_file(&file);
+            visitor.result?;
+
+            return Ok(replace_ranges(&content, replacements));
+
+            struct Visitor<'a, 'b> {
+                include_env: &'a IncludeEnv<'a>,
+                mod_dir: ModDir,
+                cfg_env: Option<&'a CfgEnv<'b>>,
+                replacements: &'a mut BTreeMap<(LineColumn, LineColumn), String>,
+                result: anyhow::Result<()>,
+            }
+
+            impl Visitor<'_, '_> {
+                fn expand(&mut self, item_mod: &ItemMod) -> anyhow::Result<()> {
+                    let ItemMod {
+                        attrs, ident, semi, ..
+                    } = item_mod;
+
+                    let (path, mod_dir) = match self.mod_dir.file_path(attrs, ident, self.cfg_env) {
+                        Ok(file_path) => file_path,
+                        // rustc does not look for the file if the `#[cfg(..)]` is false.
+                        // Make it an empty inline module so that rustfmt does not look for it
+                        // either.
+                        Err(_)
+                            if attrs.iter().any(|a| a.path.is_ident("cfg"))
+                                && self.cfg_env.and_then(|e| e.eval_attrs(attrs)).is_none() =>
+                        {
+                            self.replacements
+                                .insert((semi.span().start(), semi.span().end()), " {}".to_owned());
+                            return Ok(());
+                        }
+                        Err(err) => return Err(err),
+                    };
+                    let include_env = IncludeEnv {
+                        src_path: &path,
+                        ..*self.include_env
+                    };
+                    let content = expand_mods(&include_env, &mod_dir, self.cfg_env)?;
+                    // Indented one level deeper than the line of the `mod` item.
+                    let column = item_mod.span().start().column;
+                    let content = indent_code(&content, column / 4 + 1);
+                    let content = format!(" {{\n{}{}}}", content, " ".repeat(column));
+                    self.replacements
+                        .insert((semi.span().start(), semi.span().end()), content);
+                    Ok(())
+                }
+            }
+
+            impl Visit<'_> for Visitor<'_, '_> {
+                fn visit_item_macro(&mut self, i: &ItemMacro) {
+                    // Modules declared in the included file are looked up as if they were
+                    // declared in this file, while the paths of `include!`s and so on are
+                    // resolved from the included file.
+                    if self.result.is_ok()
+                        && i.ident.is_none()
+                        && is_std_macro(&i.mac.path, "include")
+                    {
+                        if let Some(path) = self.include_env.resolve_path(i.mac.tokens.clone()) {
+                            if path.is_file() {
+                                let include_env = IncludeEnv {
+                                    src_path: &path,
+                                    ..*self.include_env
+                                };
+                                self.result = expand_mods(
+                                    &include_env,
+                                    &self.mod_dir,
+                                    self.cfg_env,
+                                )
+                                .map(|content| {
+                                    self.replacements
+                                        .insert((i.span().start(), i.span().end()), content);
+                                });
+                            }
+                        }
+                    }
+                }
+
+                fn visit_item_mod(&mut self, i: &'_ ItemMod) {
+                    if self.result.is_err() {
+                        return;
+                    }
+                    if self.cfg_env.and_then(|e| e.eval_attrs(&i.attrs)) == Some(false) {
+                        let span = (i.span().start(), i.span().end());
+                        // Including the ones `expand_includes` made.
+                        self.replacements
+                            .retain(|&(start, end), _| !(span.0 <= start && end <= span.1));
+                        self.replacements.insert(span, "".to_owned());
+                    } else if i.content.is_none() {
+                        self.result = self.expand(i);
+                    } else {
+                        let mod_dir = match self.mod_dir.inline(&i.attrs, &i.ident, self.cfg_env) {
+                            Ok(mod_dir) => mod_dir,
+                            Err(err) => {
+                                self.result = Err(err);
+                                return;
+                            }
+                        };
+                        let mod_dir = mem::replace(&mut self.mod_dir, mod_dir);
+                        visit::visit_item_mod(self, i);
+                        self.mod_dir = mod_dir;
+                    }
+                }
+
+                fn visit_block(&mut self, i: &'_ Block) {
+                    let mod_dir = self.mod_dir.block();
+                    let mod_dir = mem::replace(&mut self.mod_dir, mod_dir);
+                    visit::visit_block(self, i);
+                    self.mod_dir = mod_dir;
+                }
+            }
+        }
+
+        /// Where the files of out-of-line modules are looked up, following rustc.
+        ///
+        /// See <https://github.com/rust-lang/rust/blob/1.76.0/compiler/rustc_expand/src/module.rs>.
+        #[derive(Clone)]
+        struct ModDir {
+            path: Utf8PathBuf,
+            /// `Some("foo")` in `foo.rs`, where `mod bar;` means `foo/bar.rs`.
+            relative: Option<String>,
+            in_block: bool,
+        }
+
+        impl ModDir {
+            fn root(src_path: &Utf8Path) -> Self {
+                Self {
+                    path: src_path.with_file_name(""),
+                    relative: None,
+                    in_block: false,
+                }
+            }
+
+            fn file_path(
+                &self,
+                attrs: &[Attribute],
+                ident: &Ident,
+                cfg_env: Option<&CfgEnv<'_>>,
+            ) -> anyhow::Result<(Utf8PathBuf, Self)> {
+                if let Some(path) = path_attr(attrs, ident, cfg_env)? {
+                    let path = self.path.join(path);
+                    if !path.exists() {
+                        bail!("`{}` does not exist", path);
+                    }
+                    return Ok((path.clone(), Self::root(&path)));
+                }
+
+                let name = ident.unraw().to_string();
+                if self.in_block {
+                    bail!(
+                        "cannot declare a non-inline module `{}` inside a block unless it has a \
+                         path attribute",
+                        name,
+                    );
+                }
+                let dir = match &self.relative {
+                    Some(relative) => self.path.join(relative),
+                    None => self.path.clone(),
+                };
+                let paths = [
+                    dir.join(&name).with_extension("rs"),
+                    dir.join(&name).join("mod.rs"),
+                ];
+                match paths {
+                    [path, _] if path.exists() => Ok((
+                        path.clone(),
+                        Self {
+                            path: dir,
+                            relative: Some(name),
+                            in_block: false,
+                        },
+                    )),
+                    [_, path] if path.exists() => Ok((path.clone(), Self::root(&path))),
+                    paths => bail!("one of {:?} does not exist", paths),
+                }
+            }
+
+            fn inline(
+                &self,
+                attrs: &[Attribute],
+                ident: &Ident,
+                cfg_env: Option<&CfgEnv<'_>>,
+            ) -> anyhow::Result<Self> {
+                if let Some(path) = path_attr(attrs, ident, cfg_env)? {
+                    return Ok(Self {
+                        path: self.path.join(path),
+                        relative: None,
+                        in_block: false,
+                    });
+                }
+
+                let mut path = self.path.clone();
+                if !self.in_block {
+                    if let Some(relative) = &self.relative {
+                        path.push(relative);
+                    }
+                }
+                path.push(ident.unraw().to_string());
+                Ok(Self {
+                    path,
+                    relative: None,
+                    in_block: self.in_block,
                 })
-                .map(|(attrs, ident, semi)| {
-                    let paths = if let Some(path) = attrs
-                        .iter()
-                        .flat_map(Attribute::parse_meta)
-                        .flat_map(|meta| match meta {
-                            Meta::NameValue(name_value) => Some(name_value),
-                            _ => None,
-                        })
-                        .filter(|MetaNameValue { path, .. }| {
-                            matches!(path.get_ident(), Some(i) if i == "path")
+            }
+
+            fn block(&self) -> Self {
+                Self {
+                    in_block: true,
+                    ..self.clone()
+                }
+            }
+        }
+
+        /// Finds `#[path = ".."]`, including ones in `#[cfg_attr(..)]`s.
+        fn path_attr(
+            attrs: &[Attribute],
+            ident: &Ident,
+            cfg_env: Option<&CfgEnv<'_>>,
+        ) -> anyhow::Result<Option<String>> {
+            let path_value = |meta: Meta| match meta {
+                Meta::NameValue(MetaNameValue {
+                    path,
+                    lit: Lit::Str(lit),
+                    ..
+                }) if path.is_ident("path") => Some(lit.value()),
+                _ => None,
+            };
+
+            for attr in attrs {
+                if let Some(path) = attr.parse_meta().ok().and_then(path_value) {
+                    return Ok(Some(path));
+                }
+                if let Some((pred, attrs)) = parse_cfg_attr(attr) {
+                    let path = attrs.into_iter().flat_map(syn::parse2).find_map(path_value);
+                    if let Some(path) = path {
+                        match cfg_env.and_then(|e| e.eval(&pred)) {
+                            Some(true) => return Ok(Some(path)),
+                            Some(false) => {}
+                            None => bail!(
+                                "could not determine the path of `mod {};` since `{}` could not be \
+                                 evaluated. try specifying `--target`",
+                                ident,
+                                pred.original(),
+                            ),
+                        }
+                    }
+                }
+            }
+            Ok(None)
+        }
+
+        /// Where the paths in `include!`s, `include_str!`s, and `include_bytes!`s are resolved.
+        #[derive(Clone, Copy)]
+        struct IncludeEnv<'a> {
+            /// The file which contains the macros. Relative paths are resolved from it.
+            src_path: &'a Utf8Path,
+            /// `env!("CARGO_MANIFEST_DIR")`.
+            manifest_dir: &'a Utf8Path,
+            /// `env!("OUT_DIR")`.
+            out_dir: Option<&'a Utf8Path>,
+        }
+
+        impl IncludeEnv<'_> {
+            fn resolve(&self, expr: &Expr) -> Option<String> {
+                if let Expr::Macro(ExprMacro {
+                    mac: Macro { path, tokens, .. },
+                    ..
+                }) = expr
+                {
+                    if is_std_macro(path, "concat") {
+                        (|parse_stream: ParseStream<'_>| {
+                            Punctuated::<Expr, Token![,]>::parse_terminated(parse_stream)
                         })
-                        .find_map(|MetaNameValue { lit, .. }| match lit {
-                            Lit::Str(s) => Some(s.value()),
-                            _ => None,
-                        }) {
-                        vec![src_path.with_file_name("").join(path)]
-                    } else if depth == 0 || src_path.file_name() == Some("mod.rs") {
-                        vec![
-                            src_path
-                                .with_file_name(ident.to_string())
-                                .with_extension("rs"),
-                            src_path.with_file_name(ident.to_string()).join("mod.rs"),
-                        ]
+                        .parse2(tokens.clone())
+                        .ok()?
+                        .iter()
+                        .map(|expr| self.resolve(expr))
+                        .collect()
+                    } else if is_std_macro(path, "env") {
+                        let name = syn::parse2::<LitStr>(tokens.clone()).ok()?.value();
+                        if name == "CARGO_MANIFEST_DIR" {
+                            Some(self.manifest_dir.as_str().to_owned())
+                        } else if name == "OUT_DIR" {
+                            self.out_dir.map(|out_dir| out_dir.as_str().to_owned())
+                        } else {
+                            env::var(name).ok()
+                        }
                     } else {
-                        vec![
-                            src_path
-                                .with_extension("")
-                                .with_file_name(ident.to_string())
-                                .with_extension("rs"),
-                            src_path
-                                .with_extension("")
-                                .with_file_name(ident.to_string())
-                                .join("mod.rs"),
-                        ]
-                    };
+                        None
+                    }
+                } else if let Expr::Lit(ExprLit {
+                    lit: Lit::Str(lit_str),
+                    ..
+                }) = expr
+                {
+                    Some(lit_str.value())
+                } else {
+                    None
+                }
+            }
+
+            fn resolve_path(&self, tokens: TokenStream) -> Option<Utf8PathBuf> {
+                let path = self.resolve(&syn::parse2(tokens).ok()?)?;
+                Some(self.src_path.with_file_name("").join(path))
+            }
+        }
+
+        /// Expands `include!`s in expressions, `include_str!`s, and `include_bytes!`s whose paths
+        /// can be determined. `include!`s in items are expanded in `expand_mods`.
+        fn expand_includes(
+            file: &syn::File,
+            include_env: &IncludeEnv<'_>,
+        ) -> BTreeMap<(LineColumn, LineColumn), String> {
+            let mut replacements = btreemap!();
+            let mut visitor = Visitor {
+                include_env,
+                replacements: &mut replacements,
+            };
+            visitor.visit_file(file);
+            visitor.visit_token_stream(file.to_token_stream());
+            return replacements;
+
+            /// Reads an `include!`d expression, expanding the macros in it as well.
+            fn expand_included_expr(include_env: &IncludeEnv<'_>) -> Option<String> {
+                let content = cargo_util::paths::read(include_env.src_path.as_ref()).ok()?;
+                let expr = syn::parse_str::<Expr>(&content).ok()?;
+                let mut replacements = btreemap!();
+                let mut visitor = Visitor {
+                    include_env,
+                    replacements: &mut replacements,
+                };
+                visitor.visit_expr(&expr);
+                visitor.visit_token_stream(expr.to_token_stream());
+                Some(replace_ranges(&content, replacements))
+            }
+
+            struct Visitor<'a> {
+                include_env: &'a IncludeEnv<'a>,
+                replacements: &'a mut BTreeMap<(LineColumn, LineColumn), String>,
+            }
+
+            impl Visitor<'_> {
+                /// Replaces `include_str!(..)`s and `include_bytes!(..)`s with literals wherever
+                /// they are, including in attributes and in arguments of other macros.
+                fn visit_token_stream(&mut self, token_stream: TokenStream) {
+                    let tts = token_stream.into_iter().collect::<Vec<_>>();
+                    for (i, tt) in tts.iter().enumerate() {
+                        match tt {
+                            TokenTree::Ident(name)
+                                if name == "include_str" || name == "include_bytes" =>
+                            {
+                                let args = match (tts.get(i + 1), tts.get(i + 2)) {
+                                    (
+                                        Some(TokenTree::Punct(bang)),
+                                        Some(TokenTree::Group(args)),
+                                    ) if bang.as_char() == '!' => args,
+                                    _ => continue,
+                                };
+                                let path = match self.include_env.resolve_path(args.stream()) {
+                                    Some(path) => path,
+                                    None => continue,
+                                };
+                                let lit = if name == "include_str" {
+                                    match cargo_util::paths::read(path.as_ref()) {
+                                        Ok(content) => proc_macro2::Literal::string(&content),
+                                        Err(_) => continue,
+                                    }
+                                } else {
+                                    match cargo_util::paths::read_bytes(path.as_ref()) {
+                                        Ok(content) => proc_macro2::Literal::byte_string(&content),
+                                        Err(_) => continue,
+                                    }
+                                };
+                                let start = std_macro_path_start(&tts, i);
+                                self.replacements.insert(
+                                    (tts[start].span().start(), args.span().end()),
+                                    lit.to_string(),
+                                );
+                            }
+                            TokenTree::Group(group) => self.visit_token_stream(group.stream()),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+
+            impl Visit<'_> for Visitor<'_> {
+                fn visit_expr_macro(&mut self, i: &ExprMacro) {
+                    if is_std_macro(&i.mac.path, "include") {
+                        if let Some(path) = self.include_env.resolve_path(i.mac.tokens.clone()) {
+                            let include_env = IncludeEnv {
+                                src_path: &path,
+                                ..*self.include_env
+                            };
+                            if let Some(content) = expand_included_expr(&include_env) {
+                                self.replacements.insert(
+                                    (i.mac.span().start(), i.mac.span().end()),
+                                    format!("({})", content.trim_end()),
+                                );
+                            }
+                        }
+                    }
+                }
+            }
 
-                    if let Some(path) = paths.iter().find(|p| p.exists()) {
-                        let start = semi.span().start();
-                        let end = semi.span().end();
-                        let content = expand_mods(path, depth + 1)?;
-                        let content = indent_code(&content, depth + 1);
-                        let content = format!(" {{\n{}{}}}", content, "    ".repeat(depth + 1));
-                        Ok(((start, end), content))
+            /// Returns the index where `name`, `std::name`, `::core::name` or so on starts.
+            fn std_macro_path_start(tts: &[TokenTree], i: usize) -> usize {
+                let is_colon2 = |j: usize| {
+                    matches!(
+                        (&tts[j], &tts[j + 1]),
+                        (TokenTree::Punct(p1), TokenTree::Punct(p2))
+                        if p1.as_char() == ':' && p2.as_char() == ':'
+                    )
+                };
+                if i >= 3
+                    && is_colon2(i - 2)
+                    && matches!(&tts[i - 3], TokenTree::Ident(k) if k == "std" || k == "core")
+                {
+                    if i >= 5 && is_colon2(i - 5) {
+                        i - 5
                     } else {
-                        bail!("one of {:?} does not exist", paths);
+                        i - 3
                     }
-                })
-                .collect::<anyhow::Result<_>>()?;
+                } else {
+                    i
+                }
+            }
+        }
 
-            Ok(replace_ranges(&content, replacements))
+        fn is_std_macro(path: &syn::Path, name: &str) -> bool {
+            match &*path.segments.iter().collect::<Vec<_>>() {
+                [segment] => path.leading_colon.is_none() && segment.ident == name,
+                [krate, segment] => {
+                    (krate.ident == "std" || krate.ident == "core") && segment.ident == name
+                }
+                _ => false,
+            }
         }
     }
 
@@ -444,6 +1095,37 @@ impl<'opt> CodeEdit<'opt> {
         }
     }
 
+    /// Generates `use`s of the module `mod_name` and all the modules in it, so that
+    /// [`prune_unused_mods`] keeps the whole module.
+    pub(crate) fn uses_of_mod_tree(
+        &mut self,
+        extern_crate_name: &str,
+        mod_name: &str,
+    ) -> anyhow::Result<String> {
+        self.apply()?;
+
+        let mut uses = "".to_owned();
+        let mut stack = self
+            .file
+            .items
+            .iter()
+            .map(|item| (extern_crate_name.to_owned(), item))
+            .collect::<Vec<_>>();
+        while let Some((prefix, item)) = stack.pop() {
+            if let Item::Mod(ItemMod { ident, content, .. }) = item {
+                if prefix == extern_crate_name && ident != mod_name {
+                    continue;
+                }
+                let path = format!("{}::{}", prefix, ident);
+                uses += &format!("use {};\n", path);
+                if let Some((_, items)) = content {
+                    stack.extend(items.iter().map(|item| (path.clone(), item)));
+                }
+            }
+        }
+        Ok(uses)
+    }
+
     pub(crate) fn has_local_inner_macros_attr(&self) -> bool {
         self.has_local_inner_macros_attr
     }
@@ -873,118 +1555,28 @@ impl<'opt> CodeEdit<'opt> {
             }
         }
 
-        fn to_range(lines: &[&str], span: Span) -> Range<usize> {
-            to_index(lines, span.start())..to_index(lines, span.end())
-        }
-
-        fn to_index(lines: &[&str], loc: LineColumn) -> usize {
-            lines[..loc.line - 1]
-                .iter()
-                .map(|s| s.len() + 1)
-                .sum::<usize>()
-                + lines[loc.line - 1]
-                    .char_indices()
-                    .nth(loc.column)
-                    .map(|(i, _)| i)
-                    .unwrap_or_else(|| lines[loc.line - 1].len())
-        }
-
         fn minify_group(group: proc_macro2::Group) -> String {
             rustminify::minify_tokens(TokenTree::from(group).into())
         }
     }
 
-    pub(crate) fn expand_includes(&mut self, out_dir: &Utf8Path) -> anyhow::Result<()> {
+    pub(crate) fn translate_extern_crate_paths(
+        &mut self,
+        translate_extern_crate_name: impl FnMut(&str) -> Option<String>,
+    ) -> anyhow::Result<()> {
         self.apply()?;
         Visitor {
-            out_dir,
             replacements: &mut self.replacements,
+            cargo_equip_mod_name: self.cargo_equip_mod_name,
+            translate_extern_crate_name,
         }
         .visit_file(&self.file);
         return Ok(());
 
-        struct Visitor<'a> {
-            out_dir: &'a Utf8Path,
+        struct Visitor<'a, F> {
             replacements: &'a mut BTreeMap<(LineColumn, LineColumn), String>,
-        }
-
-        impl Visitor<'_> {
-            fn resolve(&self, expr: &Expr) -> Option<String> {
-                if let Expr::Macro(ExprMacro {
-                    mac: Macro { path, tokens, .. },
-                    ..
-                }) = expr
-                {
-                    if [parse_quote!(::core::concat), parse_quote!(::std::concat)].contains(path) {
-                        (|parse_stream: ParseStream<'_>| {
-                            Punctuated::<Expr, Token![,]>::parse_separated_nonempty(parse_stream)
-                        })
-                        .parse2(tokens.clone())
-                        .ok()?
-                        .iter()
-                        .map(|expr| self.resolve(expr))
-                        .collect()
-                    } else if [parse_quote!(::core::env), parse_quote!(::std::env)].contains(path) {
-                        let name = syn::parse2::<LitStr>(tokens.clone()).ok()?.value();
-                        if name == "OUT_DIR" {
-                            Some(self.out_dir.as_str().to_owned())
-                        } else {
-                            env::var(name).ok()
-                        }
-                    } else {
-                        None
-                    }
-                } else if let Expr::Lit(ExprLit {
-                    lit: Lit::Str(lit_str),
-                    ..
-                }) = expr
-                {
-                    Some(lit_str.value())
-                } else {
-                    None
-                }
-            }
-        }
-
-        impl Visit<'_> for Visitor<'_> {
-            fn visit_item_macro(&mut self, i: &ItemMacro) {
-                if i.ident.is_none()
-                    && [parse_quote!(::core::include), parse_quote!(::std::include)]
-                        .contains(&i.mac.path)
-                {
-                    if let Ok(expr) = syn::parse2(i.mac.tokens.clone()) {
-                        if let Some(path) = self.resolve(&expr) {
-                            let path = Utf8PathBuf::from(path);
-                            if path.is_absolute() {
-                                if let Ok(content) = cargo_util::paths::read(path.as_ref()) {
-                                    self.replacements
-                                        .insert((i.span().start(), i.span().end()), content);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-    }
-
-    pub(crate) fn translate_extern_crate_paths(
-        &mut self,
-        translate_extern_crate_name: impl FnMut(&str) -> Option<String>,
-    ) -> anyhow::Result<()> {
-        self.apply()?;
-        Visitor {
-            replacements: &mut self.replacements,
-            cargo_equip_mod_name: self.cargo_equip_mod_name,
-            translate_extern_crate_name,
-        }
-        .visit_file(&self.file);
-        return Ok(());
-
-        struct Visitor<'a, F> {
-            replacements: &'a mut BTreeMap<(LineColumn, LineColumn), String>,
-            cargo_equip_mod_name: &'a Ident,
-            translate_extern_crate_name: F,
+            cargo_equip_mod_name: &'a Ident,
+            translate_extern_crate_name: F,
         }
 
         impl<F: FnMut(&str) -> Option<String>> Visitor<'_, F> {
@@ -1265,10 +1857,14 @@ impl<'opt> CodeEdit<'opt> {
     pub(crate) fn resolve_pseudo_prelude(
         &mut self,
         pseudo_extern_crate_name: &str,
+        rust_2024_prelude: bool,
         libs_with_local_inner_macros: &BTreeSet<&str>,
         extern_crate_name_translation: &BTreeMap<String, String>,
     ) -> anyhow::Result<String> {
-        if extern_crate_name_translation.is_empty() && libs_with_local_inner_macros.is_empty() {
+        if !rust_2024_prelude
+            && extern_crate_name_translation.is_empty()
+            && libs_with_local_inner_macros.is_empty()
+        {
             return Ok("".to_owned());
         }
 
@@ -1307,6 +1903,13 @@ impl<'opt> CodeEdit<'opt> {
         };
 
         let mut prelude = "".to_owned();
+        if rust_2024_prelude {
+            // Rust 2024 adds `Future` and `IntoFuture` to the prelude.
+            prelude += &format!(
+                "pub(in crate::{}) use ::core::future::{{Future, IntoFuture}};",
+                self.cargo_equip_mod_name,
+            );
+        }
         if let Some(external_local_inner_macros) = &external_local_inner_macros {
             prelude += &format!(
                 "pub(in crate::{0}) use crate::{0}::macros::{1};",
@@ -1372,21 +1975,245 @@ impl<'opt> CodeEdit<'opt> {
         Ok(prelude)
     }
 
-    pub(crate) fn resolve_cfgs(&mut self, features: &[String]) -> anyhow::Result<()> {
+    /// Makes code written in Rust 2015/2018/2021 compile in Rust 2024.
+    ///
+    /// - `gen`, which is reserved in Rust 2024, is replaced with `r#gen`.
+    /// - `expr` fragment specifiers in `macro_rules!` are replaced with `expr_2021`.
+    ///
+    /// Errors if the code needs to be migrated with the syntax which `syn` 1 cannot parse, namely
+    /// `impl Trait + use<..>`, `unsafe extern` blocks, and `#[unsafe(..)]` attributes.
+    pub(crate) fn migrate_to_rust_2024(&mut self) -> anyhow::Result<()> {
+        self.apply()?;
+        visit_token_stream(&mut self.replacements, self.file.to_token_stream(), false);
+        let mut visitor = Visitor {
+            impl_generics: None,
+            errors: vec![],
+        };
+        visitor.visit_file(&self.file);
+        if let Some(err) = visitor.errors.into_iter().next() {
+            bail!("could not migrate the code to Rust 2024: {}", err);
+        }
+        return Ok(());
+
+        struct Visitor<'ast> {
+            /// Generics of the enclosing `impl` if it is an inherent one.
+            impl_generics: Option<&'ast Generics>,
+            errors: Vec<String>,
+        }
+
+        impl Visitor<'_> {
+            /// Checks if `impl Trait` in the return type captures the same lifetimes in Rust 2024,
+            /// where it captures all of the lifetimes in scope.
+            fn check_captures(&mut self, impl_generics: Option<&Generics>, sig: &Signature) {
+                let ty = match (&sig.asyncness, &sig.output) {
+                    (None, ReturnType::Type(_, ty)) => ty,
+                    _ => return,
+                };
+                let mut rpits = ImplTraits::default();
+                rpits.visit_type(ty);
+
+                let lifetime_params = impl_generics
+                    .into_iter()
+                    .chain(iter::once(&sig.generics))
+                    .flat_map(|g| g.lifetimes())
+                    .map(|l| l.lifetime.to_string())
+                    .collect::<Vec<_>>();
+                let mut elided = Lifetimes::default();
+                for input in &sig.inputs {
+                    elided.visit_fn_arg(input);
+                }
+                let num_elided = elided.0.iter().filter(|l| *l == "'_").count();
+
+                for rpit in rpits.0 {
+                    let mut captured = Lifetimes::default();
+                    captured.visit_type_impl_trait(rpit);
+                    let mut overcaptured = lifetime_params
+                        .iter()
+                        .filter(|l| !captured.0.contains(l))
+                        .map(|l| format!("`{}`", l))
+                        .collect::<Vec<_>>();
+                    if num_elided > usize::from(captured.0.iter().any(|l| l == "'_")) {
+                        overcaptured.push("elided lifetimes".to_owned());
+                    }
+                    if !overcaptured.is_empty() {
+                        self.errors.push(format!(
+                            "`impl Trait` in the return type of `fn {}` would also capture {}, \
+                             which needs `use<..>` to prevent",
+                            sig.ident,
+                            overcaptured.iter().format(", "),
+                        ));
+                        return;
+                    }
+                }
+            }
+        }
+
+        impl<'ast> Visit<'ast> for Visitor<'ast> {
+            fn visit_item_impl(&mut self, i: &'ast ItemImpl) {
+                let impl_generics = mem::replace(
+                    &mut self.impl_generics,
+                    Some(&i.generics).filter(|_| i.trait_.is_none()),
+                );
+                visit::visit_item_impl(self, i);
+                self.impl_generics = impl_generics;
+            }
+
+            fn visit_item_fn(&mut self, i: &'ast ItemFn) {
+                self.check_captures(None, &i.sig);
+                visit::visit_item_fn(self, i);
+            }
+
+            fn visit_impl_item_method(&mut self, i: &'ast ImplItemMethod) {
+                // `impl Trait` in traits captures all of the lifetimes in scope in any edition.
+                if let Some(impl_generics) = self.impl_generics {
+                    self.check_captures(Some(impl_generics), &i.sig);
+                }
+                visit::visit_impl_item_method(self, i);
+            }
+
+            fn visit_item_foreign_mod(&mut self, i: &'ast ItemForeignMod) {
+                self.errors
+                    .push("`extern` blocks need to be `unsafe extern` blocks".to_owned());
+                visit::visit_item_foreign_mod(self, i);
+            }
+
+            fn visit_attribute(&mut self, attr: &'ast Attribute) {
+                let attrs = if let Some((_, attrs)) = parse_cfg_attr(attr) {
+                    attrs
+                } else {
+                    vec![attr.path.to_token_stream()]
+                };
+                for attr in attrs {
+                    if let Some(TokenTree::Ident(name)) = attr.into_iter().next() {
+                        if ["no_mangle", "export_name", "link_section"]
+                            .iter()
+                            .any(|s| name == s)
+                        {
+                            self.errors
+                                .push(format!("`#[{}]` needs to be `#[unsafe({0})]`", name,));
+                        }
+                    }
+                }
+            }
+        }
+
+        #[derive(Default)]
+        struct ImplTraits<'ast>(Vec<&'ast TypeImplTrait>);
+
+        impl<'ast> Visit<'ast> for ImplTraits<'ast> {
+            fn visit_type_impl_trait(&mut self, i: &'ast TypeImplTrait) {
+                self.0.push(i);
+                visit::visit_type_impl_trait(self, i);
+            }
+        }
+
+        /// Lifetimes except for `'static` and the ones introduced with `for<..>`. Elided ones are
+        /// counted as `'_`.
+        #[derive(Default)]
+        struct Lifetimes(Vec<String>);
+
+        impl Visit<'_> for Lifetimes {
+            fn visit_lifetime(&mut self, i: &'_ syn::Lifetime) {
+                if i.ident != "static" {
+                    self.0.push(i.to_string());
+                }
+            }
+
+            fn visit_type_reference(&mut self, i: &'_ TypeReference) {
+                if i.lifetime.is_none() {
+                    self.0.push("'_".to_owned());
+                }
+                visit::visit_type_reference(self, i);
+            }
+
+            fn visit_receiver(&mut self, i: &'_ Receiver) {
+                if let Some((_, None)) = &i.reference {
+                    self.0.push("'_".to_owned());
+                }
+                visit::visit_receiver(self, i);
+            }
+
+            fn visit_bound_lifetimes(&mut self, _: &'_ syn::BoundLifetimes) {}
+
+            fn visit_type_bare_fn(&mut self, _: &'_ syn::TypeBareFn) {}
+
+            fn visit_parenthesized_generic_arguments(
+                &mut self,
+                _: &'_ syn::ParenthesizedGenericArguments,
+            ) {
+            }
+        }
+
+        fn visit_token_stream(
+            replacements: &mut BTreeMap<(LineColumn, LineColumn), String>,
+            token_stream: TokenStream,
+            in_macro_rules: bool,
+        ) {
+            let tts = token_stream.into_iter().collect::<Vec<_>>();
+            for (i, tt) in tts.iter().enumerate() {
+                let prev = |n: usize| i.checked_sub(n).map(|i| &tts[i]);
+                match tt {
+                    TokenTree::Group(group) => {
+                        let is_macro_rules_body = matches!(
+                            (prev(3), prev(2), prev(1)),
+                            (
+                                Some(TokenTree::Ident(macro_rules)),
+                                Some(TokenTree::Punct(bang)),
+                                Some(TokenTree::Ident(_)),
+                            ) if macro_rules == "macro_rules" && bang.as_char() == '!'
+                        );
+                        visit_token_stream(
+                            replacements,
+                            group.stream(),
+                            in_macro_rules || is_macro_rules_body,
+                        );
+                    }
+                    TokenTree::Ident(ident) if ident == "gen" => {
+                        if !matches!(prev(1), Some(TokenTree::Punct(p)) if ['$', '\''].contains(&p.as_char()))
+                        {
+                            replacements.insert(
+                                (ident.span().start(), ident.span().end()),
+                                "r#gen".to_owned(),
+                            );
+                        }
+                    }
+                    TokenTree::Ident(ident) if in_macro_rules && ident == "expr" => {
+                        if matches!(
+                            (prev(3), prev(2), prev(1)),
+                            (
+                                Some(TokenTree::Punct(dollar)),
+                                Some(TokenTree::Ident(_)),
+                                Some(TokenTree::Punct(colon)),
+                            ) if dollar.as_char() == '$' && colon.as_char() == ':'
+                        ) {
+                            replacements.insert(
+                                (ident.span().start(), ident.span().end()),
+                                "expr_2021".to_owned(),
+                            );
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Resolves `#[cfg(..)]`s, `#[cfg_attr(..)]`s and `cfg!(..)`s.
+    pub(crate) fn resolve_cfgs(&mut self, cfg_env: &CfgEnv<'_>) -> anyhow::Result<()> {
         self.apply()?;
         Visitor {
             replacements: &mut self.replacements,
-            features,
+            cfg_env,
         }
         .visit_file(&self.file);
         return Ok(());
 
-        struct Visitor<'a> {
+        struct Visitor<'a, 'b> {
             replacements: &'a mut BTreeMap<(LineColumn, LineColumn), String>,
-            features: &'a [String],
+            cfg_env: &'a CfgEnv<'b>,
         }
 
-        impl Visitor<'_> {
+        impl Visitor<'_, '_> {
             fn proceed<'a, T: ToTokens>(
                 &mut self,
                 i: &'a T,
@@ -1407,19 +2234,7 @@ impl<'opt> CodeEdit<'opt> {
                                 .ok()?;
                         Some((span, expr))
                     })
-                    .map(|(span, expr)| {
-                        let sufficiency = expr.eval(|pred| match pred {
-                            cfg_expr::Predicate::Test | cfg_expr::Predicate::ProcMacro => {
-                                Some(false)
-                            }
-                            cfg_expr::Predicate::Flag("cargo_equip") => Some(true),
-                            cfg_expr::Predicate::Feature(feature) => {
-                                Some(self.features.contains(&(*feature).to_owned()))
-                            }
-                            _ => None,
-                        });
-                        (span, sufficiency)
-                    })
+                    .map(|(span, expr)| (span, self.eval(&expr)))
                     .collect::<Vec<_>>();
 
                 if sufficiencies.iter().any(|&(_, p)| p == Some(false)) {
@@ -1432,9 +2247,103 @@ impl<'opt> CodeEdit<'opt> {
                                 .insert((span.start(), span.end()), "".to_owned());
                         }
                     }
+                    for attr in attrs(i) {
+                        self.resolve_cfg_attr(attr);
+                    }
                     visit(self, i);
                 }
             }
+
+            fn eval(&self, expr: &cfg_expr::Expression) -> Option<bool> {
+                self.cfg_env.eval(expr)
+            }
+
+            /// Expands `#[cfg_attr(<pred>, <attr>, ..)]` into `#[<attr>]..`, or removes it.
+            fn resolve_cfg_attr(&mut self, attr: &Attribute) {
+                let (pred, attrs) = match parse_cfg_attr(attr) {
+                    Some(cfg_attr) => cfg_attr,
+                    None => return,
+                };
+                let replacement = match self.eval(&pred) {
+                    Some(true) => {
+                        let bang = if let AttrStyle::Inner(_) = attr.style {
+                            "!"
+                        } else {
+                            ""
+                        };
+                        attrs
+                            .iter()
+                            .map(|attr| format!("#{}[{}]", bang, attr))
+                            .join("")
+                    }
+                    Some(false) => "".to_owned(),
+                    None => return,
+                };
+                self.replacements
+                    .insert((attr.span().start(), attr.span().end()), replacement);
+            }
+
+            fn eval_cfg_macro(&self, mac: &Macro) -> Option<bool> {
+                let is_cfg = match &*mac.path.segments.iter().collect::<Vec<_>>() {
+                    [cfg] => cfg.ident == "cfg",
+                    [krate, cfg] => {
+                        (krate.ident == "std" || krate.ident == "core") && cfg.ident == "cfg"
+                    }
+                    _ => false,
+                };
+                if !is_cfg {
+                    return None;
+                }
+                self.eval(&cfg_expr::Expression::parse(&mac.tokens.to_string()).ok()?)
+            }
+
+            /// Keeps only the taken branch of `if cfg!(..) { .. } else { .. }`.
+            fn resolve_expr_if(&mut self, expr_if: &ExprIf) {
+                let ExprIf {
+                    if_token,
+                    cond,
+                    then_branch,
+                    else_branch,
+                    ..
+                } = expr_if;
+
+                let sufficiency = match &**cond {
+                    Expr::Macro(ExprMacro { attrs, mac }) if attrs.is_empty() => {
+                        self.eval_cfg_macro(mac)
+                    }
+                    _ => None,
+                };
+
+                match (sufficiency, else_branch) {
+                    (Some(true), _) => {
+                        self.replacements.insert(
+                            (if_token.span.start(), then_branch.span().start()),
+                            "".to_owned(),
+                        );
+                        if else_branch.is_some() {
+                            self.replacements.insert(
+                                (then_branch.span().end(), expr_if.span().end()),
+                                "".to_owned(),
+                            );
+                        }
+                        self.visit_block(then_branch);
+                    }
+                    (Some(false), Some((_, else_expr))) => {
+                        self.replacements.insert(
+                            (if_token.span.start(), else_expr.span().start()),
+                            "".to_owned(),
+                        );
+                        self.visit_expr(else_expr);
+                    }
+                    (Some(false), None) => {
+                        self.replacements.insert(
+                            (if_token.span.start(), expr_if.span().end()),
+                            "{}".to_owned(),
+                        );
+                    }
+                    (None, _) => visit::visit_expr_if(self, expr_if),
+                }
+            }
         }
 
         macro_rules! impl_visits {
@@ -1447,7 +2356,7 @@ impl<'opt> CodeEdit<'opt> {
             };
         }
 
-        impl Visit<'_> for Visitor<'_> {
+        impl Visit<'_> for Visitor<'_, '_> {
             impl_visits! {
                 fn visit_arm                (&mut self, _: &'_ Arm              ) { _(_, _, visit::visit_arm                ) }
                 fn visit_bare_fn_arg        (&mut self, _: &'_ BareFnArg        ) { _(_, _, visit::visit_bare_fn_arg        ) }
@@ -1468,7 +2377,7 @@ impl<'opt> CodeEdit<'opt> {
                 fn visit_expr_field         (&mut self, _: &'_ ExprField        ) { _(_, _, visit::visit_expr_field         ) }
                 fn visit_expr_for_loop      (&mut self, _: &'_ ExprForLoop      ) { _(_, _, visit::visit_expr_for_loop      ) }
                 fn visit_expr_group         (&mut self, _: &'_ ExprGroup        ) { _(_, _, visit::visit_expr_group         ) }
-                fn visit_expr_if            (&mut self, _: &'_ ExprIf           ) { _(_, _, visit::visit_expr_if            ) }
+                fn visit_expr_if            (&mut self, _: &'_ ExprIf           ) { _(_, _, Self::resolve_expr_if           ) }
                 fn visit_expr_index         (&mut self, _: &'_ ExprIndex        ) { _(_, _, visit::visit_expr_index         ) }
                 fn visit_expr_let           (&mut self, _: &'_ ExprLet          ) { _(_, _, visit::visit_expr_let           ) }
                 fn visit_expr_lit           (&mut self, _: &'_ ExprLit          ) { _(_, _, visit::visit_expr_lit           ) }
@@ -1545,6 +2454,15 @@ impl<'opt> CodeEdit<'opt> {
                 fn visit_variadic           (&mut self, _: &'_ Variadic         ) { _(_, _, visit::visit_variadic           ) }
                 fn visit_variant            (&mut self, _: &'_ Variant          ) { _(_, _, visit::visit_variant            ) }
             }
+
+            fn visit_macro(&mut self, mac: &'_ Macro) {
+                if let Some(sufficiency) = self.eval_cfg_macro(mac) {
+                    self.replacements.insert(
+                        (mac.span().start(), mac.span().end()),
+                        sufficiency.to_string(),
+                    );
+                }
+            }
         }
     }
 
@@ -1674,7 +2592,9 @@ impl<'opt> CodeEdit<'opt> {
 
 #[cfg(test)]
 mod tests {
-    use crate::rust::CodeEdit;
+    use crate::rust::{self, CfgEnv, CodeEdit};
+    use camino::Utf8Path;
+    use indoc::indoc;
     use pretty_assertions::assert_eq;
     use proc_macro2::Span;
     use syn::Ident;
@@ -1683,6 +2603,342 @@ mod tests {
         static DUMMY_MOD_NAME: Ident = Ident::new("__", Span::call_site());
     }
 
+    #[test]
+    fn expand_includes() -> anyhow::Result<()> {
+        let dir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        for (path, content) in [
+            ("README.md", "Docs.\n"),
+            ("data/s.txt", "s"),
+            ("data/b.bin", "b"),
+            (
+                "src/lib.rs",
+                indoc! {r#"
+                    #![doc = include_str!("../README.md")]
+                    include!("items.rs");
+                    pub const S: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/data/s.txt"));
+                    pub const B: &[u8] = include_bytes!("../data/b.bin");
+                    pub fn f() -> usize { include!("expr/len.rs") }
+                "#},
+            ),
+            (
+                "src/items.rs",
+                indoc! {r#"
+                    pub const T: &str = include_str!("t.txt");
+                    #[path = "m.rs"]
+                    pub mod m;
+                "#},
+            ),
+            ("src/t.txt", "t"),
+            ("src/m.rs", "pub fn m() {}\n"),
+            (
+                "src/expr/len.rs",
+                "include_bytes!(\"../../data/b.bin\").len()\n",
+            ),
+        ] {
+            cargo_util::paths::create_dir_all(dir.join(path).parent().unwrap())?;
+            cargo_util::paths::write(dir.join(path), content)?;
+        }
+
+        DUMMY_MOD_NAME.with(|dummy_mod_name| {
+            let edit = CodeEdit::new(
+                dummy_mod_name,
+                &dir.join("src").join("lib.rs"),
+                dir,
+                None,
+                None,
+                || unreachable!(),
+            )?;
+            assert_eq!(
+                indoc! {r#"
+                    #![doc = "Docs.\n"]
+                    pub const T: &str = "t";
+                    #[path = "m.rs"]
+                    pub mod m {
+                        pub fn m() {}
+                    }
+
+                    pub const S: &str = "s";
+                    pub const B: &[u8] = b"b";
+                    pub fn f() -> usize { (b"b".len()) }
+                "#},
+                edit.finish()?,
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn expand_includes_in_removed_mods() -> anyhow::Result<()> {
+        let files = &[
+            (
+                "src/lib.rs",
+                indoc! {r#"
+                    #[cfg(feature = "x")]
+                    mod m {
+                        pub const T: &str = include_str!("data.txt");
+                        pub fn f() {}
+                    }
+                    pub fn g() {}
+                "#},
+            ),
+            ("src/data.txt", "t"),
+        ];
+        let features = &["x".to_owned()];
+        let with_x = &CfgEnv {
+            features,
+            target_preds: None,
+            debug_assertions: None,
+        };
+        let without_x = &CfgEnv {
+            features: &[],
+            ..*with_x
+        };
+        assert_eq!(
+            indoc! {r#"
+                #[cfg(feature = "x")]
+                mod m {
+                    pub const T: &str = "t";
+                    pub fn f() {}
+                }
+                pub fn g() {}
+            "#},
+            expand_mods(files, Some(with_x))?,
+        );
+        assert_eq!(
+            "\n\n\n\n\npub fn g() {}\n",
+            expand_mods(files, Some(without_x))?,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mod_file_paths() -> anyhow::Result<()> {
+        let files = &[
+            (
+                "src/lib.rs",
+                indoc! {r#"
+                    #[path = "p.rs"]
+                    mod renamed;
+                    mod x;
+                    #[path = "w"]
+                    mod inline {
+                        mod v;
+                    }
+                    #[cfg_attr(feature = "x", path = "alt.rs")]
+                    mod k;
+                    #[cfg(feature = "y")]
+                    mod missing_y;
+                    #[cfg(unknown)]
+                    mod missing_unknown;
+                "#},
+            ),
+            ("src/p.rs", "pub struct P;\n"),
+            (
+                "src/x.rs",
+                indoc! {r#"
+                    #[path = "q.rs"]
+                    mod q;
+                    mod inline {
+                        mod r;
+                    }
+                    #[path = "s"]
+                    mod inline_with_path {
+                        mod t;
+                    }
+                "#},
+            ),
+            ("src/q.rs", "pub struct Q;\n"),
+            ("src/x/inline/r.rs", "pub struct R;\n"),
+            ("src/s/t.rs", "pub struct T;\n"),
+            ("src/w/v.rs", "pub struct V;\n"),
+            ("src/k.rs", "pub struct K;\n"),
+            ("src/alt.rs", "pub struct Alt;\n"),
+        ];
+        let features = &["x".to_owned()];
+        let cfg_env = &CfgEnv {
+            features,
+            target_preds: None,
+            debug_assertions: None,
+        };
+        assert_eq!(
+            indoc! {r#"
+                #[path = "p.rs"]
+                mod renamed {
+                    pub struct P;
+                }
+                mod x {
+                    #[path = "q.rs"]
+                    mod q {
+                        pub struct Q;
+                    }
+                    mod inline {
+                        mod r {
+                            pub struct R;
+                        }
+                    }
+                    #[path = "s"]
+                    mod inline_with_path {
+                        mod t {
+                            pub struct T;
+                        }
+                    }
+                }
+                #[path = "w"]
+                mod inline {
+                    mod v {
+                        pub struct V;
+                    }
+                }
+                #[cfg_attr(feature = "x", path = "alt.rs")]
+                mod k {
+                    pub struct Alt;
+                }
+
+
+                #[cfg(unknown)]
+                mod missing_unknown {}
+            "#},
+            expand_mods(files, Some(cfg_env))?,
+        );
+
+        // Without `#[cfg(..)]`s resolved, the modules under them whose files are missing are
+        // left empty.
+        let files = &[
+            (
+                "src/lib.rs",
+                "#[cfg(feature = \"y\")]\nmod missing;\nmod a;\n",
+            ),
+            ("src/a.rs", "pub struct A;\n"),
+        ];
+        assert_eq!(
+            indoc! {r#"
+                #[cfg(feature = "y")]
+                mod missing {}
+                mod a {
+                    pub struct A;
+                }
+            "#},
+            expand_mods(files, None)?,
+        );
+
+        let files = &[
+            (
+                "src/lib.rs",
+                "#[cfg_attr(unknown, path = \"alt.rs\")]\nmod k;\n",
+            ),
+            ("src/k.rs", ""),
+        ];
+        assert_eq!(
+            "could not expand `lib` from `lib 0.1.0 (path+file:///lib)`: could not determine \
+             the path of `mod k;` since `unknown` could not be evaluated. try specifying \
+             `--target`",
+            format!("{:#}", expand_mods(files, Some(cfg_env)).unwrap_err()),
+        );
+        Ok(())
+    }
+
+    /// Writes `files` in a temporary directory and expands `src/lib.rs` in it.
+    fn expand_mods(files: &[(&str, &str)], cfg_env: Option<&CfgEnv<'_>>) -> anyhow::Result<String> {
+        let dir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        for (path, content) in files {
+            cargo_util::paths::create_dir_all(dir.join(path).parent().unwrap())?;
+            cargo_util::paths::write(dir.join(path), content)?;
+        }
+        DUMMY_MOD_NAME.with(|dummy_mod_name| {
+            CodeEdit::new(
+                dummy_mod_name,
+                &dir.join("src").join("lib.rs"),
+                dir,
+                None,
+                cfg_env,
+                || ("lib".to_owned(), "lib 0.1.0 (path+file:///lib)"),
+            )?
+            .finish()
+        })
+    }
+
+    #[test]
+    fn expand_nested_mods() -> anyhow::Result<()> {
+        let files = &[
+            (
+                "src/lib.rs",
+                indoc! {r#"
+                    pub mod a {
+                        pub mod b;
+                    }
+                    pub mod x;
+                    pub fn f() -> u32 {
+                        #[path = "c.rs"]
+                        mod c;
+                        c::C
+                    }
+                    pub mod d {
+                        pub fn g() -> u32 {
+                            #[path = "e.rs"]
+                            mod e;
+                            e::E
+                        }
+                    }
+                "#},
+            ),
+            ("src/a/b.rs", "pub struct B;\n"),
+            ("src/x.rs", "pub mod y {\n    pub mod z;\n}\n"),
+            ("src/x/y/z.rs", "pub struct Z;\n"),
+            ("src/c.rs", "pub const C: u32 = 1;\n"),
+            ("src/d/e.rs", "pub const E: u32 = 2;\n"),
+        ];
+        assert_eq!(
+            indoc! {r#"
+                pub mod a {
+                    pub mod b {
+                        pub struct B;
+                    }
+                }
+                pub mod x {
+                    pub mod y {
+                        pub mod z {
+                            pub struct Z;
+                        }
+                    }
+                }
+                pub fn f() -> u32 {
+                    #[path = "c.rs"]
+                    mod c {
+                        pub const C: u32 = 1;
+                    }
+                    c::C
+                }
+                pub mod d {
+                    pub fn g() -> u32 {
+                        #[path = "e.rs"]
+                        mod e {
+                            pub const E: u32 = 2;
+                        }
+                        e::E
+                    }
+                }
+            "#},
+            expand_mods(files, None)?,
+        );
+
+        let files = &[
+            ("src/lib.rs", "pub fn f() {\n    mod c;\n}\n"),
+            ("src/c.rs", ""),
+        ];
+        assert_eq!(
+            "could not expand `lib` from `lib 0.1.0 (path+file:///lib)`: cannot declare a \
+             non-inline module `c` inside a block unless it has a path attribute",
+            format!("{:#}", expand_mods(files, None).unwrap_err()),
+        );
+        Ok(())
+    }
+
     #[test]
     fn erase_docs() -> anyhow::Result<()> {
         fn test(input: &str, expected: &str) -> anyhow::Result<()> {
@@ -1770,4 +3026,403 @@ fn main() {
 "#,
         )
     }
+
+    #[test]
+    fn resolve_cfgs() -> anyhow::Result<()> {
+        DUMMY_MOD_NAME.with(|dummy_mod_name| {
+            let mut edit = CodeEdit::from_code(
+                dummy_mod_name,
+                r#"#[cfg(feature = "a")]
+fn a() {}
+#[cfg(target_os = "windows")]
+fn windows() {}
+#[cfg(unix)]
+fn unix() {}
+#[cfg(debug_assertions)]
+fn debug() {}
+#[cfg(all(unix, feature = "b"))]
+fn unix_b() {}
+#[cfg_attr(feature = "a", derive(Debug, Clone), allow(dead_code))]
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(feature = "c", derive(Eq))]
+struct S;
+fn f() -> bool {
+    if cfg!(feature = "b") {
+        g();
+    } else if cfg!(windows) {
+        h();
+    } else {
+        i();
+    }
+    cfg!(feature = "a") && cfg!(feature = "c")
+}
+"#,
+            )?;
+            let target_preds = ["unix", r#"target_os = "linux""#]
+                .iter()
+                .map(|s| cfg_expr::Expression::parse(s))
+                .collect::<Result<Vec<_>, _>>()?;
+            let target_preds = target_preds
+                .iter()
+                .flat_map(cfg_expr::Expression::predicates)
+                .collect::<Vec<_>>();
+            edit.resolve_cfgs(&CfgEnv {
+                features: &["a".to_owned()],
+                target_preds: Some(&target_preds),
+                debug_assertions: Some(false),
+            })?;
+            assert_eq!(
+                r#"
+fn a() {}
+
+
+
+fn unix() {}
+
+
+
+
+#[derive (Debug , Clone)]#[allow (dead_code)]
+
+
+struct S;
+fn f() -> bool {
+    
+
+
+
+{
+        i();
+    }
+    true && false
+}
+"#,
+                edit.finish()?,
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn migrate_to_rust_2024() -> anyhow::Result<()> {
+        DUMMY_MOD_NAME.with(|dummy_mod_name| {
+            let mut edit = CodeEdit::from_code(
+                dummy_mod_name,
+                r#"macro_rules! m {
+    ($gen:ident, $x:expr) => { $gen.gen($x) };
+}
+fn gen<'a>(rng: &'a mut Rng) -> u32 {
+    m!(rng, 1) + rng.gen::<u32>() + r#gen(rng)
+}
+"#,
+            )?;
+            edit.migrate_to_rust_2024()?;
+            assert_eq!(
+                r#"macro_rules! m {
+    ($gen:ident, $x:expr_2021) => { $gen.r#gen($x) };
+}
+fn r#gen<'a>(rng: &'a mut Rng) -> u32 {
+    m!(rng, 1) + rng.r#gen::<u32>() + r#gen(rng)
+}
+"#,
+                edit.finish()?,
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn migrate_to_rust_2024_captures() -> anyhow::Result<()> {
+        DUMMY_MOD_NAME.with(|dummy_mod_name| {
+            let code = r#"fn f<'a, T>(x: &'a T, y: impl Sized) -> impl Iterator<Item = impl Sized + 'a> {
+    todo!()
+}
+fn g(x: &u32, f: impl Fn(&u32) -> &u32) -> &(impl Sized + for<'b> Fn(&'b u32) + '_) {
+    todo!()
+}
+fn h(x: &'static str) -> impl Sized {
+    x
+}
+struct S<'a, T>(&'a T);
+impl<'a, T> S<'a, T> {
+    fn f(&self) -> impl Iterator<Item = &'a T> + '_ {
+        todo!()
+    }
+}
+impl<'a, T> IntoIterator for S<'a, T> {
+    fn into_iter(self) -> impl Sized {}
+}
+trait Trait {
+    fn f(&self) -> impl Sized;
+}
+"#;
+            let mut edit = CodeEdit::from_code(dummy_mod_name, code)?;
+            edit.migrate_to_rust_2024()?;
+            assert_eq!(code, edit.finish()?);
+
+            for (code, err) in [
+                (
+                    "fn f(x: &u32) -> impl Sized {}\n",
+                    "elided lifetimes",
+                ),
+                (
+                    "fn f<'a>(x: &'a u32, y: &u32) -> impl Sized + 'a {}\n",
+                    "elided lifetimes",
+                ),
+                (
+                    "fn f<'a, 'b>(x: &'a u32, y: &'b u32) -> impl Sized + '_ {}\n",
+                    "`'a`, `'b`",
+                ),
+                (
+                    "impl<'a> S<'a> { fn f(self) -> impl Sized {} }\n",
+                    "`'a`",
+                ),
+            ] {
+                let mut edit = CodeEdit::from_code(dummy_mod_name, code)?;
+                assert_eq!(
+                    format!(
+                        "could not migrate the code to Rust 2024: `impl Trait` in the return \
+                         type of `fn f` would also capture {}, which needs `use<..>` to prevent",
+                        err,
+                    ),
+                    edit.migrate_to_rust_2024().unwrap_err().to_string(),
+                );
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn migrate_to_rust_2024_unsafe() -> anyhow::Result<()> {
+        DUMMY_MOD_NAME.with(|dummy_mod_name| {
+            for (code, err) in [
+                (
+                    "extern \"C\" {\n    fn abs(x: i32) -> i32;\n}\n",
+                    "`extern` blocks need to be `unsafe extern` blocks",
+                ),
+                (
+                    "#[no_mangle]\npub extern \"C\" fn f() {}\n",
+                    "`#[no_mangle]` needs to be `#[unsafe(no_mangle)]`",
+                ),
+                (
+                    "#[cfg_attr(unix, inline, export_name = \"g\")]\npub fn f() {}\n",
+                    "`#[export_name]` needs to be `#[unsafe(export_name)]`",
+                ),
+            ] {
+                let mut edit = CodeEdit::from_code(dummy_mod_name, code)?;
+                assert_eq!(
+                    format!("could not migrate the code to Rust 2024: {}", err),
+                    edit.migrate_to_rust_2024().unwrap_err().to_string(),
+                );
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn prune_unused_items() -> anyhow::Result<()> {
+        DUMMY_MOD_NAME.with(|dummy_mod_name| {
+            let mut edit = CodeEdit::from_code(
+                dummy_mod_name,
+                r#"pub mod a {
+    pub fn f() -> B { B }
+    pub fn g() {}
+}
+pub struct B;
+impl B { pub fn h(&self) {} }
+pub struct C;
+impl C {}
+pub trait T { fn t(&self); }
+impl T for u32 { fn t(&self) {} }
+pub mod unused {
+    pub fn i() {}
+}
+"#,
+            )?;
+            rust::prune_unused_items(
+                "fn main() { lib::a::f().h(); 1u32.t(); }",
+                &mut [(&mut edit, true)],
+            )?;
+            assert_eq!(
+                r#"pub mod a {
+    pub fn f() -> B { B }
+    
+}
+pub struct B;
+impl B { pub fn h(&self) {} }
+
+
+pub trait T { fn t(&self); }
+impl T for u32 { fn t(&self) {} }
+
+
+
+"#,
+                edit.finish()?,
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn prune_unused_mods() -> anyhow::Result<()> {
+        DUMMY_MOD_NAME.with(|dummy_mod_name| {
+            let mut lib1 = CodeEdit::from_code(
+                dummy_mod_name,
+                r#"pub mod a {
+    pub use self::b::*;
+    mod b {
+        pub fn f() -> u32 { lib2::x::g() }
+    }
+}
+pub mod c {
+    pub fn h() -> u32 { lib2::y::i() }
+}
+"#,
+            )?;
+            let mut lib2 = CodeEdit::from_code(
+                dummy_mod_name,
+                r#"pub mod x {
+    pub fn g() -> u32 { 1 }
+}
+pub mod y {
+    pub fn i() -> u32 { 2 }
+}
+"#,
+            )?;
+            let mut lib3 = CodeEdit::from_code(dummy_mod_name, "pub fn unused() {}\n")?;
+            let kept = rust::prune_unused_mods(
+                dummy_mod_name,
+                "use lib1::a::f; fn main() { f(); }",
+                &mut [
+                    rust::LibToPrune {
+                        edit: &mut lib1,
+                        pseudo_extern_crate_name: "lib1",
+                        extern_crate_names: maplit::hashmap!("lib2".to_owned() => 1),
+                        prunable: true,
+                    },
+                    rust::LibToPrune {
+                        edit: &mut lib2,
+                        pseudo_extern_crate_name: "lib2",
+                        extern_crate_names: maplit::hashmap!(),
+                        prunable: true,
+                    },
+                    rust::LibToPrune {
+                        edit: &mut lib3,
+                        pseudo_extern_crate_name: "lib3",
+                        extern_crate_names: maplit::hashmap!(),
+                        prunable: true,
+                    },
+                ],
+            )?;
+            assert_eq!([true, true, false], *kept);
+            assert_eq!(
+                r#"pub mod a {
+    pub use self::b::*;
+    mod b {
+        pub fn f() -> u32 { lib2::x::g() }
+    }
+}
+
+
+
+"#,
+                lib1.finish()?,
+            );
+            assert_eq!(
+                r#"pub mod x {
+    pub fn g() -> u32 { 1 }
+}
+
+
+
+"#,
+                lib2.finish()?,
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn bundled_crate_sizes() -> anyhow::Result<()> {
+        DUMMY_MOD_NAME.with(|dummy_mod_name| {
+            let sizes = rust::bundled_crate_sizes(
+                dummy_mod_name,
+                r#"fn main() {}
+
+#[allow(unused)]
+mod __ {
+    pub(crate) mod crates {
+        pub mod lib1 {pub fn f() {}}
+        pub mod lib2 {pub fn g() -> &'static str { "ｇ" }}
+    }
+
+    pub(crate) mod macros {
+        pub mod lib1 {}
+    }
+
+    pub(crate) mod prelude {pub use crate::__::crates::*;}
+
+    mod preludes {
+        pub mod lib1 {}
+        pub mod lib2 {}
+    }
+}
+"#,
+            )?;
+            assert_eq!(
+                maplit::btreemap!("lib1".to_owned() => [28, 15, 15], "lib2".to_owned() => [51, 0, 15]),
+                sizes,
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn license_notices_size() -> anyhow::Result<()> {
+        DUMMY_MOD_NAME.with(|dummy_mod_name| {
+            let size = rust::license_notices_size(
+                dummy_mod_name,
+                r#"fn main() {}
+
+/// # Bundled libraries
+///
+/// - `lib1 0.1.0` licensed under `MIT` as `crate::__::crates::lib1`
+///
+/// # License and Copyright Notices
+///
+/// - `lib1 0.1.0`
+///
+///     ```text
+///     ```
+#[allow(unused)]
+mod __ {}
+"#,
+            )?;
+            assert_eq!(90, size);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn uses_of_mod_tree() -> anyhow::Result<()> {
+        DUMMY_MOD_NAME.with(|dummy_mod_name| {
+            let mut edit = CodeEdit::from_code(
+                dummy_mod_name,
+                r#"pub mod a {
+    pub mod b {
+        pub mod c {}
+    }
+    pub mod d {}
+}
+pub mod e {}
+"#,
+            )?;
+            assert_eq!(
+                "use lib::a;\nuse lib::a::d;\nuse lib::a::b;\nuse lib::a::b::c;\n",
+                edit.uses_of_mod_tree("lib", "a")?,
+            );
+            Ok(())
+        })
+    }
 }
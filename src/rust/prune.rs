@@ -0,0 +1,796 @@
+use super::CodeEdit;
+use proc_macro2::{LineColumn, Spacing, TokenStream, TokenTree};
+use quote::ToTokens;
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
+use syn::{
+    Attribute, Item, ItemExternCrate, ItemImpl, ItemMacro, ItemMod, ItemTrait, ItemUse, TraitItem,
+    UseTree,
+};
+
+/// Removes the items of the libraries which cannot be reached from `root_code` or the unprunable
+/// crates.
+///
+/// Items are traced by their names. An item is kept when any identifier in the reachable code is
+/// equal to its name, so an item is removed only if nothing could possibly refer to it.
+pub(super) fn unused_items(
+    root_code: &str,
+    edits: &mut [(&mut CodeEdit<'_>, bool)],
+) -> anyhow::Result<()> {
+    let root_code = root_code
+        .parse::<TokenStream>()
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    if uses_ident_concatenation(root_code.clone()) {
+        return Ok(());
+    }
+
+    let mut roots = vec![];
+    collect_idents(root_code, &mut roots);
+
+    let mut graph = Graph::default();
+
+    for (i, (edit, prunable)) in edits.iter_mut().enumerate() {
+        edit.apply()?;
+        let tokens = edit.file.to_token_stream();
+        if *prunable && !uses_ident_concatenation(tokens.clone()) {
+            graph.add_items(i, None, &edit.file.items);
+        } else {
+            collect_idents(tokens, &mut roots);
+        }
+    }
+
+    let live = graph.solve(roots);
+
+    for (i, (edit, _)) in edits.iter_mut().enumerate() {
+        let mut stack = graph.top_level.get(&i).cloned().unwrap_or_default();
+        while let Some(node) = stack.pop() {
+            let node = &graph.nodes[node];
+            if !live[node.index] {
+                edit.replacements.insert(node.span, "".to_owned());
+            } else {
+                stack.extend(&node.children);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    top_level: HashMap<usize, Vec<usize>>,
+    by_name: HashMap<String, Vec<usize>>,
+    always: Vec<usize>,
+    impls: Vec<(usize, Vec<String>, Option<String>)>,
+}
+
+struct Node {
+    index: usize,
+    span: (LineColumn, LineColumn),
+    parent: Option<usize>,
+    children: Vec<usize>,
+    idents: Vec<String>,
+}
+
+impl Graph {
+    fn add_items(&mut self, edit: usize, parent: Option<usize>, items: &[Item]) {
+        for item in items {
+            let index = self.nodes.len();
+            self.nodes.push(Node {
+                index,
+                span: {
+                    let span = syn::spanned::Spanned::span(item);
+                    (span.start(), span.end())
+                },
+                parent,
+                children: vec![],
+                idents: vec![],
+            });
+            if let Some(parent) = parent {
+                self.nodes[parent].children.push(index);
+            } else {
+                self.top_level.entry(edit).or_default().push(index);
+            }
+
+            if let Item::Mod(ItemMod {
+                attrs,
+                ident,
+                content: Some((_, items)),
+                ..
+            }) = item
+            {
+                let mut idents = vec![];
+                for attr in attrs {
+                    collect_idents(attr.to_token_stream(), &mut idents);
+                }
+                self.nodes[index].idents = idents;
+                if is_always_used(attrs) {
+                    self.always.push(index);
+                }
+                self.register(unraw(ident), index);
+                self.add_items(edit, Some(index), items);
+                continue;
+            }
+
+            collect_idents(item.to_token_stream(), &mut self.nodes[index].idents);
+
+            match item {
+                Item::Const(item) if item.ident == "_" => self.always.push(index),
+                Item::Const(item) => self.register_item(&item.attrs, &item.ident, index),
+                Item::Enum(item) => self.register_item(&item.attrs, &item.ident, index),
+                Item::Fn(item) => self.register_item(&item.attrs, &item.sig.ident, index),
+                Item::Macro(item) => match &item.ident {
+                    Some(ident) => self.register_item(&item.attrs, ident, index),
+                    None => self.always.push(index),
+                },
+                Item::Macro2(item) => self.register_item(&item.attrs, &item.ident, index),
+                Item::Static(item) => self.register_item(&item.attrs, &item.ident, index),
+                Item::Struct(item) => self.register_item(&item.attrs, &item.ident, index),
+                Item::TraitAlias(item) => self.register_item(&item.attrs, &item.ident, index),
+                Item::Type(item) => self.register_item(&item.attrs, &item.ident, index),
+                Item::Union(item) => self.register_item(&item.attrs, &item.ident, index),
+                Item::Trait(item) => self.register_trait(item, index),
+                Item::Impl(item) => self.register_impl(item, index),
+                Item::Use(item) => self.register_use(item, index),
+                _ => self.always.push(index),
+            }
+        }
+    }
+
+    fn register(&mut self, name: String, node: usize) {
+        self.by_name.entry(name).or_default().push(node);
+    }
+
+    fn register_item(&mut self, attrs: &[Attribute], ident: &syn::Ident, node: usize) {
+        if is_always_used(attrs) {
+            self.always.push(node);
+        } else {
+            self.register(unraw(ident), node);
+        }
+    }
+
+    fn register_trait(&mut self, item: &ItemTrait, node: usize) {
+        // A method call does not mention the trait, so the trait is reached through any of its
+        // associated items.
+        self.register_item(&item.attrs, &item.ident, node);
+        for trait_item in &item.items {
+            let ident = match trait_item {
+                TraitItem::Const(item) => &item.ident,
+                TraitItem::Method(item) => &item.sig.ident,
+                TraitItem::Type(item) => &item.ident,
+                _ => continue,
+            };
+            self.register(unraw(ident), node);
+        }
+    }
+
+    fn register_impl(&mut self, item: &ItemImpl, node: usize) {
+        let generics = item
+            .generics
+            .type_params()
+            .map(|p| unraw(&p.ident))
+            .collect::<HashSet<_>>();
+        let mut self_idents = vec![];
+        collect_idents(item.self_ty.to_token_stream(), &mut self_idents);
+        self_idents.retain(|ident| !generics.contains(ident));
+        let trait_ident = item
+            .trait_
+            .as_ref()
+            .and_then(|(_, path, _)| path.segments.last())
+            .map(|segment| unraw(&segment.ident));
+        self.impls.push((node, self_idents, trait_ident));
+    }
+
+    fn register_use(&mut self, item: &ItemUse, node: usize) {
+        let mut leaves = vec![];
+        let always = item.leading_colon.is_some()
+            || is_always_used(&item.attrs)
+            || !use_leaves(&item.tree, None, &mut leaves)
+            || starts_with_std(&item.tree);
+        if always {
+            self.always.push(node);
+        } else {
+            for leaf in leaves {
+                self.register(leaf, node);
+            }
+        }
+    }
+
+    fn solve(&mut self, roots: Vec<String>) -> Vec<bool> {
+        // An `impl` is kept when the local type it is for is reached. If the type is not defined
+        // in the libraries (e.g. `impl Trait for u64` or `impl<T> Trait for T`), the local trait
+        // decides instead. An `impl` which mentions neither is always kept.
+        for (node, self_idents, trait_ident) in std::mem::take(&mut self.impls) {
+            let self_idents = self_idents
+                .into_iter()
+                .filter(|ident| self.by_name.contains_key(ident))
+                .collect::<HashSet<_>>();
+            if !self_idents.is_empty() {
+                for ident in self_idents {
+                    self.register(ident, node);
+                }
+            } else if let Some(trait_ident) =
+                trait_ident.filter(|ident| self.by_name.contains_key(ident))
+            {
+                self.register(trait_ident, node);
+            } else {
+                self.always.push(node);
+            }
+        }
+
+        let mut kept = vec![false; self.nodes.len()];
+        let mut reached = HashSet::new();
+        let mut names = roots;
+        let mut nodes = self.always.clone();
+
+        while !(names.is_empty() && nodes.is_empty()) {
+            for name in names.drain(..) {
+                if let Some(found) = self.by_name.get(&name) {
+                    if reached.insert(name) {
+                        nodes.extend(found);
+                    }
+                }
+            }
+            for node in nodes.drain(..) {
+                if !kept[node] {
+                    kept[node] = true;
+                    names.extend(self.nodes[node].idents.iter().cloned());
+                }
+            }
+        }
+
+        // A module survives if it is named or contains something that survives.
+        let mut live = kept;
+        for node in (0..self.nodes.len()).rev() {
+            if live[node] {
+                if let Some(parent) = self.nodes[node].parent {
+                    live[parent] = true;
+                }
+            }
+        }
+        live
+    }
+}
+
+fn is_always_used(attrs: &[Attribute]) -> bool {
+    attrs.iter().any(|attr| {
+        ["no_mangle", "export_name", "used", "link_section"]
+            .iter()
+            .any(|name| attr.path.is_ident(name))
+    })
+}
+
+/// Collects the names introduced by a `use` tree. Returns `false` if the tree contains a glob or
+/// an underscore import, which cannot be traced by name.
+fn use_leaves(tree: &UseTree, parent: Option<&syn::Ident>, acc: &mut Vec<String>) -> bool {
+    match tree {
+        UseTree::Path(path) => use_leaves(&path.tree, Some(&path.ident), acc),
+        UseTree::Name(name) if name.ident == "self" => {
+            if let Some(parent) = parent {
+                acc.push(unraw(parent));
+            }
+            true
+        }
+        UseTree::Name(name) => {
+            acc.push(unraw(&name.ident));
+            true
+        }
+        UseTree::Rename(rename) if rename.rename == "_" => false,
+        UseTree::Rename(rename) => {
+            acc.push(unraw(&rename.rename));
+            true
+        }
+        UseTree::Glob(_) => false,
+        UseTree::Group(group) => group
+            .items
+            .iter()
+            .fold(true, |all, tree| use_leaves(tree, parent, acc) && all),
+    }
+}
+
+fn starts_with_std(tree: &UseTree) -> bool {
+    match tree {
+        UseTree::Path(path) => ["std", "core", "alloc"].iter().any(|s| path.ident == s),
+        UseTree::Group(group) => group.items.iter().any(starts_with_std),
+        _ => false,
+    }
+}
+
+fn uses_ident_concatenation(tokens: TokenStream) -> bool {
+    let mut follows_concatenation_macro_name = false;
+    for tt in tokens {
+        match &tt {
+            TokenTree::Group(group) if uses_ident_concatenation(group.stream()) => return true,
+            TokenTree::Punct(punct)
+                if punct.as_char() == '!' && follows_concatenation_macro_name =>
+            {
+                return true;
+            }
+            _ => {}
+        }
+        follows_concatenation_macro_name = matches!(
+            &tt,
+            TokenTree::Ident(ident) if ident == "paste" || ident == "concat_idents"
+        );
+    }
+    false
+}
+
+fn collect_idents(tokens: TokenStream, acc: &mut Vec<String>) {
+    for tt in tokens {
+        match tt {
+            TokenTree::Group(group) => collect_idents(group.stream(), acc),
+            TokenTree::Ident(ident) => acc.push(unraw(&ident)),
+            TokenTree::Punct(_) | TokenTree::Literal(_) => {}
+        }
+    }
+}
+
+fn unraw(ident: &syn::Ident) -> String {
+    let ident = ident.to_string();
+    match ident.strip_prefix("r#") {
+        Some(ident) => ident.to_owned(),
+        None => ident,
+    }
+}
+
+/// Removes the inline modules of the libraries which cannot be reached from `root_code`, and
+/// returns whether each library is reached at all.
+///
+/// A module is reached through a path or a `use` in a reached module, through a `pub use` which
+/// the path goes through, through a `macro_rules!` it defines, or through an `impl` for a type or
+/// a trait in a reached module. A module is kept whenever the path to it cannot be resolved.
+pub(super) fn unused_mods(
+    cargo_equip_mod_name: &syn::Ident,
+    root_code: &str,
+    libs: &mut [super::LibToPrune<'_, '_>],
+) -> anyhow::Result<Vec<bool>> {
+    let root_code = syn::parse_file(root_code).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let mut tree = ModTree {
+        cargo_equip_mod_name: cargo_equip_mod_name.to_string(),
+        ..ModTree::default()
+    };
+
+    for (krate, lib) in libs.iter_mut().enumerate() {
+        lib.edit.apply()?;
+        tree.pseudo_extern_crate_names
+            .insert(lib.pseudo_extern_crate_name.to_owned(), krate);
+        tree.extern_crate_names.push(lib.extern_crate_names.clone());
+        let root = tree.add_mod(krate, None, None, &lib.edit.file.items);
+        tree.roots.push(root);
+    }
+    let bin = libs.len();
+    tree.extern_crate_names
+        .push(tree.pseudo_extern_crate_names.clone());
+    let bin_root = tree.add_mod(bin, None, None, &root_code.items);
+    tree.roots.push(bin_root);
+
+    let mut reached = vec![false; tree.mods.len()];
+    let mut queue = vec![];
+    for (i, module) in tree.mods.iter().enumerate() {
+        if module.krate == bin || libs.get(module.krate).map_or(false, |lib| !lib.prunable) {
+            reached[i] = true;
+            queue.push(i);
+        }
+    }
+
+    loop {
+        while let Some(module) = queue.pop() {
+            let targets = tree.mods[module]
+                .paths
+                .iter()
+                .flat_map(|path| tree.resolve(module, path, 0))
+                .chain(
+                    tree.mods[module]
+                        .macro_calls
+                        .iter()
+                        .flat_map(|name| tree.macro_defs.get(name))
+                        .flatten()
+                        .copied(),
+                )
+                .collect::<Vec<_>>();
+            for target in targets {
+                tree.reach(target, &mut reached, &mut queue);
+            }
+        }
+
+        // `impl`s are not named by anyone. Reach one if it is for a type or a trait in a reached
+        // module.
+        let impl_mods = (0..tree.mods.len())
+            .filter(|&module| !reached[module])
+            .filter(|&module| {
+                tree.mods[module]
+                    .impl_headers
+                    .iter()
+                    .flat_map(|path| tree.resolve(module, path, 0))
+                    .any(|target| reached[target])
+            })
+            .collect::<Vec<_>>();
+        if impl_mods.is_empty() {
+            break;
+        }
+        for module in impl_mods {
+            tree.reach(module, &mut reached, &mut queue);
+        }
+    }
+
+    for (krate, lib) in libs.iter_mut().enumerate() {
+        for (module, node) in tree.mods.iter().enumerate() {
+            if node.krate == krate
+                && !reached[module]
+                && matches!(node.parent, Some(parent) if reached[parent])
+            {
+                if let Some(span) = node.span {
+                    lib.edit.replacements.insert(span, "".to_owned());
+                }
+            }
+        }
+    }
+
+    Ok((0..libs.len())
+        .map(|krate| reached[tree.roots[krate]])
+        .collect())
+}
+
+#[derive(Default)]
+struct ModTree {
+    cargo_equip_mod_name: String,
+    pseudo_extern_crate_names: HashMap<String, usize>,
+    extern_crate_names: Vec<HashMap<String, usize>>,
+    roots: Vec<usize>,
+    mods: Vec<Mod>,
+    by_name: HashMap<(usize, String), Vec<usize>>,
+    macro_defs: HashMap<String, Vec<usize>>,
+}
+
+#[derive(Default)]
+struct Mod {
+    krate: usize,
+    parent: Option<usize>,
+    span: Option<(LineColumn, LineColumn)>,
+    children: HashMap<String, usize>,
+    aliases: HashMap<String, Vec<Vec<String>>>,
+    globs: Vec<Vec<String>>,
+    paths: Vec<Vec<String>>,
+    macro_calls: Vec<String>,
+    impl_headers: Vec<Vec<String>>,
+}
+
+impl ModTree {
+    fn add_mod(
+        &mut self,
+        krate: usize,
+        parent: Option<usize>,
+        span: Option<(LineColumn, LineColumn)>,
+        items: &[Item],
+    ) -> usize {
+        let index = self.mods.len();
+        self.mods.push(Mod {
+            krate,
+            parent,
+            span,
+            ..Mod::default()
+        });
+
+        for item in items {
+            match item {
+                Item::Mod(ItemMod {
+                    ident,
+                    content: Some((_, items)),
+                    ..
+                }) => {
+                    let span = syn::spanned::Spanned::span(item);
+                    let span = Some((span.start(), span.end()));
+                    let child = self.add_mod(krate, Some(index), span, items);
+                    self.mods[index].children.insert(unraw(ident), child);
+                    self.by_name
+                        .entry((krate, unraw(ident)))
+                        .or_default()
+                        .push(child);
+                    continue;
+                }
+                Item::Use(ItemUse {
+                    leading_colon,
+                    tree,
+                    ..
+                }) => {
+                    let prefix = if leading_colon.is_some() {
+                        vec!["::".to_owned()]
+                    } else {
+                        vec![]
+                    };
+                    let module = &mut self.mods[index];
+                    use_aliases(tree, prefix, &mut module.aliases, &mut module.globs);
+                }
+                Item::ExternCrate(ItemExternCrate {
+                    ident,
+                    rename: Some((_, rename)),
+                    ..
+                }) => {
+                    self.mods[index]
+                        .aliases
+                        .entry(unraw(rename))
+                        .or_default()
+                        .push(vec!["::".to_owned(), unraw(ident)]);
+                }
+                Item::Impl(ItemImpl {
+                    trait_, self_ty, ..
+                }) => {
+                    let mut headers = vec![];
+                    if let Some((_, path, _)) = trait_ {
+                        scan_paths(path.to_token_stream(), &[], &mut headers, &mut vec![]);
+                    }
+                    scan_paths(self_ty.to_token_stream(), &[], &mut headers, &mut vec![]);
+                    self.mods[index].impl_headers.extend(headers);
+                }
+                Item::Macro(ItemMacro {
+                    ident: Some(ident), ..
+                }) => {
+                    self.macro_defs.entry(unraw(ident)).or_default().push(index);
+                }
+                _ => {}
+            }
+            let module = &mut self.mods[index];
+            scan_paths(
+                item.to_token_stream(),
+                &[],
+                &mut module.paths,
+                &mut module.macro_calls,
+            );
+        }
+        index
+    }
+
+    fn reach(&self, module: usize, reached: &mut [bool], queue: &mut Vec<usize>) {
+        let mut module = Some(module);
+        while let Some(i) = module.filter(|&i| !reached[i]) {
+            reached[i] = true;
+            queue.push(i);
+            module = self.mods[i].parent;
+        }
+    }
+
+    /// Resolves `path` in `module` to the innermost modules it goes through.
+    fn resolve(&self, module: usize, path: &[String], depth: usize) -> Vec<usize> {
+        const MAX_DEPTH: usize = 8;
+
+        let krate = self.mods[module].krate;
+        let (first, rest) = match path.split_first() {
+            Some(split) if depth < MAX_DEPTH => split,
+            _ => return vec![],
+        };
+
+        let (starts, rest) = match &**first {
+            "::" => match rest.split_first() {
+                Some((name, rest)) => (self.extern_crate_root(krate, name), rest),
+                None => return vec![],
+            },
+            "crate" => match rest {
+                [cargo_equip_mod_name, crates, name, rest @ ..]
+                    if krate + 1 == self.roots.len()
+                        && *cargo_equip_mod_name == self.cargo_equip_mod_name
+                        && crates == "crates" =>
+                {
+                    let starts = self
+                        .pseudo_extern_crate_names
+                        .get(name)
+                        .map(|&krate| self.roots[krate])
+                        .into_iter()
+                        .collect();
+                    (starts, rest)
+                }
+                rest => (vec![self.roots[krate]], rest),
+            },
+            "self" => (vec![module], rest),
+            "super" => (self.mods[module].parent.into_iter().collect(), rest),
+            "Self" => return vec![],
+            name => {
+                let mut starts = self.lookup(module, name, depth);
+                if starts.is_empty() {
+                    starts = self.extern_crate_root(krate, name);
+                }
+                if starts.is_empty() && !rest.is_empty() {
+                    // e.g. a name imported by a `use` in a function body
+                    starts = self
+                        .by_name
+                        .get(&(krate, name.to_owned()))
+                        .cloned()
+                        .unwrap_or_default();
+                }
+                (starts, rest)
+            }
+        };
+
+        starts
+            .into_iter()
+            .flat_map(|start| self.walk(start, rest, depth))
+            .collect()
+    }
+
+    fn walk(&self, mut module: usize, path: &[String], depth: usize) -> Vec<usize> {
+        for (i, segment) in path.iter().enumerate() {
+            let next = match &**segment {
+                "super" => self.mods[module].parent.into_iter().collect(),
+                "self" => vec![module],
+                segment => self.lookup(module, segment, depth),
+            };
+            match *next {
+                [] => break,
+                [next] => module = next,
+                _ => {
+                    return next
+                        .into_iter()
+                        .flat_map(|next| self.walk(next, &path[i + 1..], depth))
+                        .collect();
+                }
+            }
+        }
+        vec![module]
+    }
+
+    fn lookup(&self, module: usize, name: &str, depth: usize) -> Vec<usize> {
+        let node = &self.mods[module];
+        if let Some(&child) = node.children.get(name) {
+            return vec![child];
+        }
+        let mut found = node
+            .aliases
+            .get(name)
+            .into_iter()
+            .flatten()
+            .flat_map(|path| self.resolve(module, path, depth + 1))
+            .collect::<Vec<_>>();
+        if found.is_empty() {
+            found = node
+                .globs
+                .iter()
+                .flat_map(|path| self.resolve(module, path, depth + 1))
+                .filter(|&glob| glob != module)
+                .flat_map(|glob| self.lookup(glob, name, depth + 1))
+                .collect();
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+
+    fn extern_crate_root(&self, krate: usize, name: &str) -> Vec<usize> {
+        self.extern_crate_names[krate]
+            .get(name)
+            .map(|&krate| self.roots[krate])
+            .into_iter()
+            .collect()
+    }
+}
+
+fn use_aliases(
+    tree: &UseTree,
+    mut prefix: Vec<String>,
+    aliases: &mut HashMap<String, Vec<Vec<String>>>,
+    globs: &mut Vec<Vec<String>>,
+) {
+    match tree {
+        UseTree::Path(path) => {
+            prefix.push(unraw(&path.ident));
+            use_aliases(&path.tree, prefix, aliases, globs);
+        }
+        UseTree::Name(name) if name.ident == "self" => {
+            if let Some(last) = prefix.last() {
+                aliases.entry(last.clone()).or_default().push(prefix);
+            }
+        }
+        UseTree::Name(name) => {
+            let name = unraw(&name.ident);
+            prefix.push(name.clone());
+            aliases.entry(name).or_default().push(prefix);
+        }
+        UseTree::Rename(rename) if rename.rename == "_" => {}
+        UseTree::Rename(rename) => {
+            if rename.ident != "self" {
+                prefix.push(unraw(&rename.ident));
+            }
+            aliases
+                .entry(unraw(&rename.rename))
+                .or_default()
+                .push(prefix);
+        }
+        UseTree::Glob(_) => globs.push(prefix),
+        UseTree::Group(group) => {
+            for tree in &group.items {
+                use_aliases(tree, prefix.clone(), aliases, globs);
+            }
+        }
+    }
+}
+
+/// Collects the paths such as `a::b::c`, `::a::b`, `$crate::a` and `a::{b, c::d}` in `tokens`, and
+/// the names of the macros called in `tokens`.
+fn scan_paths(
+    tokens: TokenStream,
+    prefix: &[String],
+    paths: &mut Vec<Vec<String>>,
+    macro_calls: &mut Vec<String>,
+) {
+    let mut path = prefix.to_owned();
+    let mut after_colons = !prefix.is_empty();
+    let mut tokens = tokens.into_iter().peekable();
+
+    let flush = |path: &mut Vec<String>, paths: &mut Vec<Vec<String>>| {
+        if path.len() > prefix.len() {
+            paths.push(mem::replace(path, prefix.to_owned()));
+        } else {
+            path.truncate(prefix.len());
+        }
+    };
+
+    while let Some(tt) = tokens.next() {
+        match tt {
+            TokenTree::Ident(ident) => {
+                if !after_colons {
+                    flush(&mut path, paths);
+                }
+                path.push(unraw(&ident));
+                after_colons = false;
+            }
+            TokenTree::Punct(punct)
+                if punct.as_char() == ':'
+                    && punct.spacing() == Spacing::Joint
+                    && matches!(tokens.peek(), Some(TokenTree::Punct(p)) if p.as_char() == ':') =>
+            {
+                tokens.next();
+                if path.len() == prefix.len() && !after_colons {
+                    path.push("::".to_owned());
+                }
+                after_colons = true;
+            }
+            TokenTree::Punct(punct)
+                if punct.as_char() == '!' && punct.spacing() == Spacing::Alone && !after_colons =>
+            {
+                if path.len() > prefix.len() {
+                    macro_calls.extend(path.last().cloned());
+                }
+                flush(&mut path, paths);
+            }
+            TokenTree::Group(group)
+                if after_colons && group.delimiter() == proc_macro2::Delimiter::Brace =>
+            {
+                for tree in split_use_group(group.stream()) {
+                    scan_paths(tree, &path, paths, macro_calls);
+                }
+                path.truncate(prefix.len());
+                after_colons = false;
+            }
+            TokenTree::Group(group) => {
+                flush(&mut path, paths);
+                after_colons = false;
+                scan_paths(group.stream(), &[], paths, macro_calls);
+            }
+            _ => {
+                flush(&mut path, paths);
+                after_colons = false;
+            }
+        }
+    }
+    flush(&mut path, paths);
+}
+
+fn split_use_group(tokens: TokenStream) -> Vec<TokenStream> {
+    let mut trees = vec![TokenStream::new()];
+    let mut after_as = false;
+    for tt in tokens {
+        match &tt {
+            TokenTree::Punct(punct) if punct.as_char() == ',' => {
+                trees.push(TokenStream::new());
+                after_as = false;
+            }
+            TokenTree::Ident(ident) if ident == "as" => after_as = true,
+            _ if after_as => {}
+            _ => trees.last_mut().unwrap().extend(Some(tt)),
+        }
+    }
+    trees
+}
@@ -1,23 +1,27 @@
 use std::{
     fmt,
     io::{self, Sink, Write},
+    mem,
 };
 use termcolor::{Color, ColorSpec, NoColor, StandardStream, WriteColor};
 
 pub struct Shell {
     output: ShellOut,
+    warnings: Vec<String>,
 }
 
 impl Shell {
     pub fn new() -> Self {
         Self {
             output: ShellOut::stream(),
+            warnings: vec![],
         }
     }
 
     pub fn from_stdout(stdout: Box<dyn Write>) -> Shell {
         Self {
             output: ShellOut::write(stdout),
+            warnings: vec![],
         }
     }
 
@@ -44,13 +48,41 @@ impl Shell {
     }
 
     pub(crate) fn warn(&mut self, message: impl fmt::Display) -> io::Result<()> {
+        self.warnings.push(message.to_string());
         self.print("warning", message, Color::Yellow, false)
     }
 
+    /// Returns the warnings printed since the last call to [`Shell::take_warnings`].
+    pub(crate) fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Returns the warnings printed since the last call.
+    pub(crate) fn take_warnings(&mut self) -> Vec<String> {
+        mem::take(&mut self.warnings)
+    }
+
     pub fn error(&mut self, message: impl fmt::Display) -> io::Result<()> {
         self.print("error", message, Color::Red, false)
     }
 
+    /// Prints the error and its causes.
+    pub fn report(&mut self, err: &anyhow::Error) -> io::Result<()> {
+        self.error(err)?;
+
+        for cause in err.chain().skip(1) {
+            writeln!(self.err(), "\nCaused by:")?;
+
+            for line in cause.to_string().lines() {
+                match line {
+                    "" => writeln!(self.err())?,
+                    line => writeln!(self.err(), "  {}", line)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn print(
         &mut self,
         status: impl fmt::Display,
@@ -0,0 +1,133 @@
+use camino::Utf8PathBuf;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+const INTERVAL: Duration = Duration::from_millis(500);
+
+/// Lists the modification times of the files in `dirs`, except for `ignore`, `target` and hidden
+/// directories.
+pub(crate) fn mtimes(dirs: &[Utf8PathBuf], ignore: &[&Path]) -> BTreeMap<PathBuf, SystemTime> {
+    let mut mtimes = BTreeMap::new();
+    for dir in dirs {
+        walk(dir.as_ref(), ignore, &mut mtimes);
+    }
+    return mtimes;
+
+    fn walk(dir: &Path, ignore: &[&Path], mtimes: &mut BTreeMap<PathBuf, SystemTime>) {
+        // Files can be removed while walking. Treat them as missing.
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(_) => continue,
+            };
+            if file_type.is_dir() {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if name != "target" && !name.starts_with('.') {
+                    walk(&path, ignore, mtimes);
+                }
+            } else if !ignore.contains(&&*path) {
+                if let Ok(mtime) = entry.metadata().and_then(|m| m.modified()) {
+                    mtimes.insert(path, mtime);
+                }
+            }
+        }
+    }
+}
+
+/// Polls `dirs` until any file is added, modified or removed, and returns the changed files.
+pub(crate) fn wait_for_changes(
+    dirs: &[Utf8PathBuf],
+    ignore: &[&Path],
+    mtimes: &mut BTreeMap<PathBuf, SystemTime>,
+) -> Vec<PathBuf> {
+    loop {
+        thread::sleep(INTERVAL);
+
+        let new_mtimes = self::mtimes(dirs, ignore);
+        let changed = changes(mtimes, &new_mtimes);
+
+        if !changed.is_empty() {
+            *mtimes = new_mtimes;
+            return changed;
+        }
+    }
+}
+
+fn changes(
+    mtimes: &BTreeMap<PathBuf, SystemTime>,
+    new_mtimes: &BTreeMap<PathBuf, SystemTime>,
+) -> Vec<PathBuf> {
+    new_mtimes
+        .iter()
+        .filter(|&(path, mtime)| mtimes.get(path) != Some(mtime))
+        .map(|(path, _)| path)
+        .chain(mtimes.keys().filter(|path| !new_mtimes.contains_key(*path)))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use maplit::btreemap;
+    use pretty_assertions::assert_eq;
+    use std::{
+        path::{Path, PathBuf},
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    fn mtimes() -> anyhow::Result<()> {
+        let dir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let dir = dir.path();
+        for path in [
+            "src/main.rs",
+            "src/a/mod.rs",
+            "src/out.rs",
+            "src/report.json",
+            "src/target/debug/x",
+            "src/.git/HEAD",
+        ] {
+            cargo_util::paths::create_dir_all(dir.join(path).parent().unwrap())?;
+            cargo_util::paths::write(dir.join(path), "")?;
+        }
+
+        let dirs = &[Utf8PathBuf::from_path_buf(dir.join("src")).unwrap()];
+        let ignore = &[&*dir.join("src/out.rs"), &*dir.join("src/report.json")];
+        assert_eq!(
+            [dir.join("src/a/mod.rs"), dir.join("src/main.rs")],
+            &*super::mtimes(dirs, ignore).into_keys().collect::<Vec<_>>(),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn changes() {
+        let t0 = SystemTime::UNIX_EPOCH;
+        let t1 = t0 + Duration::from_secs(1);
+        let path = |p: &str| Path::new(p).to_owned();
+
+        let mtimes = btreemap!(path("a.rs") => t0, path("b.rs") => t0, path("c.rs") => t0);
+        assert_eq!(Vec::<PathBuf>::new(), super::changes(&mtimes, &mtimes));
+        assert_eq!(
+            [path("b.rs"), path("d.rs"), path("c.rs")],
+            &*super::changes(
+                &mtimes,
+                &btreemap!(path("a.rs") => t0, path("b.rs") => t1, path("d.rs") => t0),
+            ),
+        );
+    }
+}
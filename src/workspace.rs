@@ -1,7 +1,10 @@
+mod features;
+#[cfg(test)]
+pub(crate) mod fixture;
 mod license;
 
 use crate::{process::ProcessBuilderExt as _, shell::Shell, toolchain, User};
-use anyhow::{bail, Context as _};
+use anyhow::{anyhow, bail, Context as _};
 use camino::{Utf8Path, Utf8PathBuf};
 use cargo_metadata as cm;
 use cargo_util::ProcessBuilder;
@@ -15,6 +18,7 @@ use std::{
     collections::{BTreeMap, HashMap, HashSet},
     env,
     io::Cursor,
+    iter,
     path::{Path, PathBuf},
     str,
 };
@@ -32,11 +36,23 @@ pub(crate) fn locate_project(cwd: &Path) -> anyhow::Result<PathBuf> {
         })
 }
 
-pub(crate) fn cargo_metadata(manifest_path: &Path, cwd: &Path) -> cm::Result<cm::Metadata> {
-    cm::MetadataCommand::new()
-        .manifest_path(manifest_path)
-        .current_dir(cwd)
-        .exec()
+pub(crate) fn cargo_metadata(
+    manifest_path: &Path,
+    feature_flags: &FeatureFlags,
+    cwd: &Path,
+) -> cm::Result<cm::Metadata> {
+    let mut cmd = cm::MetadataCommand::new();
+    cmd.manifest_path(manifest_path).current_dir(cwd);
+    if !feature_flags.features.is_empty() {
+        cmd.features(cm::CargoOpt::SomeFeatures(feature_flags.features.clone()));
+    }
+    if feature_flags.all_features {
+        cmd.features(cm::CargoOpt::AllFeatures);
+    }
+    if feature_flags.no_default_features {
+        cmd.features(cm::CargoOpt::NoDefaultFeatures);
+    }
+    cmd.exec()
 }
 
 pub(crate) fn resolve_behavior(
@@ -45,9 +61,10 @@ pub(crate) fn resolve_behavior(
 ) -> anyhow::Result<ResolveBehavior> {
     let cargo_toml = &cargo_util::paths::read(workspace_root.join("Cargo.toml").as_ref())?;
     let CargoToml { workspace } = toml::from_str(cargo_toml)?;
-    return Ok(workspace
-        .resolver
-        .unwrap_or_else(|| package.edition().default_resolver_behavior()));
+    return match workspace.resolver {
+        Some(resolver) => Ok(resolver),
+        None => Ok(package.edition()?.default_resolver_behavior()),
+    };
 
     #[derive(Deserialize)]
     struct CargoToml {
@@ -61,11 +78,158 @@ pub(crate) fn resolve_behavior(
     }
 }
 
+/// Lists the packages that a judge environment (`Cargo.toml` or `Cargo.lock`) provides, matched by
+/// name and semver-compatible version.
+pub(crate) fn packages_provided_by(
+    path: &Path,
+    metadata: &cm::Metadata,
+) -> anyhow::Result<Vec<PkgSpec>> {
+    let provided = match toml::from_str(&cargo_util::paths::read(path)?).with_context(|| {
+        format!(
+            "could not parse `{}` as a `Cargo.toml` or a `Cargo.lock`",
+            path.display(),
+        )
+    })? {
+        JudgeEnvironment::Lock { package } => package
+            .into_iter()
+            .flat_map(
+                |LockedPackage {
+                     name,
+                     version,
+                     source,
+                 }| {
+                    let compatibility = semver_compatibility(
+                        version.major,
+                        Some(version.minor),
+                        Some(version.patch),
+                    );
+                    Some((name, source?, compatibility))
+                },
+            )
+            .collect::<Vec<_>>(),
+        JudgeEnvironment::Manifest {
+            dependencies,
+            target,
+            workspace,
+        } => dependencies
+            .into_iter()
+            .chain(target.into_iter().flat_map(|(_, t)| t.dependencies))
+            .chain(workspace.dependencies)
+            .flat_map(|(name_in_toml, dep)| {
+                let (name, req) = match dep {
+                    Dependency::Simple(req) => (name_in_toml, req),
+                    Dependency::Detailed {
+                        package,
+                        version: Some(req),
+                        path: None,
+                        git: None,
+                        registry: None,
+                    } => (package.unwrap_or(name_in_toml), req),
+                    Dependency::Detailed { .. } => return None,
+                };
+                let semver::Comparator {
+                    major,
+                    minor,
+                    patch,
+                    ..
+                } = *req.comparators.first()?;
+                let compatibility = semver_compatibility(major, minor, patch);
+                Some((name, CRATES_IO.to_owned(), compatibility))
+            })
+            .collect(),
+    };
+
+    return Ok(metadata
+        .packages
+        .iter()
+        .filter(|package| {
+            let compatibility = semver_compatibility(
+                package.version.major,
+                Some(package.version.minor),
+                Some(package.version.patch),
+            );
+            provided.iter().any(|(name, source, c)| {
+                *name == package.name
+                    && package.source.as_ref().map(|s| &s.repr) == Some(source)
+                    && *c == compatibility
+            })
+        })
+        .map(|package| PkgSpec {
+            name: package.name.clone(),
+            version: Some(package.version.clone()),
+            url: None,
+        })
+        .collect());
+
+    static CRATES_IO: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+    fn semver_compatibility(
+        major: u64,
+        minor: Option<u64>,
+        patch: Option<u64>,
+    ) -> (u64, Option<u64>, Option<u64>) {
+        match (major, minor) {
+            (0, Some(0)) => (0, Some(0), patch),
+            (0, minor) => (0, minor, None),
+            (major, _) => (major, None, None),
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum JudgeEnvironment {
+        Lock {
+            package: Vec<LockedPackage>,
+        },
+        Manifest {
+            #[serde(default)]
+            dependencies: BTreeMap<String, Dependency>,
+            #[serde(default)]
+            target: BTreeMap<String, Target>,
+            #[serde(default)]
+            workspace: Workspace,
+        },
+    }
+
+    #[derive(Deserialize)]
+    struct LockedPackage {
+        name: String,
+        version: semver::Version,
+        source: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    struct Target {
+        #[serde(default)]
+        dependencies: BTreeMap<String, Dependency>,
+    }
+
+    #[derive(Default, Deserialize)]
+    struct Workspace {
+        #[serde(default)]
+        dependencies: BTreeMap<String, Dependency>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Dependency {
+        Simple(semver::VersionReq),
+        Detailed {
+            package: Option<String>,
+            version: Option<semver::VersionReq>,
+            path: Option<String>,
+            git: Option<String>,
+            registry: Option<String>,
+        },
+    }
+}
+
 pub(crate) fn cargo_check_message_format_json(
     toolchain: &str,
     metadata: &cm::Metadata,
     package: &cm::Package,
-    krate: &cm::Target,
+    krates: &[&cm::Target],
+    feature_flags: &FeatureFlags,
     shell: &mut Shell,
 ) -> anyhow::Result<Vec<cm::Message>> {
     let messages = ProcessBuilder::new(toolchain::rustup_exe(package.manifest_dir())?)
@@ -77,7 +241,13 @@ pub(crate) fn cargo_check_message_format_json(
         .arg("json")
         .arg("-p")
         .arg(format!("{}:{}", package.name, package.version))
-        .args(&krate.target_option())
+        .args(
+            &krates
+                .iter()
+                .flat_map(|krate| krate.target_option())
+                .collect::<Vec<_>>(),
+        )
+        .args(&feature_flags.to_args())
         .cwd(&metadata.workspace_root)
         .try_inspect(|this| shell.status("Running", this))?
         .read_stdout::<Vec<u8>>()?;
@@ -111,6 +281,7 @@ pub(crate) fn cargo_check_using_current_lockfile_and_cache(
     package: &cm::Package,
     target: &cm::Target,
     exclude: &[PkgSpec],
+    feature_flags: &FeatureFlags,
     code: &str,
 ) -> anyhow::Result<()> {
     let package_name = {
@@ -223,6 +394,41 @@ pub(crate) fn cargo_check_using_current_lockfile_and_cache(
         modify_dependencies(table);
     }
 
+    // The features which refer to the bundled dependencies are dropped along with them.
+    let dep_names = |manifest: &toml_edit::Document| {
+        ["dependencies", "dev-dependencies"]
+            .iter()
+            .flat_map(|key| manifest[key].as_table())
+            .flat_map(|table| table.iter().map(|(name, _)| name.to_owned()))
+            .collect::<HashSet<_>>()
+    };
+    let remaining_deps = dep_names(&temp_manifest);
+    let removed_deps = &dep_names(&orig_manifest) - &remaining_deps;
+    let mut feature_names = HashSet::new();
+    if let Some(orig_features) = orig_manifest["features"].as_table() {
+        temp_manifest["features"] = toml_edit::Item::Table(retain_features(
+            orig_features,
+            &remaining_deps,
+            &removed_deps,
+        ));
+        feature_names.extend(orig_features.iter().map(|(name, _)| name.to_owned()));
+    }
+    let feature_flags = FeatureFlags {
+        features: feature_flags
+            .features
+            .iter()
+            .filter(|f| {
+                if f.contains('/') {
+                    refers_to_remaining_deps(f, &remaining_deps)
+                } else {
+                    feature_names.contains(*f) || remaining_deps.contains(*f)
+                }
+            })
+            .cloned()
+            .collect(),
+        ..feature_flags.clone()
+    };
+
     cargo_util::paths::write(
         temp_pkg.path().join("Cargo.toml"),
         temp_manifest.to_string(),
@@ -246,6 +452,7 @@ pub(crate) fn cargo_check_using_current_lockfile_and_cache(
         } else {
             vec!["--lib"]
         })
+        .args(&feature_flags.to_args())
         .arg("--offline")
         .cwd(&metadata.workspace_root)
         .exec()?;
@@ -255,27 +462,54 @@ pub(crate) fn cargo_check_using_current_lockfile_and_cache(
 }
 
 pub(crate) trait MetadataExt {
-    fn exactly_one_target(&self) -> anyhow::Result<(&cm::Target, &cm::Package)>;
-    fn lib_target(&self) -> anyhow::Result<(&cm::Target, &cm::Package)>;
+    fn exactly_one_target(
+        &self,
+        package: Option<&PkgSpec>,
+    ) -> anyhow::Result<(&cm::Target, &cm::Package)>;
+    fn lib_target(&self, package: Option<&PkgSpec>) -> anyhow::Result<(&cm::Target, &cm::Package)>;
     fn bin_target_by_name<'a>(
         &'a self,
+        package: Option<&PkgSpec>,
         name: &str,
     ) -> anyhow::Result<(&'a cm::Target, &'a cm::Package)>;
     fn example_target_by_name<'a>(
         &'a self,
+        package: Option<&PkgSpec>,
         name: &str,
     ) -> anyhow::Result<(&'a cm::Target, &'a cm::Package)>;
     fn target_by_src_path<'a>(
         &'a self,
+        package: Option<&PkgSpec>,
         src_path: &Path,
     ) -> anyhow::Result<(&'a cm::Target, &'a cm::Package)>;
+    fn bin_like_targets<'a>(
+        &'a self,
+        package: Option<&PkgSpec>,
+        examples: bool,
+    ) -> anyhow::Result<(Vec<&'a cm::Target>, &'a cm::Package)>;
+    fn resolve_features<'a>(
+        &'a self,
+        package_id: &'a cm::PackageId,
+        need_dev_deps: bool,
+        resolve_behavior: ResolveBehavior,
+        feature_flags: &FeatureFlags,
+        target_preds: &[cfg_expr::Predicate<'_>],
+    ) -> anyhow::Result<HashMap<&'a cm::PackageId, Vec<String>>>;
+    #[allow(clippy::type_complexity)]
     fn libs_to_bundle<'a>(
         &'a self,
         package_id: &'a cm::PackageId,
         need_dev_deps: bool,
+        features: &HashMap<&'a cm::PackageId, Vec<String>>,
+        target_preds: &[cfg_expr::Predicate<'_>],
         cargo_udeps_outcome: &HashSet<String>,
         exclude: &[PkgSpec],
-    ) -> anyhow::Result<BTreeMap<&'a cm::PackageId, (&'a cm::Target, String)>>;
+    ) -> anyhow::Result<(
+        BTreeMap<&'a cm::PackageId, (&'a cm::Target, String)>,
+        Vec<(&'a cm::PackageId, Vec<semver::Version>)>,
+        Vec<&'a cm::PackageId>,
+        Vec<&'a cm::PackageId>,
+    )>;
     fn dep_lib_by_extern_crate_name(
         &self,
         package_id: &cm::PackageId,
@@ -289,10 +523,30 @@ pub(crate) trait MetadataExt {
 }
 
 impl MetadataExt for cm::Metadata {
-    fn exactly_one_target(&self) -> anyhow::Result<(&cm::Target, &cm::Package)> {
-        let root_package = self.root_package();
+    fn exactly_one_target(
+        &self,
+        package: Option<&PkgSpec>,
+    ) -> anyhow::Result<(&cm::Target, &cm::Package)> {
+        let root_package = selected_package(self, package)?;
+
+        if let Some(root_package) = root_package {
+            if let Some(default_run) = &root_package.default_run {
+                return root_package
+                    .targets
+                    .iter()
+                    .find(|t| t.is_bin() && t.name == *default_run)
+                    .map(|t| (t, root_package))
+                    .with_context(|| {
+                        format!(
+                            "`default-run` of `{}` is `{}`, but there is no such bin target",
+                            root_package.name, default_run,
+                        )
+                    });
+            }
+        }
+
         match (
-            &*targets_in_ws(self)
+            &*targets_in_ws(self, package)?
                 .filter(|(t, p)| {
                     (t.is_lib() || t.is_bin() || t.is_example())
                         && root_package.map_or(true, |r| r.id == p.id)
@@ -307,9 +561,9 @@ impl MetadataExt for cm::Metadata {
             ([t], _) => Ok(*t),
             ([ts @ ..], _) => bail!(
                 "could not determine which target to choose. Use the `--bin` option, `--example` \
-                 option, `--lib` option, or `--src` option to specify a target.\n\
-                 available targets: {}\n\
-                 note: currently `cargo-equip` does not support the `default-run` manifest key.",
+                 option, `--lib` option, or `--src` option to specify a target, or set the \
+                 `default-run` manifest key.\n\
+                 available targets: {}",
                 ts.iter()
                     .map(|(target, _)| format!(
                         "{}{}",
@@ -329,10 +583,10 @@ impl MetadataExt for cm::Metadata {
         }
     }
 
-    fn lib_target(&self) -> anyhow::Result<(&cm::Target, &cm::Package)> {
-        let root_package = self.root_package();
+    fn lib_target(&self, package: Option<&PkgSpec>) -> anyhow::Result<(&cm::Target, &cm::Package)> {
+        let root_package = selected_package(self, package)?;
         match (
-            &*targets_in_ws(self)
+            &*targets_in_ws(self, package)?
                 .filter(|(t, p)| t.is_lib() && root_package.map_or(true, |r| r.id == p.id))
                 .collect::<Vec<_>>(),
             root_package,
@@ -351,23 +605,26 @@ impl MetadataExt for cm::Metadata {
 
     fn bin_target_by_name<'a>(
         &'a self,
+        package: Option<&PkgSpec>,
         name: &str,
     ) -> anyhow::Result<(&'a cm::Target, &'a cm::Package)> {
-        target_by_kind_and_name(self, "bin", name)
+        target_by_kind_and_name(self, package, "bin", name)
     }
 
     fn example_target_by_name<'a>(
         &'a self,
+        package: Option<&PkgSpec>,
         name: &str,
     ) -> anyhow::Result<(&'a cm::Target, &'a cm::Package)> {
-        target_by_kind_and_name(self, "example", name)
+        target_by_kind_and_name(self, package, "example", name)
     }
 
     fn target_by_src_path<'a>(
         &'a self,
+        package: Option<&PkgSpec>,
         src_path: &Path,
     ) -> anyhow::Result<(&'a cm::Target, &'a cm::Package)> {
-        match *targets_in_ws(self)
+        match *targets_in_ws(self, package)?
             .filter(|(t, _)| t.src_path == src_path)
             .collect::<Vec<_>>()
         {
@@ -383,13 +640,110 @@ impl MetadataExt for cm::Metadata {
         }
     }
 
+    fn bin_like_targets<'a>(
+        &'a self,
+        package: Option<&PkgSpec>,
+        examples: bool,
+    ) -> anyhow::Result<(Vec<&'a cm::Target>, &'a cm::Package)> {
+        let root_package = selected_package(self, package)?;
+        let kinds = if examples { "bin/example" } else { "bin" };
+        let targets = targets_in_ws(self, package)?
+            .filter(|(t, p)| {
+                (t.is_bin() || examples && t.is_example())
+                    && root_package.map_or(true, |r| r.id == p.id)
+            })
+            .collect::<Vec<_>>();
+        match (
+            &*targets
+                .iter()
+                .map(|(_, p)| *p)
+                .unique_by(|p| &p.id)
+                .collect::<Vec<_>>(),
+            root_package,
+        ) {
+            ([], Some(root_package)) => bail!("no {} target in `{}`", kinds, root_package.name),
+            ([], None) => bail!("no {} target in this workspace", kinds),
+            ([package], _) => Ok((targets.into_iter().map(|(t, _)| t).collect(), package)),
+            ([..], _) => bail!(
+                "could not determine which package to choose. Use the `-p` option to specify a \
+                 package.",
+            ),
+        }
+    }
+
+    fn resolve_features<'a>(
+        &'a self,
+        package_id: &'a cm::PackageId,
+        need_dev_deps: bool,
+        resolve_behavior: ResolveBehavior,
+        feature_flags: &FeatureFlags,
+        target_preds: &[cfg_expr::Predicate<'_>],
+    ) -> anyhow::Result<HashMap<&'a cm::PackageId, Vec<String>>> {
+        let cm::Resolve { nodes, .. } = self
+            .resolve
+            .as_ref()
+            .with_context(|| "`resolve` is `null`")?;
+
+        match resolve_behavior {
+            ResolveBehavior::V1 => Ok(nodes
+                .iter()
+                .map(|cm::Node { id, features, .. }| (id, features.clone()))
+                .collect()),
+            ResolveBehavior::V2 | ResolveBehavior::V3 => {
+                let package = &self[package_id];
+
+                let mut root_features = vec![];
+                if !feature_flags.no_default_features {
+                    root_features.push("default".to_owned());
+                }
+                if feature_flags.all_features {
+                    root_features.extend(package.features.keys().cloned());
+                    root_features.extend(
+                        package
+                            .dependencies
+                            .iter()
+                            .filter(|cm::Dependency { optional, .. }| *optional)
+                            .map(|cm::Dependency { name, rename, .. }| {
+                                format!("dep:{}", rename.as_ref().unwrap_or(name))
+                            }),
+                    );
+                }
+                root_features.extend(feature_flags.features.iter().map(|feature| {
+                    feature
+                        .strip_prefix(&format!("{}/", package.name))
+                        .unwrap_or(feature)
+                        .to_owned()
+                }));
+
+                features::resolve(
+                    self,
+                    package_id,
+                    need_dev_deps,
+                    &root_features,
+                    |cm::Dependency { target, .. }| {
+                        target.as_ref().map_or(true, |target| {
+                            platform_matches(&target.to_string(), target_preds)
+                        })
+                    },
+                )
+            }
+        }
+    }
+
     fn libs_to_bundle<'a>(
         &'a self,
         package_id: &'a cm::PackageId,
         need_dev_deps: bool,
+        features: &HashMap<&'a cm::PackageId, Vec<String>>,
+        target_preds: &[cfg_expr::Predicate<'_>],
         cargo_udeps_outcome: &HashSet<String>,
         exclude: &[PkgSpec],
-    ) -> anyhow::Result<BTreeMap<&'a cm::PackageId, (&'a cm::Target, String)>> {
+    ) -> anyhow::Result<(
+        BTreeMap<&'a cm::PackageId, (&'a cm::Target, String)>,
+        Vec<(&'a cm::PackageId, Vec<semver::Version>)>,
+        Vec<&'a cm::PackageId>,
+        Vec<&'a cm::PackageId>,
+    )> {
         let package = &self[package_id];
 
         let renames = package
@@ -401,60 +755,36 @@ impl MetadataExt for cm::Metadata {
             .flat_map(|cm::Dependency { rename, .. }| rename)
             .collect::<HashSet<_>>();
 
-        let preds = {
-            let rustc_exe = crate::process::cargo_exe()?
-                .with_file_name("rustc")
-                .with_extension(env::consts::EXE_EXTENSION);
-
-            ProcessBuilder::new(rustc_exe)
-                .args(&["--print", "cfg"])
-                .cwd(package.manifest_path.with_file_name(""))
-                .read_stdout::<String>()?
-                .lines()
-                .flat_map(cfg_expr::Expression::parse) // https://github.com/EmbarkStudios/cfg-expr/blob/25290dba689ce3f3ab589926ba545875f048c130/src/expr/parser.rs#L180-L195
-                .collect::<Vec<_>>()
-        };
-        let preds = preds
-            .iter()
-            .flat_map(cfg_expr::Expression::predicates)
-            .collect::<Vec<_>>();
-
         let cm::Resolve { nodes, .. } = self
             .resolve
             .as_ref()
             .with_context(|| "`resolve` is `null`")?;
         let nodes = nodes.iter().map(|n| (&n.id, n)).collect::<HashMap<_, _>>();
 
-        let satisfies = |node_dep: &cm::NodeDep, accepts_dev: bool| -> _ {
-            if exclude.iter().any(|s| s.matches(&self[&node_dep.pkg])) {
+        let is_excluded =
+            |node_dep: &cm::NodeDep| exclude.iter().any(|s| s.matches(&self[&node_dep.pkg]));
+
+        let is_used = |node_dep: &cm::NodeDep, accepts_dev: bool| -> _ {
+            if !features.contains_key(&node_dep.pkg) {
                 return false;
             }
 
-            let cm::Node { features, .. } = &nodes[&node_dep.pkg];
-            let features = features.iter().map(|s| &**s).collect::<HashSet<_>>();
-
             node_dep
                 .dep_kinds
                 .iter()
                 .any(|cm::DepKindInfo { kind, target, .. }| {
                     (*kind == cm::DependencyKind::Normal
                         || accepts_dev && *kind == cm::DependencyKind::Development)
-                        && target
-                            .as_ref()
-                            .and_then(|target| {
-                                cfg_expr::Expression::parse(&target.to_string()).ok()
-                            })
-                            .map_or(true, |target| {
-                                target.eval(|pred| match pred {
-                                    cfg_expr::Predicate::Feature(feature) => {
-                                        features.contains(feature)
-                                    }
-                                    pred => preds.contains(pred),
-                                })
-                            })
+                        && target.as_ref().map_or(true, |target| {
+                            platform_matches(&target.to_string(), target_preds)
+                        })
                 })
         };
 
+        let satisfies = |node_dep: &cm::NodeDep, accepts_dev: bool| -> _ {
+            !is_excluded(node_dep) && is_used(node_dep, accepts_dev)
+        };
+
         if nodes[package_id]
             .deps
             .iter()
@@ -463,6 +793,8 @@ impl MetadataExt for cm::Metadata {
             bail!("this tool requires Rust 1.41+ for calculating dependencies");
         }
 
+        let mut unused = vec![];
+
         let mut deps = nodes[package_id]
             .deps
             .iter()
@@ -480,6 +812,7 @@ impl MetadataExt for cm::Metadata {
                     (lib_target.crate_name(), &lib_package.name)
                 };
                 if cargo_udeps_outcome.contains(lib_name_in_toml) {
+                    unused.push(&lib_package.id);
                     return None;
                 }
                 Some((&lib_package.id, (lib_target, lib_extern_crate_name)))
@@ -530,7 +863,48 @@ impl MetadataExt for cm::Metadata {
             !next_is_empty
         } {}
 
-        Ok(deps)
+        let version_mismatches = deps
+            .keys()
+            .filter(|id| **id != package_id)
+            .map(|id| {
+                let versions = exclude
+                    .iter()
+                    .filter(|spec| {
+                        spec.version.is_some()
+                            && PkgSpec {
+                                name: spec.name.clone(),
+                                version: None,
+                                url: spec.url.clone(),
+                            }
+                            .matches(&self[id])
+                    })
+                    .flat_map(|spec| spec.version.clone())
+                    .sorted()
+                    .dedup()
+                    .collect::<Vec<_>>();
+                (*id, versions)
+            })
+            .filter(|(_, versions)| !versions.is_empty())
+            .collect();
+
+        let excluded = iter::once((package_id, need_dev_deps))
+            .chain(
+                deps.iter()
+                    .filter(|(_, (cm::Target { kind, .. }, _))| *kind == ["lib".to_owned()])
+                    .map(|(package_id, _)| (*package_id, false)),
+            )
+            .flat_map(|(package_id, accepts_dev)| {
+                nodes[package_id]
+                    .deps
+                    .iter()
+                    .filter(move |node_dep| is_excluded(node_dep) && is_used(node_dep, accepts_dev))
+            })
+            .map(|cm::NodeDep { pkg, .. }| pkg)
+            .sorted()
+            .dedup()
+            .collect();
+
+        Ok((deps, version_mismatches, excluded, unused))
     }
 
     fn dep_lib_by_extern_crate_name(
@@ -633,10 +1007,11 @@ impl MetadataExt for cm::Metadata {
 
 fn target_by_kind_and_name<'a>(
     metadata: &'a cm::Metadata,
+    package: Option<&PkgSpec>,
     kind: &str,
     name: &str,
 ) -> anyhow::Result<(&'a cm::Target, &'a cm::Package)> {
-    match *targets_in_ws(metadata)
+    match *targets_in_ws(metadata, package)?
         .filter(|(t, _)| t.name == name && t.kind == [kind.to_owned()])
         .collect::<Vec<_>>()
     {
@@ -650,12 +1025,82 @@ fn target_by_kind_and_name<'a>(
     }
 }
 
-fn targets_in_ws(metadata: &cm::Metadata) -> impl Iterator<Item = (&cm::Target, &cm::Package)> {
-    metadata
+fn targets_in_ws<'a>(
+    metadata: &'a cm::Metadata,
+    package: Option<&PkgSpec>,
+) -> anyhow::Result<impl Iterator<Item = (&'a cm::Target, &'a cm::Package)>> {
+    let package = package
+        .map(|package| selected_package(metadata, Some(package)))
+        .transpose()?
+        .flatten();
+    Ok(metadata
         .packages
         .iter()
         .filter(move |cm::Package { id, .. }| metadata.workspace_members.contains(id))
-        .flat_map(|p| p.targets.iter().map(move |t| (t, p)))
+        .filter(move |p| package.map_or(true, |package| package.id == p.id))
+        .flat_map(|p| p.targets.iter().map(move |t| (t, p))))
+}
+
+/// Returns the workspace member specified with `-p`, or the root package.
+fn selected_package<'a>(
+    metadata: &'a cm::Metadata,
+    package: Option<&PkgSpec>,
+) -> anyhow::Result<Option<&'a cm::Package>> {
+    let spec = match package {
+        Some(spec) => spec,
+        None => return Ok(metadata.root_package()),
+    };
+    match *metadata
+        .packages
+        .iter()
+        .filter(|p| metadata.workspace_members.contains(&p.id) && spec.matches(p))
+        .collect::<Vec<_>>()
+    {
+        [] => bail!("package `{}` is not a member of this workspace", spec.name),
+        [package] => Ok(Some(package)),
+        [..] => bail!(
+            "`{}` matches multiple packages in this workspace. Specify the version",
+            spec.name,
+        ),
+    }
+}
+
+/// Returns `rustc --print cfg` for the target, or for the host if `target` is `None`.
+pub(crate) fn rustc_cfgs(
+    package: &cm::Package,
+    target: Option<&str>,
+) -> anyhow::Result<Vec<cfg_expr::Expression>> {
+    let rustc_exe = crate::process::cargo_exe()?
+        .with_file_name("rustc")
+        .with_extension(env::consts::EXE_EXTENSION);
+
+    let mut rustc = ProcessBuilder::new(rustc_exe);
+    rustc.args(&["--print", "cfg"]);
+    if let Some(target) = target {
+        rustc.args(&["--target", target]);
+    }
+
+    Ok(rustc
+        .cwd(package.manifest_path.with_file_name(""))
+        .read_stdout::<String>()?
+        .lines()
+        .flat_map(cfg_expr::Expression::parse) // https://github.com/EmbarkStudios/cfg-expr/blob/25290dba689ce3f3ab589926ba545875f048c130/src/expr/parser.rs#L180-L195
+        .collect())
+}
+
+/// Evaluates `target` in `[target.<target>.dependencies]`, which is either `cfg(..)` or a target
+/// triple.
+fn platform_matches(target: &str, preds: &[cfg_expr::Predicate<'_>]) -> bool {
+    if let Ok(target) = cfg_expr::Expression::parse(target) {
+        target.eval(|pred| preds.contains(pred))
+    } else if let Some(target) = cfg_expr::targets::get_builtin_target_by_triple(target) {
+        preds.iter().all(|pred| match pred {
+            cfg_expr::Predicate::Target(pred) => pred.matches(target),
+            _ => true,
+        })
+    } else {
+        true
+    }
 }
 
 pub(crate) trait PackageExt {
@@ -664,7 +1109,7 @@ pub(crate) trait PackageExt {
     fn has_proc_macro(&self) -> bool;
     fn lib_like_target(&self) -> Option<&cm::Target>;
     fn manifest_dir(&self) -> &Utf8Path;
-    fn edition(&self) -> Edition;
+    fn edition(&self) -> anyhow::Result<Edition>;
     fn read_license_text(&self, mine: &[User], cache_dir: &Path) -> anyhow::Result<Option<String>>;
 }
 
@@ -691,8 +1136,14 @@ impl PackageExt for cm::Package {
         self.manifest_path.parent().expect("should not be empty")
     }
 
-    fn edition(&self) -> Edition {
-        self.edition.parse().expect("`edition` modified invalidly")
+    fn edition(&self) -> anyhow::Result<Edition> {
+        self.edition.parse().map_err(|_| {
+            anyhow!(
+                "`{}` uses an unsupported edition `{}`",
+                self.name,
+                self.edition
+            )
+        })
     }
 
     fn read_license_text(&self, mine: &[User], cache_dir: &Path) -> anyhow::Result<Option<String>> {
@@ -782,7 +1233,7 @@ impl SourceExt for cm::Source {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, EnumString)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, EnumString)]
 pub(crate) enum Edition {
     #[strum(serialize = "2015")]
     Edition2015,
@@ -790,6 +1241,8 @@ pub(crate) enum Edition {
     Edition2018,
     #[strum(serialize = "2021")]
     Edition2021,
+    #[strum(serialize = "2024")]
+    Edition2024,
 }
 
 impl Edition {
@@ -797,7 +1250,32 @@ impl Edition {
         match self {
             Self::Edition2015 | Self::Edition2018 => ResolveBehavior::V1,
             Self::Edition2021 => ResolveBehavior::V2,
+            Self::Edition2024 => ResolveBehavior::V3,
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub(crate) struct FeatureFlags {
+    pub(crate) features: Vec<String>,
+    pub(crate) all_features: bool,
+    pub(crate) no_default_features: bool,
+}
+
+impl FeatureFlags {
+    pub(crate) fn to_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if !self.features.is_empty() {
+            args.push("--features".to_owned());
+            args.push(self.features.join(","));
+        }
+        if self.all_features {
+            args.push("--all-features".to_owned());
         }
+        if self.no_default_features {
+            args.push("--no-default-features".to_owned());
+        }
+        args
     }
 }
 
@@ -807,4 +1285,387 @@ pub(crate) enum ResolveBehavior {
     V1,
     #[serde(rename = "2")]
     V2,
+    /// Same as `V2` as far as features are concerned.
+    #[serde(rename = "3")]
+    V3,
+}
+
+/// Removes the values in `[features]` which refer to `removed_deps`, namely `dep:a`, `a/f`, `a?/f`,
+/// and `a` for the implicit feature of an optional dependency `a`.
+fn retain_features(
+    features: &toml_edit::Table,
+    remaining_deps: &HashSet<String>,
+    removed_deps: &HashSet<String>,
+) -> toml_edit::Table {
+    let mut retained = toml_edit::Table::new();
+    for (name, values) in features.iter() {
+        if let Some(values) = values.as_array() {
+            retained[name] = toml_edit::value(
+                values
+                    .iter()
+                    .flat_map(|v| v.as_str())
+                    .filter(|&v| {
+                        refers_to_remaining_deps(v, remaining_deps)
+                            && (features.contains_key(v) || !removed_deps.contains(v))
+                    })
+                    .collect::<toml_edit::Array>(),
+            );
+        }
+    }
+    retained
+}
+
+fn refers_to_remaining_deps(value: &str, remaining_deps: &HashSet<String>) -> bool {
+    match value
+        .strip_prefix("dep:")
+        .or_else(|| value.split('/').next().filter(|_| value.contains('/')))
+    {
+        Some(dep_name) => remaining_deps.contains(dep_name.trim_end_matches('?')),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fixture, MetadataExt as _};
+    use indoc::indoc;
+    use krates::PkgSpec;
+    use maplit::hashset;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn packages_provided_by() -> anyhow::Result<()> {
+        static CRATES_IO: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+        let metadata = fixture::metadata(json!([
+            { "name": "a" },
+            { "name": "itertools", "version": "0.10.5", "source": CRATES_IO },
+            { "name": "num", "version": "0.2.1", "source": CRATES_IO },
+            { "name": "proconio", "version": "0.4.5", "source": CRATES_IO },
+            { "name": "rand", "version": "0.8.5", "source": CRATES_IO },
+            { "name": "smallvec", "version": "1.11.0", "source": CRATES_IO },
+            { "name": "foo", "version": "0.1.0", "source": "git+https://example.com/foo#0" },
+        ]));
+
+        let dir = tempfile::Builder::new()
+            .prefix("cargo-equip-test-")
+            .tempdir()?;
+        let provided = |file_name: &str, content: &str| -> anyhow::Result<_> {
+            let path = dir.path().join(file_name);
+            cargo_util::paths::write(&path, content)?;
+            Ok(super::packages_provided_by(&path, &metadata)?
+                .into_iter()
+                .map(|spec| format!("{}@{}", spec.name, spec.version.unwrap()))
+                .collect::<Vec<_>>())
+        };
+
+        assert_eq!(
+            [
+                "itertools@0.10.5",
+                "proconio@0.4.5",
+                "rand@0.8.5",
+                "smallvec@1.11.0"
+            ],
+            &*provided(
+                "Cargo.toml",
+                indoc! {r#"
+                    [package]
+                    name = "main"
+                    version = "0.1.0"
+
+                    [dependencies]
+                    iter = { package = "itertools", version = "=0.10.1" }
+                    num = "0.3.0"
+                    proconio = { version = "=0.4.3", features = ["derive"] }
+                    foo = { git = "https://example.com/foo" }
+
+                    [target.'cfg(unix)'.dependencies]
+                    rand = "0.8"
+
+                    [workspace.dependencies]
+                    smallvec = "1.6.1"
+                "#},
+            )?,
+        );
+
+        assert_eq!(
+            ["num@0.2.1", "rand@0.8.5"],
+            &*provided(
+                "Cargo.lock",
+                &format!(
+                    indoc! {r#"
+                        version = 3
+
+                        [[package]]
+                        name = "main"
+                        version = "0.1.0"
+
+                        [[package]]
+                        name = "num"
+                        version = "0.2.0"
+                        source = "{0}"
+
+                        [[package]]
+                        name = "rand"
+                        version = "0.8.0"
+                        source = "{0}"
+
+                        [[package]]
+                        name = "smallvec"
+                        version = "0.6.14"
+                        source = "{0}"
+                    "#},
+                    CRATES_IO,
+                ),
+            )?,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retain_features() {
+        let features = r#"
+            default = ["a", "b", "c", "d"]
+            a = ["dep:a", "b/std", "c?/std", "e/std"]
+            d = []
+        "#
+        .parse::<toml_edit::Document>()
+        .unwrap();
+
+        let retained = super::retain_features(
+            features.as_table(),
+            &hashset!["e".to_owned()],
+            &hashset![
+                "a".to_owned(),
+                "b".to_owned(),
+                "c".to_owned(),
+                "d".to_owned()
+            ],
+        );
+
+        assert_eq!(
+            indoc! {r#"
+                default = ["a", "d"]
+                a = ["e/std"]
+                d = []
+            "#},
+            retained.to_string(),
+        );
+    }
+
+    fn spec(s: &str) -> PkgSpec {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn selected_package() -> anyhow::Result<()> {
+        let mut metadata = fixture::metadata(json!([
+            {
+                "name": "a",
+                "targets": [{ "name": "a", "kind": "lib" }, { "name": "main", "kind": "bin" }],
+            },
+            {
+                "name": "b",
+                "targets": [{ "name": "main", "kind": "bin" }, { "name": "only-b", "kind": "bin" }],
+            },
+            { "name": "c", "kind": "bin" },
+            { "name": "d", "source": "registry+https://github.com/rust-lang/crates.io-index" },
+        ]));
+
+        assert_eq!(Ok(None), selected(&metadata, None));
+        assert_eq!(Ok(Some("b")), selected(&metadata, Some("b")));
+        assert_eq!(Ok(Some("b")), selected(&metadata, Some("b:0.1.0")));
+        assert_eq!(
+            Err("package `b` is not a member of this workspace".to_owned()),
+            selected(&metadata, Some("b:0.2.0")),
+        );
+        assert_eq!(
+            Err("package `d` is not a member of this workspace".to_owned()),
+            selected(&metadata, Some("d")),
+        );
+        assert_eq!(
+            Err("package `e` is not a member of this workspace".to_owned()),
+            selected(&metadata, Some("e")),
+        );
+
+        let root = metadata.packages[0].id.clone();
+        metadata.resolve.as_mut().unwrap().root = Some(root);
+        assert_eq!(Ok(Some("a")), selected(&metadata, None));
+        assert_eq!(Ok(Some("b")), selected(&metadata, Some("b")));
+        return Ok(());
+
+        fn selected<'a>(
+            metadata: &'a cargo_metadata::Metadata,
+            package: Option<&str>,
+        ) -> Result<Option<&'a str>, String> {
+            super::selected_package(metadata, package.map(spec).as_ref())
+                .map(|p| p.map(|p| &*p.name))
+                .map_err(|e| e.to_string())
+        }
+    }
+
+    #[test]
+    fn targets_with_package() {
+        let metadata = fixture::metadata(json!([
+            {
+                "name": "a",
+                "targets": [{ "name": "a", "kind": "lib" }, { "name": "main", "kind": "bin" }],
+            },
+            {
+                "name": "b",
+                "targets": [{ "name": "main", "kind": "bin" }, { "name": "only-b", "kind": "bin" }],
+            },
+            { "name": "c", "kind": "bin" },
+        ]));
+
+        let names =
+            |result: anyhow::Result<(&cargo_metadata::Target, &cargo_metadata::Package)>| {
+                result
+                    .map(|(t, p)| (t.name.clone(), p.name.clone()))
+                    .map_err(|e| e.to_string())
+            };
+        let ok = |target: &str, package: &str| Ok((target.to_owned(), package.to_owned()));
+
+        assert_eq!(
+            Err(
+                "could not determine which target to choose. Use the `--bin` option, `--example` \
+                 option, `--lib` option, or `--src` option to specify a target, or set the \
+                 `default-run` manifest key.\n\
+                 available targets: a (lib), main (bin), main (bin), only-b (bin), c (bin)"
+                    .to_owned(),
+            ),
+            names(metadata.exactly_one_target(None)),
+        );
+        assert_eq!(
+            Err(
+                "could not determine which target to choose. Use the `--bin` option, `--example` \
+                 option, `--lib` option, or `--src` option to specify a target, or set the \
+                 `default-run` manifest key.\n\
+                 available targets: a (lib), main (bin)"
+                    .to_owned(),
+            ),
+            names(metadata.exactly_one_target(Some(&spec("a")))),
+        );
+        assert_eq!(
+            ok("c", "c"),
+            names(metadata.exactly_one_target(Some(&spec("c"))))
+        );
+        assert_eq!(
+            Err("package `d` is not a member of this workspace".to_owned()),
+            names(metadata.exactly_one_target(Some(&spec("d")))),
+        );
+
+        assert_eq!(
+            Err("multiple bin targets named `main` in this workspace".to_owned()),
+            names(metadata.bin_target_by_name(None, "main")),
+        );
+        assert_eq!(
+            ok("main", "b"),
+            names(metadata.bin_target_by_name(Some(&spec("b")), "main")),
+        );
+        assert_eq!(
+            ok("only-b", "b"),
+            names(metadata.bin_target_by_name(None, "only-b")),
+        );
+        assert_eq!(
+            Err("no bin target named `only-b`".to_owned()),
+            names(metadata.bin_target_by_name(Some(&spec("a")), "only-b")),
+        );
+        assert_eq!(ok("a", "a"), names(metadata.lib_target(Some(&spec("a")))),);
+        assert_eq!(
+            Err("`b` does not have a `lib` target".to_owned()),
+            names(metadata.lib_target(Some(&spec("b")))),
+        );
+    }
+
+    #[test]
+    fn default_run() {
+        let mut metadata = fixture::metadata(json!([
+            {
+                "name": "a",
+                "targets": [
+                    { "name": "a", "kind": "lib" },
+                    { "name": "x", "kind": "bin" },
+                    { "name": "y", "kind": "bin" },
+                ],
+                "default-run": "y",
+            },
+            {
+                "name": "b",
+                "targets": [{ "name": "x", "kind": "bin" }, { "name": "y", "kind": "bin" }],
+                "default-run": "z",
+            },
+        ]));
+
+        let names =
+            |result: anyhow::Result<(&cargo_metadata::Target, &cargo_metadata::Package)>| {
+                result
+                    .map(|(t, p)| (t.name.clone(), p.name.clone()))
+                    .map_err(|e| e.to_string())
+            };
+
+        assert_eq!(
+            Ok(("y".to_owned(), "a".to_owned())),
+            names(metadata.exactly_one_target(Some(&spec("a")))),
+        );
+        assert_eq!(
+            Err("`default-run` of `b` is `z`, but there is no such bin target".to_owned()),
+            names(metadata.exactly_one_target(Some(&spec("b")))),
+        );
+
+        let root = metadata.packages[0].id.clone();
+        metadata.resolve.as_mut().unwrap().root = Some(root);
+        assert_eq!(
+            Ok(("y".to_owned(), "a".to_owned())),
+            names(metadata.exactly_one_target(None)),
+        );
+    }
+
+    #[test]
+    fn bin_like_targets() {
+        let metadata = fixture::metadata(json!([
+            {
+                "name": "a",
+                "targets": [
+                    { "name": "a", "kind": "lib" },
+                    { "name": "x", "kind": "bin" },
+                    { "name": "y", "kind": "bin" },
+                    { "name": "e", "kind": "example" },
+                ],
+            },
+            { "name": "b", "kind": "bin" },
+            { "name": "c" },
+        ]));
+
+        let names = |package: Option<&str>, examples| {
+            metadata
+                .bin_like_targets(package.map(spec).as_ref(), examples)
+                .map(|(ts, p)| {
+                    let ts = ts.iter().map(|t| &*t.name).collect::<Vec<_>>();
+                    (ts, &*p.name)
+                })
+                .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(Ok((vec!["x", "y"], "a")), names(Some("a"), false));
+        assert_eq!(Ok((vec!["x", "y", "e"], "a")), names(Some("a"), true));
+        assert_eq!(Ok((vec!["b"], "b")), names(Some("b"), true));
+        assert_eq!(
+            Err(
+                "could not determine which package to choose. Use the `-p` option to specify a \
+                 package."
+                    .to_owned(),
+            ),
+            names(None, false),
+        );
+        assert_eq!(
+            Err("no bin target in `c`".to_owned()),
+            names(Some("c"), false)
+        );
+        assert_eq!(
+            Err("no bin/example target in `c`".to_owned()),
+            names(Some("c"), true),
+        );
+    }
 }
@@ -0,0 +1,459 @@
+use crate::workspace::{PackageExt as _, TargetExt as _};
+use anyhow::Context as _;
+use cargo_metadata as cm;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Resolves the features in the way of [the feature resolver version 2].
+///
+/// Only the packages which are built for the target are resolved. Build dependencies and the
+/// dependencies of procedural macros are not followed, and dev-dependencies are followed only from
+/// the root package.
+///
+/// [the feature resolver version 2]: https://doc.rust-lang.org/cargo/reference/features.html#feature-resolver-version-2
+pub(super) fn resolve<'cm>(
+    metadata: &'cm cm::Metadata,
+    root: &'cm cm::PackageId,
+    need_dev_deps: bool,
+    root_features: &[String],
+    platform_matches: impl Fn(&cm::Dependency) -> bool,
+) -> anyhow::Result<HashMap<&'cm cm::PackageId, Vec<String>>> {
+    let cm::Resolve { nodes, .. } = metadata
+        .resolve
+        .as_ref()
+        .with_context(|| "`resolve` is `null`")?;
+
+    let mut resolver = Resolver {
+        metadata,
+        nodes: nodes.iter().map(|n| (&n.id, n)).collect(),
+        root,
+        need_dev_deps,
+        platform_matches,
+        features: HashMap::new(),
+        activated_deps: HashSet::new(),
+        weak_dep_features: vec![],
+    };
+
+    resolver.activate_package(root)?;
+    for feature in root_features {
+        resolver.enable_value(root, feature)?;
+    }
+
+    Ok(resolver
+        .features
+        .into_iter()
+        .map(|(package_id, features)| (package_id, features.into_iter().collect()))
+        .collect())
+}
+
+struct Resolver<'cm, F> {
+    metadata: &'cm cm::Metadata,
+    nodes: HashMap<&'cm cm::PackageId, &'cm cm::Node>,
+    root: &'cm cm::PackageId,
+    need_dev_deps: bool,
+    platform_matches: F,
+    features: HashMap<&'cm cm::PackageId, BTreeSet<String>>,
+    activated_deps: HashSet<(&'cm cm::PackageId, usize)>,
+    weak_dep_features: Vec<(&'cm cm::PackageId, String, String)>,
+}
+
+impl<'cm, F: Fn(&cm::Dependency) -> bool> Resolver<'cm, F> {
+    fn activate_package(&mut self, package_id: &'cm cm::PackageId) -> anyhow::Result<()> {
+        if self.features.contains_key(package_id) {
+            return Ok(());
+        }
+        self.features.insert(package_id, BTreeSet::new());
+
+        if self.is_proc_macro(package_id) {
+            return Ok(());
+        }
+
+        let package = &{ self.metadata }[package_id];
+        for (i, dep) in package.dependencies.iter().enumerate() {
+            if !dep.optional && self.follows(package_id, dep) {
+                self.activate_dep(package_id, i)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn follows(&self, package_id: &cm::PackageId, dep: &cm::Dependency) -> bool {
+        (dep.kind == cm::DependencyKind::Normal
+            || self.need_dev_deps
+                && package_id == self.root
+                && dep.kind == cm::DependencyKind::Development)
+            && (self.platform_matches)(dep)
+    }
+
+    fn activate_dep(&mut self, package_id: &'cm cm::PackageId, i: usize) -> anyhow::Result<()> {
+        if !self.activated_deps.insert((package_id, i)) {
+            return Ok(());
+        }
+
+        let dep = &{ self.metadata }[package_id].dependencies[i];
+        let dep_package_id = match self.dep_package_id(package_id, dep) {
+            Some(dep_package_id) => dep_package_id,
+            None => return Ok(()),
+        };
+
+        self.activate_package(dep_package_id)?;
+        if dep.uses_default_features {
+            self.enable_feature(dep_package_id, "default")?;
+        }
+        for feature in &dep.features {
+            self.enable_value(dep_package_id, feature)?;
+        }
+
+        let weak_dep_features = self
+            .weak_dep_features
+            .iter()
+            .filter(|(p, d, _)| *p == package_id && d == name_in_toml(dep))
+            .map(|(_, _, feature)| feature.clone())
+            .collect::<Vec<_>>();
+        for feature in weak_dep_features {
+            self.enable_value(dep_package_id, &feature)?;
+        }
+        Ok(())
+    }
+
+    fn enable_feature(
+        &mut self,
+        package_id: &'cm cm::PackageId,
+        feature: &str,
+    ) -> anyhow::Result<()> {
+        if !self
+            .features
+            .get_mut(package_id)
+            .expect("should have been activated")
+            .insert(feature.to_owned())
+        {
+            return Ok(());
+        }
+
+        let package = &{ self.metadata }[package_id];
+        if let Some(values) = package.features.get(feature) {
+            for value in values {
+                self.enable_value(package_id, value)?;
+            }
+        } else {
+            // an implicit feature for an optional dependency
+            self.activate_deps_named(package_id, feature)?;
+        }
+        Ok(())
+    }
+
+    fn enable_value(&mut self, package_id: &'cm cm::PackageId, value: &str) -> anyhow::Result<()> {
+        if let Some(dep_name) = value.strip_prefix("dep:") {
+            self.activate_deps_named(package_id, dep_name)
+        } else if let Some((dep_name, dep_feature)) = value.split_once('/') {
+            let (dep_name, weak) = match dep_name.strip_suffix('?') {
+                Some(dep_name) => (dep_name, true),
+                None => (dep_name, false),
+            };
+            if !weak {
+                // Like Cargo, `dep/feat` also enables the feature named `dep` if any, including
+                // the implicit one.
+                self.activate_deps_named(package_id, dep_name)?;
+                if self.has_feature(package_id, dep_name) {
+                    self.enable_feature(package_id, dep_name)?;
+                }
+            }
+            let package = &{ self.metadata }[package_id];
+            let mut activated = false;
+            for (i, dep) in package.dependencies.iter().enumerate() {
+                if name_in_toml(dep) == dep_name && self.activated_deps.contains(&(package_id, i)) {
+                    if let Some(dep_package_id) = self.dep_package_id(package_id, dep) {
+                        self.enable_value(dep_package_id, dep_feature)?;
+                        activated = true;
+                    }
+                }
+            }
+            if !activated {
+                self.weak_dep_features.push((
+                    package_id,
+                    dep_name.to_owned(),
+                    dep_feature.to_owned(),
+                ));
+            }
+            Ok(())
+        } else {
+            self.enable_feature(package_id, value)
+        }
+    }
+
+    fn activate_deps_named(
+        &mut self,
+        package_id: &'cm cm::PackageId,
+        name: &str,
+    ) -> anyhow::Result<()> {
+        // The dependencies of procedural macros are built for the host.
+        if self.is_proc_macro(package_id) {
+            return Ok(());
+        }
+
+        let package = &{ self.metadata }[package_id];
+        for (i, dep) in package.dependencies.iter().enumerate() {
+            if name_in_toml(dep) == name && self.follows(package_id, dep) {
+                self.activate_dep(package_id, i)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `package_id` has an explicit feature named `name`, or an implicit one for the
+    /// optional dependency `name` which no `dep:name` hides.
+    fn has_feature(&self, package_id: &cm::PackageId, name: &str) -> bool {
+        let package = &self.metadata[package_id];
+        package.features.contains_key(name)
+            || package
+                .dependencies
+                .iter()
+                .any(|dep| dep.optional && name_in_toml(dep) == name)
+                && !package
+                    .features
+                    .values()
+                    .flatten()
+                    .any(|value| value.strip_prefix("dep:") == Some(name))
+    }
+
+    fn is_proc_macro(&self, package_id: &cm::PackageId) -> bool {
+        self.metadata[package_id]
+            .lib_like_target()
+            .map_or(false, |t| t.is_proc_macro())
+    }
+
+    fn dep_package_id(
+        &self,
+        package_id: &cm::PackageId,
+        dep: &cm::Dependency,
+    ) -> Option<&'cm cm::PackageId> {
+        let metadata = self.metadata;
+        self.nodes[package_id]
+            .deps
+            .iter()
+            .map(|cm::NodeDep { name, pkg, .. }| (name, &metadata[pkg]))
+            .find(|(name, package)| {
+                if let Some(rename) = &dep.rename {
+                    **name == rename.replace('-', "_")
+                } else {
+                    package.name == dep.name && dep.req.matches(&package.version)
+                }
+            })
+            .map(|(_, package)| &package.id)
+    }
+}
+
+fn name_in_toml(dep: &cm::Dependency) -> &str {
+    dep.rename.as_ref().unwrap_or(&dep.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::workspace::fixture;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    fn resolve(packages: serde_json::Value, need_dev_deps: bool) -> BTreeMap<String, Vec<String>> {
+        let metadata = &fixture::metadata(packages);
+        super::resolve(
+            metadata,
+            &fixture::package_id("root"),
+            need_dev_deps,
+            &["default".to_owned()],
+            |_| true,
+        )
+        .unwrap()
+        .into_iter()
+        .map(|(package_id, features)| (metadata[package_id].name.clone(), features))
+        .collect()
+    }
+
+    fn features(features: &[&str]) -> Vec<String> {
+        features.iter().map(|&s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn default_features() {
+        assert_eq!(
+            maplit::btreemap!(
+                "root".to_owned() => features(&["default"]),
+                "a".to_owned() => features(&["default", "x"]),
+                "b".to_owned() => features(&["y"]),
+            ),
+            resolve(
+                json!([
+                    {
+                        "name": "root",
+                        "dependencies": [
+                            { "name": "a" },
+                            { "name": "b", "default-features": false, "features": ["y"] },
+                        ],
+                    },
+                    { "name": "a", "features": { "default": ["x"], "x": [], "y": [] } },
+                    { "name": "b", "features": { "default": ["x"], "x": [], "y": [] } },
+                ]),
+                false,
+            ),
+        );
+    }
+
+    #[test]
+    fn dep_syntax() {
+        assert_eq!(
+            maplit::btreemap!(
+                "root".to_owned() => features(&["default"]),
+                "a".to_owned() => features(&["default"]),
+            ),
+            resolve(
+                json!([
+                    {
+                        "name": "root",
+                        "features": { "default": ["dep:a"] },
+                        "dependencies": [
+                            { "name": "a", "optional": true },
+                            { "name": "b", "optional": true },
+                        ],
+                    },
+                    { "name": "a" },
+                    { "name": "b" },
+                ]),
+                false,
+            ),
+        );
+    }
+
+    #[test]
+    fn weak_dep_features() {
+        assert_eq!(
+            maplit::btreemap!(
+                "root".to_owned() => features(&["default"]),
+                "b".to_owned() => features(&["default", "x"]),
+            ),
+            resolve(
+                json!([
+                    {
+                        "name": "root",
+                        "features": { "default": ["a?/x", "b?/x", "dep:b"] },
+                        "dependencies": [
+                            { "name": "a", "optional": true },
+                            { "name": "b", "optional": true },
+                        ],
+                    },
+                    { "name": "a", "features": { "x": [] } },
+                    { "name": "b", "features": { "x": [] } },
+                ]),
+                false,
+            ),
+        );
+    }
+
+    #[test]
+    fn dep_features_enable_implicit_features() {
+        assert_eq!(
+            maplit::btreemap!(
+                "root".to_owned() => features(&["a", "default"]),
+                "a".to_owned() => features(&["default", "x"]),
+            ),
+            resolve(
+                json!([
+                    {
+                        "name": "root",
+                        "features": { "default": ["a/x"] },
+                        "dependencies": [{ "name": "a", "optional": true }],
+                    },
+                    { "name": "a", "features": { "x": [] } },
+                ]),
+                false,
+            ),
+        );
+
+        assert_eq!(
+            maplit::btreemap!(
+                "root".to_owned() => features(&["default"]),
+                "a".to_owned() => features(&["default", "x"]),
+            ),
+            resolve(
+                json!([
+                    {
+                        "name": "root",
+                        "features": { "default": ["a/x"], "other": ["dep:a"] },
+                        "dependencies": [{ "name": "a", "optional": true }],
+                    },
+                    { "name": "a", "features": { "x": [] } },
+                ]),
+                false,
+            ),
+        );
+    }
+
+    #[test]
+    fn renamed_deps() {
+        assert_eq!(
+            maplit::btreemap!(
+                "root".to_owned() => features(&["a2", "default"]),
+                "a".to_owned() => features(&["default", "x"]),
+            ),
+            resolve(
+                json!([
+                    {
+                        "name": "root",
+                        "features": { "default": ["a2/x"] },
+                        "dependencies": [{ "name": "a", "rename": "a2", "optional": true }],
+                    },
+                    { "name": "a", "features": { "x": [] } },
+                ]),
+                false,
+            ),
+        );
+    }
+
+    #[test]
+    fn dev_deps() {
+        let packages = json!([
+            { "name": "root", "dependencies": [{ "name": "d", "kind": "dev" }] },
+            { "name": "d" },
+        ]);
+        assert_eq!(
+            maplit::btreemap!("root".to_owned() => features(&["default"])),
+            resolve(packages.clone(), false),
+        );
+        assert_eq!(
+            maplit::btreemap!(
+                "root".to_owned() => features(&["default"]),
+                "d".to_owned() => features(&["default"]),
+            ),
+            resolve(packages, true),
+        );
+    }
+
+    #[test]
+    fn host_deps() {
+        assert_eq!(
+            maplit::btreemap!(
+                "root".to_owned() => features(&["default"]),
+                "m".to_owned() => features(&["default", "f"]),
+            ),
+            resolve(
+                json!([
+                    {
+                        "name": "root",
+                        "dependencies": [
+                            { "name": "m", "features": ["f"] },
+                            { "name": "b", "kind": "build" },
+                        ],
+                    },
+                    {
+                        "name": "m",
+                        "kind": "proc-macro",
+                        "features": { "default": ["n/x"], "f": ["dep:o"] },
+                        "dependencies": [{ "name": "n" }, { "name": "o", "optional": true }],
+                    },
+                    { "name": "b" },
+                    { "name": "n", "features": { "x": [] } },
+                    { "name": "o" },
+                ]),
+                false,
+            ),
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Small `cargo metadata` outputs for unit tests.
+
+use cargo_metadata as cm;
+use serde_json::json;
+
+pub(crate) fn package_id(name: &str) -> cm::PackageId {
+    cm::PackageId {
+        repr: format!("{} 0.1.0 (path+file:///ws/{})", name, name),
+    }
+}
+
+/// Builds a workspace from packages written as
+///
+/// ```json
+/// {
+///     "name": "a",
+///     "kind": "lib",
+///     "version": "0.1.0",
+///     "source": null,
+///     "features": {},
+///     "dependencies": [{ "name": "b", "kind": "dev", "req": "^0.1", "rename": null }],
+///     "targets": [{ "name": "a", "kind": "lib" }, { "name": "c", "kind": "example" }],
+///     "default-run": null
+/// }
+/// ```
+///
+/// Every key except for `name` can be omitted. Without `targets`, the package has one target
+/// named after it, of `kind`. The packages are resolved by name, and the ones
+/// without `source` are the workspace members.
+pub(crate) fn metadata(packages: serde_json::Value) -> cm::Metadata {
+    let packages = packages.as_array().unwrap();
+
+    let str_field = |package: &serde_json::Value, key: &str| {
+        package
+            .get(key)
+            .and_then(|v| v.as_str())
+            .map(ToOwned::to_owned)
+    };
+
+    let mut nodes = vec![];
+    let mut workspace_members = vec![];
+    let packages = packages
+        .iter()
+        .map(|package| {
+            let name = str_field(package, "name").unwrap();
+            let id = package_id(&name);
+            let source = str_field(package, "source");
+            if source.is_none() {
+                workspace_members.push(id.repr.clone());
+            }
+            let kind = str_field(package, "kind").unwrap_or_else(|| "lib".to_owned());
+            let deps = package
+                .get("dependencies")
+                .and_then(|v| v.as_array())
+                .cloned()
+                .unwrap_or_default();
+
+            nodes.push(json!({
+                "id": id.repr,
+                "dependencies": deps
+                    .iter()
+                    .map(|dep| package_id(dep["name"].as_str().unwrap()).repr)
+                    .collect::<Vec<_>>(),
+                "deps": deps
+                    .iter()
+                    .map(|dep| {
+                        let name = dep["name"].as_str().unwrap();
+                        json!({
+                            "name": dep
+                                .get("rename")
+                                .and_then(|v| v.as_str())
+                                .unwrap_or(name)
+                                .replace('-', "_"),
+                            "pkg": package_id(name).repr,
+                            "dep_kinds": [{ "kind": dep.get("kind"), "target": null }],
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+                "features": [],
+            }));
+
+            json!({
+                "name": name,
+                "version": str_field(package, "version").unwrap_or_else(|| "0.1.0".to_owned()),
+                "id": id.repr,
+                "source": source,
+                "license": str_field(package, "license"),
+                "dependencies": deps
+                    .iter()
+                    .map(|dep| json!({
+                        "name": dep["name"],
+                        "source": null,
+                        "req": dep.get("req").cloned().unwrap_or_else(|| json!("*")),
+                        "kind": dep.get("kind"),
+                        "optional": dep.get("optional").and_then(|v| v.as_bool()).unwrap_or(false),
+                        "uses_default_features": dep
+                            .get("default-features")
+                            .and_then(|v| v.as_bool())
+                            .unwrap_or(true),
+                        "features": dep.get("features").cloned().unwrap_or_else(|| json!([])),
+                        "target": null,
+                        "rename": dep.get("rename"),
+                    }))
+                    .collect::<Vec<_>>(),
+                "targets": package
+                    .get("targets")
+                    .and_then(|v| v.as_array())
+                    .cloned()
+                    .unwrap_or_else(|| vec![json!({ "name": name, "kind": kind })])
+                    .iter()
+                    .map(|target| {
+                        let target_name = target["name"].as_str().unwrap();
+                        let kind = &target["kind"];
+                        json!({
+                            "name": target_name,
+                            "kind": [kind],
+                            "crate_types": [kind],
+                            "src_path": if target_name == name {
+                                format!("/ws/{}/src/lib.rs", name)
+                            } else {
+                                format!("/ws/{}/src/{}.rs", name, target_name)
+                            },
+                            "edition": "2021",
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+                "features": package.get("features").cloned().unwrap_or_else(|| json!({})),
+                "manifest_path": format!("/ws/{}/Cargo.toml", name),
+                "default_run": str_field(package, "default-run"),
+                "edition": "2021",
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::from_value(json!({
+        "packages": packages,
+        "workspace_members": workspace_members,
+        "resolve": { "nodes": nodes, "root": null },
+        "workspace_root": "/ws",
+        "target_directory": "/ws/target",
+        "version": 1,
+    }))
+    .unwrap()
+}